@@ -0,0 +1,436 @@
+/// How far apart the detents of an encoder are in its quadrature cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DetentMode {
+    /// One detent per full cycle, like most EC11s.
+    #[default]
+    Full,
+    /// One detent per half cycle, resting with both pins high or both low.
+    Half,
+    /// A detent on every transition.
+    Quarter,
+}
+
+impl DetentMode {
+    /// Amount of valid transitions making up a single detent of the knob.
+    pub const fn transitions(self) -> i8 {
+        match self {
+            DetentMode::Full => 4,
+            DetentMode::Half => 2,
+            DetentMode::Quarter => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    Left,
+    Right,
+    None,
+}
+
+/// Packs the two encoder pins into a 2-bit Gray-code state.
+pub const fn state(a: bool, b: bool) -> u8 {
+    ((a as u8) << 1) | b as u8
+}
+
+// Both pins idle high (0b11). Turning left pulls A low first:
+// 0b11 -> 0b01 -> 0b00 -> 0b10 -> 0b11, turning right pulls B first.
+// Staying put or jumping over a state (both bits changed) is not a valid
+// transition and is ignored.
+const TRANSITIONS: [Direction; 16] = {
+    use Direction::*;
+    [
+        // prev = 0b00
+        None, Right, Left, None, //
+        // prev = 0b01
+        Left, None, None, Right, //
+        // prev = 0b10
+        Right, None, None, Left, //
+        // prev = 0b11
+        None, Left, Right, None, //
+    ]
+};
+
+/// Decodes a single quadrature transition between two states made with [`state`].
+pub const fn step(prev: u8, cur: u8) -> Direction {
+    TRANSITIONS[(((prev & 0b11) << 2) | (cur & 0b11)) as usize]
+}
+
+/// The state one transition in `direction` away from `state`, `state`
+/// itself for [`Direction::None`].
+pub const fn next_state(state: u8, direction: Direction) -> u8 {
+    let mut cur = 0;
+    while cur < 4 {
+        if step(state, cur) as u8 == direction as u8 {
+            return cur;
+        }
+        cur += 1;
+    }
+    state & 0b11
+}
+
+/// The levels of A and B in a state made with [`state`].
+pub const fn levels(state: u8) -> (bool, bool) {
+    (state & 0b10 != 0, state & 0b01 != 0)
+}
+
+/// Edges on one pin while the other one never moved before that one
+/// counts as stuck. Turning toggles both pins every detent, only a knob
+/// resting right on an edge toggles a single one a few times.
+const STUCK_EDGES: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pin {
+    A,
+    B,
+}
+
+impl Pin {
+    const fn other(self) -> Self {
+        match self {
+            Pin::A => Pin::B,
+            Pin::B => Pin::A,
+        }
+    }
+}
+
+// `==` isn't const
+const fn same_pin(a: Option<Pin>, b: Option<Pin>) -> bool {
+    matches!(
+        (a, b),
+        (None, None) | (Some(Pin::A), Some(Pin::A)) | (Some(Pin::B), Some(Pin::B))
+    )
+}
+
+/// Spots an encoder pin that stopped changing, like one with a broken
+/// solder joint. An edge on the stuck pin clears it again.
+#[derive(Default)]
+pub struct StuckPinDetector {
+    last: Option<Pin>,
+    // Edges in a row on `last`
+    run: u8,
+    stuck: Option<Pin>,
+}
+
+impl StuckPinDetector {
+    /// Records an edge on `pin`, returns whether [`Self::stuck`] changed.
+    pub const fn edge(&mut self, pin: Pin) -> bool {
+        if same_pin(self.last, Some(pin)) {
+            self.run = self.run.saturating_add(1);
+        } else {
+            self.last = Some(pin);
+            self.run = 1;
+        }
+
+        let stuck = if self.run >= STUCK_EDGES {
+            Some(pin.other())
+        } else if same_pin(self.stuck, Some(pin)) {
+            None
+        } else {
+            self.stuck
+        };
+        let changed = !same_pin(stuck, self.stuck);
+        self.stuck = stuck;
+        changed
+    }
+
+    /// The pin that looks stuck, if any.
+    pub const fn stuck(&self) -> Option<Pin> {
+        self.stuck
+    }
+}
+
+/// Turns encoder pin levels into detents.
+///
+/// Cheap encoders tend to blip back for a moment right as they click into a
+/// detent, which would count towards a detent in the wrong direction. A
+/// reversal that quick, within `dwell_us` of resting on a detent, can't
+/// complete a detent on its own, along with any further flipping back and
+/// forth. It's still counted, so turning back for real stays in step with
+/// the detents and the blip going back undoes it.
+pub struct QuadratureDecoder {
+    state: u8,
+    // Valid transitions since the last detent, positive to the right
+    transitions: i8,
+    mode: DetentMode,
+    dwell_us: u64,
+    // State before the last valid transition, and when that happened
+    prev_state: u8,
+    changed_at_us: u64,
+    // Whether the last valid transition ended on the last detent
+    at_detent: bool,
+}
+
+impl QuadratureDecoder {
+    pub const fn new(a: bool, b: bool, mode: DetentMode, dwell_us: u64) -> Self {
+        Self {
+            state: state(a, b),
+            transitions: 0,
+            mode,
+            dwell_us,
+            prev_state: state(a, b),
+            changed_at_us: 0,
+            at_detent: false,
+        }
+    }
+
+    /// Feeds the pin levels at `now_us`, returns the direction once a full
+    /// detent was turned.
+    pub const fn update(&mut self, a: bool, b: bool, now_us: u64) -> Option<Direction> {
+        let cur = state(a, b);
+        let direction = step(self.state, cur);
+        if matches!(direction, Direction::None) {
+            self.state = cur;
+            return None;
+        }
+
+        let glitch = self.at_detent
+            && cur == self.prev_state
+            && now_us.saturating_sub(self.changed_at_us) < self.dwell_us;
+        self.prev_state = self.state;
+        self.state = cur;
+        self.changed_at_us = now_us;
+
+        match direction {
+            Direction::Left => self.transitions -= 1,
+            Direction::Right => self.transitions += 1,
+            Direction::None => {}
+        }
+        // Back where the last detent was, a blip back and forth ends here
+        self.at_detent = self.transitions == 0;
+        if glitch {
+            return None;
+        }
+
+        let per_detent = self.mode.transitions();
+        let direction = if self.transitions <= -per_detent {
+            Direction::Left
+        } else if self.transitions >= per_detent {
+            Direction::Right
+        } else {
+            return None;
+        };
+        self.transitions = 0;
+        self.at_detent = true;
+        Some(direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One detent of a full cycle encoder, from both pins high back to them
+    const RIGHT: [(bool, bool); 4] = [(true, false), (false, false), (false, true), (true, true)];
+    const LEFT: [(bool, bool); 4] = [(false, true), (false, false), (true, false), (true, true)];
+
+    /// Feeds `levels` one every `step_us` from `start_us`, returns the detents.
+    fn feed(
+        decoder: &mut QuadratureDecoder,
+        levels: &[(bool, bool)],
+        start_us: u64,
+        step_us: u64,
+    ) -> Vec<Direction> {
+        let mut t = start_us;
+        let mut detents = Vec::new();
+        for &(a, b) in levels {
+            detents.extend(decoder.update(a, b, t));
+            t += step_us;
+        }
+        detents
+    }
+
+    #[test]
+    fn transitions_are_reversible() {
+        // Undoing a transition has to count in the other direction,
+        // otherwise turning back and forth drifts
+        for prev in 0..4 {
+            for cur in 0..4 {
+                let expected = match step(prev, cur) {
+                    Direction::Left => Direction::Right,
+                    Direction::Right => Direction::Left,
+                    Direction::None => Direction::None,
+                };
+                assert_eq!(step(cur, prev), expected, "{prev:02b} -> {cur:02b}");
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_every_transition() {
+        use Direction::*;
+        let expected = [
+            // Staying put
+            (0b00, 0b00, None),
+            (0b01, 0b01, None),
+            (0b10, 0b10, None),
+            (0b11, 0b11, None),
+            // Both pins changed at once, a state was missed
+            (0b00, 0b11, None),
+            (0b11, 0b00, None),
+            (0b01, 0b10, None),
+            (0b10, 0b01, None),
+            // Left: 0b11 -> 0b01 -> 0b00 -> 0b10 -> 0b11
+            (0b11, 0b01, Left),
+            (0b01, 0b00, Left),
+            (0b00, 0b10, Left),
+            (0b10, 0b11, Left),
+            // Right: the same backwards
+            (0b11, 0b10, Right),
+            (0b10, 0b00, Right),
+            (0b00, 0b01, Right),
+            (0b01, 0b11, Right),
+        ];
+        for (prev, cur, direction) in expected {
+            assert_eq!(step(prev, cur), direction, "{prev:02b} -> {cur:02b}");
+        }
+    }
+
+    #[test]
+    fn next_state_is_one_step_away() {
+        for prev in 0..4 {
+            for direction in [Direction::Left, Direction::Right, Direction::None] {
+                let cur = next_state(prev, direction);
+                assert_eq!(step(prev, cur), direction, "{prev:02b} {direction:?}");
+                let (a, b) = levels(cur);
+                assert_eq!(state(a, b), cur);
+            }
+        }
+        // Four steps make a full cycle
+        let mut s = 0b11;
+        let mut visited = Vec::new();
+        for _ in 0..4 {
+            s = next_state(s, Direction::Right);
+            visited.push(levels(s));
+        }
+        assert_eq!(visited, RIGHT);
+    }
+
+    #[test]
+    fn full_cycle_per_mode() {
+        // One full cycle is one, two or four detents depending on the mode,
+        // and the same cycle back gives as many the other way
+        for (mode, detents) in [
+            (DetentMode::Full, 1),
+            (DetentMode::Half, 2),
+            (DetentMode::Quarter, 4),
+        ] {
+            let mut decoder = QuadratureDecoder::new(true, true, mode, 0);
+            assert_eq!(
+                feed(&mut decoder, &RIGHT, 0, 0),
+                vec![Direction::Right; detents]
+            );
+            assert_eq!(
+                feed(&mut decoder, &LEFT, 0, 0),
+                vec![Direction::Left; detents]
+            );
+        }
+    }
+
+    #[test]
+    fn full_detents_both_ways() {
+        let mut decoder = QuadratureDecoder::new(true, true, DetentMode::Full, 1000);
+        let mut t = 0;
+        for _ in 0..3 {
+            // Each detent lands on the last transition of the cycle
+            assert_eq!(feed(&mut decoder, &RIGHT[..3], t, 5000), vec![]);
+            assert_eq!(
+                feed(&mut decoder, &RIGHT[3..], t + 15_000, 5000),
+                vec![Direction::Right]
+            );
+            t += 20_000;
+        }
+        let left = feed(&mut decoder, &LEFT.repeat(3), t, 5000);
+        assert_eq!(left, vec![Direction::Left; 3]);
+    }
+
+    #[test]
+    fn bouncing_pin_does_not_double_count() {
+        let mut decoder = QuadratureDecoder::new(true, true, DetentMode::Full, 1000);
+        assert_eq!(feed(&mut decoder, &RIGHT, 0, 5000), vec![Direction::Right]);
+        // A chattering between the first two states of the next detent
+        let bounces = [(true, false), (true, true)].repeat(20);
+        assert_eq!(feed(&mut decoder, &bounces, 100_000, 50), vec![]);
+        // Only the detent actually turned counts
+        assert_eq!(
+            feed(&mut decoder, &RIGHT, 200_000, 5000),
+            vec![Direction::Right]
+        );
+    }
+
+    #[test]
+    fn missed_state_is_dropped_not_guessed() {
+        // A jump over a state doesn't tell which way the knob went, it's
+        // left out rather than counted either way
+        let mut decoder = QuadratureDecoder::new(true, true, DetentMode::Full, 0);
+        assert_eq!(decoder.update(false, false, 0), None);
+        // The rest of that detent to the right and three more. The two
+        // transitions jumped over are lost, which shifts the detents but
+        // never counts one twice or the wrong way
+        let rest = [&RIGHT[2..], &RIGHT.repeat(3)].concat();
+        assert_eq!(feed(&mut decoder, &rest, 0, 0), vec![Direction::Right; 3]);
+    }
+
+    #[test]
+    fn blip_at_detent_is_dropped() {
+        // Shown on a quarter cycle encoder, where a single transition back
+        // would already be a wrong detent
+        const DWELL_US: u64 = 1000;
+        let mut decoder = QuadratureDecoder::new(true, true, DetentMode::Quarter, DWELL_US);
+        assert_eq!(decoder.update(true, false, 0), Some(Direction::Right));
+        // Blips back and forth, ending where it was
+        for t in (100..500).step_by(100) {
+            assert_eq!(decoder.update(true, true, t), None);
+            assert_eq!(decoder.update(true, false, t + 50), None);
+        }
+        // Keeps turning right
+        assert_eq!(decoder.update(false, false, 10_000), Some(Direction::Right));
+        // Turning back after resting on the detent counts right away
+        assert_eq!(
+            decoder.update(true, false, 10_000 + DWELL_US),
+            Some(Direction::Left)
+        );
+    }
+
+    #[test]
+    fn quick_reversal_stays_in_step() {
+        // Turning back for whole detents right after clicking into one
+        // gives a detent back right as the knob clicks into each
+        const DWELL_US: u64 = 1000;
+        let mut decoder = QuadratureDecoder::new(true, true, DetentMode::Full, DWELL_US);
+        assert_eq!(feed(&mut decoder, &RIGHT, 0, 0), vec![Direction::Right]);
+        for round in 0..2 {
+            let start = 100 + round * 400;
+            assert_eq!(feed(&mut decoder, &LEFT[..3], start, 100), vec![]);
+            assert_eq!(
+                feed(&mut decoder, &LEFT[3..], start + 300, 100),
+                vec![Direction::Left]
+            );
+        }
+    }
+
+    #[test]
+    fn stuck_pin_detected_and_cleared() {
+        let mut detector = StuckPinDetector::default();
+        // One edge short, then B moves
+        for _ in 1..STUCK_EDGES {
+            assert!(!detector.edge(Pin::A));
+        }
+        assert!(!detector.edge(Pin::B));
+        assert_eq!(detector.stuck(), None);
+        // B unchanged for a full run
+        for _ in 1..STUCK_EDGES {
+            assert!(!detector.edge(Pin::A));
+        }
+        assert!(detector.edge(Pin::A));
+        assert_eq!(detector.stuck(), Some(Pin::B));
+        // More edges on A change nothing
+        assert!(!detector.edge(Pin::A));
+        // B toggles and recovers
+        assert!(detector.edge(Pin::B));
+        assert_eq!(detector.stuck(), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Checks of values written to the knob's characteristics, each rejecting
+//! a malformed write with the ATT error the firmware answers it with.
+
+/// Why a write was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reject {
+    /// Not as long as the characteristic takes.
+    Length,
+    /// Not one of the values the characteristic knows.
+    NotAllowed,
+    /// Outside of the range of a number.
+    OutOfRange,
+    /// A value the characteristic knows, but the knob doesn't do.
+    NotSupported,
+}
+
+/// The single byte in `data`.
+pub const fn single(data: &[u8]) -> Result<u8, Reject> {
+    match data {
+        [value] => Ok(*value),
+        _ => Err(Reject::Length),
+    }
+}
+
+/// One of `allowed`.
+pub const fn validate_one_of(data: &[u8], allowed: &[u8]) -> Option<Reject> {
+    let value = match single(data) {
+        Ok(value) => value,
+        Err(e) => return Some(e),
+    };
+    let mut i = 0;
+    while i < allowed.len() {
+        if allowed[i] == value {
+            return None;
+        }
+        i += 1;
+    }
+    Some(Reject::NotAllowed)
+}
+
+/// A number from `min` to `max`.
+pub const fn validate_in_range(data: &[u8], min: u8, max: u8) -> Option<Reject> {
+    match single(data) {
+        Ok(value) if value >= min && value <= max => None,
+        Ok(_) => Some(Reject::OutOfRange),
+        Err(e) => Some(e),
+    }
+}
+
+/// 0 or 1.
+pub const fn validate_bool(data: &[u8]) -> Option<Reject> {
+    validate_one_of(data, &[0, 1])
+}
+
+/// Whether `name` works as a device name of up to `max` bytes, UTF-8 and
+/// without zeroes, which pad a stored name.
+pub const fn name_allowed(name: &[u8], max: usize) -> bool {
+    if name.is_empty() || name.len() > max {
+        return false;
+    }
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    core::str::from_utf8(name).is_ok()
+}
+
+pub const fn validate_name(data: &[u8], max: usize) -> Option<Reject> {
+    if name_allowed(data, max) {
+        None
+    } else {
+        Some(Reject::NotAllowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each validator takes the values at the edges of what it allows, and
+    // rejects the ones right past them and values of the wrong length
+
+    #[test]
+    fn single_byte() {
+        assert_eq!(single(&[7]), Ok(7));
+        assert_eq!(single(&[]), Err(Reject::Length));
+        assert_eq!(single(&[7, 7]), Err(Reject::Length));
+    }
+
+    #[test]
+    fn one_of() {
+        let allowed = [0x01, 0x5A, 0xA5];
+        for value in allowed {
+            assert_eq!(validate_one_of(&[value], &allowed), None);
+        }
+        assert_eq!(validate_one_of(&[0], &allowed), Some(Reject::NotAllowed));
+        assert_eq!(validate_one_of(&[0xff], &allowed), Some(Reject::NotAllowed));
+        assert_eq!(validate_one_of(&[], &allowed), Some(Reject::Length));
+        assert_eq!(
+            validate_one_of(&[0x01, 0x01], &allowed),
+            Some(Reject::Length)
+        );
+        assert_eq!(validate_one_of(&[0], &[]), Some(Reject::NotAllowed));
+    }
+
+    #[test]
+    fn in_range() {
+        assert_eq!(validate_in_range(&[0], 1, 10), Some(Reject::OutOfRange));
+        assert_eq!(validate_in_range(&[1], 1, 10), None);
+        assert_eq!(validate_in_range(&[10], 1, 10), None);
+        assert_eq!(validate_in_range(&[11], 1, 10), Some(Reject::OutOfRange));
+        assert_eq!(validate_in_range(&[], 1, 10), Some(Reject::Length));
+        assert_eq!(validate_in_range(&[1, 1], 1, 10), Some(Reject::Length));
+        // The whole range of a byte
+        assert_eq!(validate_in_range(&[u8::MAX], 0, u8::MAX), None);
+    }
+
+    #[test]
+    fn bool() {
+        assert_eq!(validate_bool(&[0]), None);
+        assert_eq!(validate_bool(&[1]), None);
+        assert_eq!(validate_bool(&[2]), Some(Reject::NotAllowed));
+        assert_eq!(validate_bool(&[0, 1]), Some(Reject::Length));
+    }
+
+    #[test]
+    fn name() {
+        const MAX: usize = 22;
+        assert_eq!(validate_name(b"Desk Knob", MAX), None);
+        assert_eq!(validate_name("Gałka".as_bytes(), MAX), None);
+        assert_eq!(validate_name(&[b'a'; MAX], MAX), None);
+        assert_eq!(
+            validate_name(&[b'a'; MAX + 1], MAX),
+            Some(Reject::NotAllowed)
+        );
+        assert_eq!(validate_name(b"", MAX), Some(Reject::NotAllowed));
+        assert_eq!(validate_name(b"Desk\0Knob", MAX), Some(Reject::NotAllowed));
+        // Cut off in the middle of a character
+        assert_eq!(
+            validate_name(&[b'G', b'a', 0xc5], MAX),
+            Some(Reject::NotAllowed)
+        );
+    }
+}
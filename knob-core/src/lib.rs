@@ -0,0 +1,12 @@
+//! The knob's logic that doesn't touch the hardware, split out of the
+//! firmware so it builds for the host as well and can be tested there,
+//! with `cargo test-host`.
+//!
+//! The firmware itself only builds for the Pico, what it checks on its own
+//! is checked at build time, in `const _` blocks.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod encoder;
+pub mod gatt;
+pub mod record;
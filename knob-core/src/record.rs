@@ -0,0 +1,275 @@
+//! Records kept in flash. Each one is stored in two copies written in
+//! turns, so a write torn by a power loss only ever hits the older one:
+//!
+//! | Bytes | Field                                        |
+//! |-------|----------------------------------------------|
+//! | 4     | Magic, telling what the record is of         |
+//! | 1     | Version of the data layout                   |
+//! | 4     | Sequence number, the newer copy's is higher  |
+//! | n     | Data                                         |
+//! | 4     | CRC-32 of everything before it               |
+
+pub const MAGIC_LEN: usize = 4;
+// magic + version + sequence number
+pub const HEADER_LEN: usize = MAGIC_LEN + 1 + 4;
+pub const CRC_LEN: usize = 4;
+
+/// Length of a record holding `data_len` bytes.
+pub const fn record_len(data_len: usize) -> usize {
+    HEADER_LEN + data_len + CRC_LEN
+}
+
+/// What a record holds, one of another kind or version doesn't open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kind {
+    pub magic: [u8; MAGIC_LEN],
+    pub version: u8,
+}
+
+/// CRC-32 as used by zlib and Ethernet.
+pub const fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= data[i] as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    !crc
+}
+
+/// Wraps `data` into `record`, which has to be [`record_len`] of it long.
+pub fn seal(kind: Kind, sequence: u32, data: &[u8], record: &mut [u8]) {
+    assert_eq!(record.len(), record_len(data.len()));
+    let (header, rest) = record.split_at_mut(HEADER_LEN);
+    header[..MAGIC_LEN].copy_from_slice(&kind.magic);
+    header[MAGIC_LEN] = kind.version;
+    header[MAGIC_LEN + 1..].copy_from_slice(&sequence.to_le_bytes());
+    rest[..data.len()].copy_from_slice(data);
+    let (covered, crc) = record.split_at_mut(record.len() - CRC_LEN);
+    crc.copy_from_slice(&crc32(covered).to_le_bytes());
+}
+
+/// The sequence number and data of a record of `kind`, `None` for an
+/// erased or torn one, or one written by another firmware.
+pub fn open(kind: Kind, record: &[u8]) -> Option<(u32, &[u8])> {
+    let (covered, crc) = record.split_at_checked(record.len().checked_sub(CRC_LEN)?)?;
+    if crc32(covered).to_le_bytes() != crc {
+        return None;
+    }
+    let (header, data) = covered.split_at_checked(HEADER_LEN)?;
+    let (magic, rest) = header.split_at(MAGIC_LEN);
+    if magic != kind.magic || rest[0] != kind.version {
+        return None;
+    }
+    let sequence = u32::from_le_bytes(rest[1..].try_into().unwrap());
+    Some((sequence, data))
+}
+
+/// Which of the two copies, by the sequence numbers of those intact, is
+/// the newest one.
+pub fn newest(sequences: [Option<u32>; 2]) -> Option<usize> {
+    match sequences {
+        [Some(a), Some(b)] => Some(if b > a { 1 } else { 0 }),
+        [Some(_), None] => Some(0),
+        [None, Some(_)] => Some(1),
+        [None, None] => None,
+    }
+}
+
+/// The copy to write next and its sequence number, the older one so the
+/// newest stays intact until the write is through.
+pub fn next(sequences: [Option<u32>; 2]) -> (usize, u32) {
+    match newest(sequences) {
+        // Sequence numbers run out long after the flash wears out
+        Some(copy) => (1 - copy, sequences[copy].unwrap() + 1),
+        None => (0, 0),
+    }
+}
+
+// used + crc
+pub const SLOT_OVERHEAD: usize = 1 + CRC_LEN;
+
+/// A slot within a record, checked on its own so one corrupted slot
+/// doesn't take the others with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot<'a> {
+    Empty,
+    Used(&'a [u8]),
+    /// Doesn't match its CRC.
+    Corrupted,
+}
+
+/// Wraps `data` into a used `slot`, [`SLOT_OVERHEAD`] longer than it. The
+/// CRC covers the used byte and the data.
+pub fn seal_slot(data: &[u8], slot: &mut [u8]) {
+    assert_eq!(slot.len(), SLOT_OVERHEAD + data.len());
+    slot[0] = 1;
+    slot[1..=data.len()].copy_from_slice(data);
+    let (covered, crc) = slot.split_at_mut(slot.len() - CRC_LEN);
+    crc.copy_from_slice(&crc32(covered).to_le_bytes());
+}
+
+pub fn open_slot(slot: &[u8]) -> Slot<'_> {
+    let Some((covered, crc)) = slot
+        .len()
+        .checked_sub(CRC_LEN)
+        .and_then(|len| slot.split_at_checked(len))
+    else {
+        return Slot::Corrupted;
+    };
+    match covered.split_first() {
+        Some((0, _)) => Slot::Empty,
+        Some((_, data)) if crc32(covered).to_le_bytes() == crc => Slot::Used(data),
+        _ => Slot::Corrupted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIND: Kind = Kind {
+        magic: *b"TEST",
+        version: 3,
+    };
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 3 + 1) as u8).collect()
+    }
+
+    fn sealed(sequence: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; record_len(data.len())];
+        seal(KIND, sequence, data, &mut record);
+        record
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 41, 150] {
+            let data = data(len);
+            let record = sealed(42, &data);
+            assert_eq!(open(KIND, &record), Some((42, &data[..])));
+        }
+    }
+
+    #[test]
+    fn flipped_bit_is_caught() {
+        let record = sealed(7, &data(30));
+        for i in 0..record.len() {
+            for bit in 0..8 {
+                let mut corrupted = record.clone();
+                corrupted[i] ^= 1 << bit;
+                assert_eq!(open(KIND, &corrupted), None, "byte {i} bit {bit}");
+            }
+        }
+    }
+
+    #[test]
+    fn erased_and_torn_records_dont_open() {
+        let record = sealed(7, &data(30));
+        assert_eq!(open(KIND, &vec![0xff; record.len()]), None);
+        // Torn anywhere, the rest of the sector still erased
+        for len in 0..record.len() {
+            let mut torn = vec![0xff; record.len()];
+            torn[..len].copy_from_slice(&record[..len]);
+            assert_eq!(open(KIND, &torn), None, "torn after {len} bytes");
+        }
+        // Too short to even hold a header
+        assert_eq!(open(KIND, &[]), None);
+        assert_eq!(open(KIND, &record[..HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn other_kinds_dont_open() {
+        let data = data(8);
+        let mut record = vec![0; record_len(data.len())];
+        let other = Kind {
+            magic: *b"TSET",
+            ..KIND
+        };
+        seal(other, 1, &data, &mut record);
+        assert_eq!(open(KIND, &record), None);
+        let newer = Kind {
+            version: KIND.version + 1,
+            ..KIND
+        };
+        seal(newer, 1, &data, &mut record);
+        assert_eq!(open(KIND, &record), None);
+    }
+
+    #[test]
+    fn newest_copy() {
+        assert_eq!(newest([None, None]), None);
+        assert_eq!(newest([Some(3), None]), Some(0));
+        assert_eq!(newest([None, Some(3)]), Some(1));
+        assert_eq!(newest([Some(3), Some(4)]), Some(1));
+        assert_eq!(newest([Some(5), Some(4)]), Some(0));
+    }
+
+    #[test]
+    fn writes_alternate() {
+        // Nothing stored yet
+        assert_eq!(next([None, None]), (0, 0));
+        // Always over the older copy
+        assert_eq!(next([Some(0), None]), (1, 1));
+        assert_eq!(next([Some(0), Some(1)]), (0, 2));
+        assert_eq!(next([Some(2), Some(1)]), (1, 3));
+        // The newer copy is torn, the older one stays and gets overwritten
+        assert_eq!(next([Some(2), None]), (1, 3));
+        assert_eq!(next([None, Some(3)]), (0, 4));
+    }
+
+    #[test]
+    fn interrupted_write_keeps_last_good_copy() {
+        let old = data(20);
+        let new: Vec<u8> = old.iter().map(|b| !b).collect();
+        let mut copies = [sealed(0, &old), vec![0xff; record_len(old.len())]];
+        let sequences =
+            |copies: &[Vec<u8>; 2]| copies.clone().map(|c| open(KIND, &c).map(|(s, _)| s));
+        let (copy, sequence) = next(sequences(&copies));
+        assert_eq!(copy, 1);
+        // Power lost halfway through writing the new copy
+        let record = sealed(sequence, &new);
+        let half = record.len() / 2;
+        copies[copy][..half].copy_from_slice(&record[..half]);
+        let newest = newest(sequences(&copies)).unwrap();
+        assert_eq!(open(KIND, &copies[newest]), Some((0, &old[..])));
+    }
+
+    #[test]
+    fn slot_round_trip() {
+        let data = data(41);
+        let mut slot = vec![0; SLOT_OVERHEAD + data.len()];
+        seal_slot(&data, &mut slot);
+        assert_eq!(open_slot(&slot), Slot::Used(&data));
+        // A single flipped bit anywhere is caught
+        for i in 0..slot.len() {
+            let mut corrupted = slot.clone();
+            corrupted[i] ^= 0x10;
+            assert_eq!(open_slot(&corrupted), Slot::Corrupted, "byte {i}");
+        }
+    }
+
+    #[test]
+    fn empty_and_erased_slots() {
+        assert_eq!(open_slot(&[0; SLOT_OVERHEAD + 41]), Slot::Empty);
+        // Left behind by a torn write
+        assert_eq!(open_slot(&[0xff; SLOT_OVERHEAD + 41]), Slot::Corrupted);
+        assert_eq!(open_slot(&[1]), Slot::Corrupted);
+    }
+}
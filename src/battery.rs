@@ -0,0 +1,227 @@
+use async_debounce::Debouncer;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::*;
+use embassy_rp::{
+    Peri,
+    adc::{Adc, Async, Channel},
+    gpio::{AnyPin, Input, Pull},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+// The Pico W senses VSYS on GPIO29, which is shared with the cyw43 SPI
+// clock, so the battery is read through an external divider instead.
+/// Ratio of the voltage divider between the battery and the ADC pin.
+const DIVIDER: u32 = 2;
+const ADC_REF_MV: u32 = 3300;
+const ADC_MAX: u32 = 4096;
+
+const SAMPLE_INTERVAL_SECS: u64 = 60;
+/// Minimum change in percent before a new level is reported, so a reading
+/// sitting on a boundary doesn't flip back and forth.
+const HYSTERESIS_PCT: u8 = 2;
+
+/// The ADC is shared with the potentiometer input, lock it for every read.
+pub type SharedAdc = Mutex<ThreadModeRawMutex, Adc<'static, Async>>;
+
+/// Chargers like the TP4056 flicker their status line when the battery
+/// is almost full, so it has to hold for this long.
+const CHARGE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Discharge curve of a single cell LiPo as (millivolts, percent),
+/// sorted by voltage. Values in between are interpolated. It's stretched
+/// over the calibrated empty and full voltages.
+const CURVE: [(u16, u8); 9] = [
+    (3300, 0),
+    (3500, 5),
+    (3600, 10),
+    (3700, 30),
+    (3800, 55),
+    (3900, 70),
+    (4000, 80),
+    (4100, 90),
+    (4200, 100),
+];
+
+const CURVE_EMPTY_MV: u16 = CURVE[0].0;
+const CURVE_FULL_MV: u16 = CURVE[CURVE.len() - 1].0;
+
+/// Battery voltages read as 0 and 100 %, configurable over GATT for other
+/// chemistries and dividers. They default to the ends of [`CURVE`].
+pub static EMPTY_MV: AtomicU16 = AtomicU16::new(CURVE_EMPTY_MV);
+pub static FULL_MV: AtomicU16 = AtomicU16::new(CURVE_FULL_MV);
+pub const EMPTY_DEFAULT_MV: u16 = CURVE_EMPTY_MV;
+pub const FULL_DEFAULT_MV: u16 = CURVE_FULL_MV;
+
+/// Lowest and highest Battery Level, also the Valid Range descriptor of the
+/// characteristic.
+pub const LEVEL_RANGE: [u8; 2] = [0, 100];
+
+/// Keeps `pct` within [`LEVEL_RANGE`], hosts may reject anything above.
+pub const fn clamp_level(pct: u8) -> u8 {
+    if pct > LEVEL_RANGE[1] {
+        LEVEL_RANGE[1]
+    } else {
+        pct
+    }
+}
+
+const _: () = {
+    core::assert!(clamp_level(0) == 0);
+    core::assert!(clamp_level(42) == 42);
+    core::assert!(clamp_level(100) == 100);
+    core::assert!(clamp_level(101) == 100);
+    core::assert!(clamp_level(u8::MAX) == 100);
+    // Every curve point is a level the host takes as is
+    let mut i = 0;
+    while i < CURVE.len() {
+        core::assert!(clamp_level(CURVE[i].1) == CURVE[i].1);
+        i += 1;
+    }
+};
+
+/// Latest reported battery percentage, picked up by the BLE task.
+pub static BATTERY_LEVEL: Signal<ThreadModeRawMutex, u8> = Signal::new();
+/// Whether the battery is charging, never signaled without a charge status pin.
+pub static CHARGING: Signal<ThreadModeRawMutex, bool> = Signal::new();
+
+/// Whether `empty_mv` and `full_mv` can be calibrated to.
+pub const fn calibration_allowed(empty_mv: u16, full_mv: u16) -> bool {
+    full_mv > empty_mv
+}
+
+/// Percentage of a battery at `mv`, with [`CURVE`] stretched from
+/// `empty_mv` to `full_mv`.
+pub const fn voltage_to_percent(mv: u16, empty_mv: u16, full_mv: u16) -> u8 {
+    if mv <= empty_mv {
+        return CURVE[0].1;
+    }
+    if mv >= full_mv {
+        return CURVE[CURVE.len() - 1].1;
+    }
+    // Where `mv` falls on the curve
+    let mv = CURVE_EMPTY_MV as u32
+        + (mv - empty_mv) as u32 * (CURVE_FULL_MV - CURVE_EMPTY_MV) as u32
+            / (full_mv - empty_mv) as u32;
+
+    let mut i = 1;
+    while i < CURVE.len() {
+        let (lo_mv, lo_pct) = CURVE[i - 1];
+        let (hi_mv, hi_pct) = CURVE[i];
+        if mv <= hi_mv as u32 {
+            let pct = lo_pct as u32
+                + (mv - lo_mv as u32) * (hi_pct - lo_pct) as u32 / (hi_mv - lo_mv) as u32;
+            return pct as u8;
+        }
+        i += 1;
+    }
+    CURVE[CURVE.len() - 1].1
+}
+
+// The uncalibrated curve, point by point
+const _: () = {
+    let (empty, full) = (EMPTY_DEFAULT_MV, FULL_DEFAULT_MV);
+    // Past either end reads as empty or full
+    core::assert!(voltage_to_percent(0, empty, full) == 0);
+    core::assert!(voltage_to_percent(CURVE_EMPTY_MV - 1, empty, full) == 0);
+    core::assert!(voltage_to_percent(CURVE_FULL_MV + 1, empty, full) == 100);
+    core::assert!(voltage_to_percent(u16::MAX, empty, full) == 100);
+    // Every knee reads as itself, halfway to the next one as halfway
+    // between them, and the level never drops as the voltage rises
+    let mut i = 0;
+    while i < CURVE.len() {
+        let (mv, pct) = CURVE[i];
+        core::assert!(voltage_to_percent(mv, empty, full) == pct);
+        if i + 1 < CURVE.len() {
+            let (next_mv, next_pct) = CURVE[i + 1];
+            let mid = voltage_to_percent((mv + next_mv) / 2, empty, full);
+            core::assert!(mid == (pct + next_pct) / 2);
+            let mut at = mv;
+            while at < next_mv {
+                core::assert!(
+                    voltage_to_percent(at + 1, empty, full) >= voltage_to_percent(at, empty, full)
+                );
+                at += 1;
+            }
+        }
+        i += 1;
+    }
+};
+
+const _: () = {
+    let (empty, full) = (EMPTY_DEFAULT_MV, FULL_DEFAULT_MV);
+    // The defaults are the curve as is
+    core::assert!(voltage_to_percent(3700, empty, full) == 30);
+    core::assert!(voltage_to_percent(3650, empty, full) == 20);
+    core::assert!(voltage_to_percent(3000, empty, full) == 0);
+    core::assert!(voltage_to_percent(4300, empty, full) == 100);
+    // Stretched over half the range, e.g. a pack behind another divider
+    core::assert!(voltage_to_percent(1650, 1650, 2100) == 0);
+    core::assert!(voltage_to_percent(1850, 1650, 2100) == 30);
+    core::assert!(voltage_to_percent(2100, 1650, 2100) == 100);
+    core::assert!(calibration_allowed(3300, 4200));
+    core::assert!(!calibration_allowed(4200, 4200));
+    core::assert!(!calibration_allowed(4200, 3300));
+};
+
+pub const fn exceeds_hysteresis(reported: u8, measured: u8) -> bool {
+    reported.abs_diff(measured) >= HYSTERESIS_PCT
+}
+
+// Only a change of the full threshold is reported, whichever way it goes
+const _: () = {
+    core::assert!(!exceeds_hysteresis(50, 50));
+    core::assert!(!exceeds_hysteresis(50, 50 + HYSTERESIS_PCT - 1));
+    core::assert!(!exceeds_hysteresis(50, 50 - (HYSTERESIS_PCT - 1)));
+    core::assert!(exceeds_hysteresis(50, 50 + HYSTERESIS_PCT));
+    core::assert!(exceeds_hysteresis(50, 50 - HYSTERESIS_PCT));
+    core::assert!(exceeds_hysteresis(0, 100) && exceeds_hysteresis(100, 0));
+};
+
+fn raw_to_millivolts(raw: u16) -> u16 {
+    (raw as u32 * ADC_REF_MV * DIVIDER / ADC_MAX) as u16
+}
+
+#[embassy_executor::task]
+pub async fn battery_monitor(adc: &'static SharedAdc, mut channel: Channel<'static>) {
+    let mut reported: Option<u8> = None;
+
+    loop {
+        let result = adc.lock().await.read(&mut channel).await;
+        match result {
+            Ok(raw) => {
+                let mv = raw_to_millivolts(raw);
+                let pct = voltage_to_percent(
+                    mv,
+                    EMPTY_MV.load(Ordering::Relaxed),
+                    FULL_MV.load(Ordering::Relaxed),
+                );
+                debug!("[battery] {} mV, {}%", mv, pct);
+                if reported.is_none_or(|reported| exceeds_hysteresis(reported, pct)) {
+                    reported = Some(pct);
+                    BATTERY_LEVEL.signal(pct);
+                }
+            }
+            Err(e) => warn!("[battery] error reading ADC: {:?}", e),
+        }
+        Timer::after_secs(SAMPLE_INTERVAL_SECS).await;
+    }
+}
+
+/// Watches an active low charge status line, like the CHRG pin of a TP4056.
+#[embassy_executor::task]
+pub async fn charge_monitor(pin: Peri<'static, AnyPin>) {
+    // The line is open drain
+    let mut pin = Debouncer::new(Input::new(pin, Pull::Up), CHARGE_DEBOUNCE);
+
+    loop {
+        // Infallible errors
+        let charging = pin.is_low().unwrap();
+        info!("[battery] charging: {}", charging);
+        CHARGING.signal(charging);
+        pin.wait_for_any_edge().await.unwrap();
+    }
+}
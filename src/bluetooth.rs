@@ -1,103 +1,814 @@
-use crate::{KEY_PRESS_CHANNEL, hid};
-use defmt::{panic, *};
-use embassy_futures::{join::join, select::select};
-use embassy_time::Timer;
+use crate::{
+    ACTIVITY, KEY_PRESS_CHANNEL, SLEEP, SWITCH_HOST,
+    battery::{self, BATTERY_LEVEL, CHARGING},
+    diagnostics::{self, DIAGNOSTICS_LEN, DISCONNECTS, DROPPED_REPORTS, ROTATION_LOG_BYTES},
+    event::{self, FwEvent},
+    fatal::{self, FatalError},
+    gatt::{self, Reject},
+    hid,
+    knob::{self, KNOB_EVENTS, KnobEvent, MODE_CHANGED},
+    led::{CONN_STATE, ConnState},
+    log::{self, LOG_LEVEL, debug, info},
+    power::{POWER_OFF, RADIO_POWER, RADIO_STOPPED, RadioPower},
+    storage::{Bonds, SETTINGS_LEN, Settings, Storage},
+    transport::{
+        self, KeySender, PRESS_ADAPTIVE, PRESS_DEFAULT_MS, PRESS_MAX_MS, PRESS_MIN_MS, PRESS_MS,
+    },
+    watchdog::{BLE_HEARTBEAT, HEARTBEAT_INTERVAL},
+};
+use core::{
+    future::pending,
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicI8, AtomicU8, Ordering},
+};
+
+use bt_hci::{
+    cmd::{
+        le::{LeConnUpdate, LeReadLocalSupportedFeatures},
+        status::ReadRssi,
+    },
+    controller::{ControllerCmdAsync, ControllerCmdSync},
+};
+use cortex_m::peripheral::SCB;
+use defmt::*;
+use embassy_futures::{
+    join::{join3, join5},
+    select::{Either, Either3, Either4, select, select3, select4},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_deadline, with_timeout};
 use rand_core::{CryptoRng, RngCore};
 use trouble_host::prelude::*;
 
-use {defmt_rtt as _, panic_probe as _};
+/// Links the host and the GATT server keep state for. The connection loop
+/// serves one host at a time, advertising stops while it's connected, so
+/// more only matter for forks serving several centrals at once. Each one
+/// costs about 570 bytes of RAM in the host, plus a CCCD table in the
+/// server.
+pub const CONNECTIONS_MAX: usize = 1;
+/// L2CAP channels on top of the fixed ATT and SMP ones, which every link
+/// gets anyway. The knob opens none itself. Each one costs about 220 bytes
+/// of RAM.
+pub const L2CAP_CHANNELS_MAX: usize = 4;
+const _: () = core::assert!(
+    CONNECTIONS_MAX >= 1,
+    "the knob needs at least one connection"
+);
+
+/// Disconnect after this long without knob or GATT activity to save power.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Put the radio into power save after this long without activity.
+const POWER_SAVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the runner is given to tell the host before the radio is cut off.
+const POWER_OFF_GRACE: Duration = Duration::from_millis(200);
+
+/// Delay after the first advertising error, doubled on every one after it.
+const ADV_RETRY_DELAY_MS: u64 = 1000;
+const ADV_RETRY_DELAY_MAX_MS: u64 = 30_000;
+/// Up to this much is added to every retry delay, so knobs failing
+/// together don't retry in lockstep.
+const ADV_RETRY_JITTER_MS: u32 = 250;
+/// Reset the device after this many advertising errors in a row.
+const ADV_MAX_FAILURES: u8 = 10;
+
+/// How long to wait after `failures` advertising errors in a row, `random`
+/// picks the jitter.
+const fn adv_retry_delay_ms(failures: u8, random: u32) -> u64 {
+    let doublings = failures.saturating_sub(1) as u32;
+    let delay = match ADV_RETRY_DELAY_MS.checked_shl(doublings) {
+        Some(delay) if delay < ADV_RETRY_DELAY_MAX_MS => delay,
+        _ => ADV_RETRY_DELAY_MAX_MS,
+    };
+    delay + (random % ADV_RETRY_JITTER_MS) as u64
+}
+
+const _: () = {
+    core::assert!(adv_retry_delay_ms(1, 0) == ADV_RETRY_DELAY_MS);
+    core::assert!(adv_retry_delay_ms(2, 0) == 2 * ADV_RETRY_DELAY_MS);
+    core::assert!(adv_retry_delay_ms(4, 0) == 8 * ADV_RETRY_DELAY_MS);
+    // Capped, also where the shift would overflow
+    core::assert!(adv_retry_delay_ms(10, 0) == ADV_RETRY_DELAY_MAX_MS);
+    core::assert!(adv_retry_delay_ms(u8::MAX, 0) == ADV_RETRY_DELAY_MAX_MS);
+    // The jitter stays below its bound
+    core::assert!(
+        adv_retry_delay_ms(1, u32::MAX) < ADV_RETRY_DELAY_MS + ADV_RETRY_JITTER_MS as u64
+    );
+    core::assert!(
+        adv_retry_delay_ms(10, u32::MAX) < ADV_RETRY_DELAY_MAX_MS + ADV_RETRY_JITTER_MS as u64
+    );
+};
 
-const CONNECTIONS_MAX: usize = 1;
-const L2CAP_CHANNELS_MAX: usize = 4;
+/// Stop advertising and sleep after this long without a connection.
+const ADV_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
-const NAME: &str = "Simple Volume Knob";
+/// How long to wait for the active bonded host before accepting anyone.
+const DIRECTED_ADV_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Intervals of undirected advertising: fast after boot, a disconnect or a
+/// host switch so the host finds the knob quickly, slow after that to save
+/// power.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct AdvSchedule {
+    pub fast_interval: Duration,
+    pub slow_interval: Duration,
+    /// How long the fast interval is kept up.
+    pub fast_for: Duration,
+}
+
+impl AdvSchedule {
+    /// Parameters for advertising at `now` in a window that began at
+    /// `started`, and when they have to change.
+    fn params(&self, started: Instant, now: Instant) -> (AdvertisementParameters, Option<Instant>) {
+        let fast_until = started + self.fast_for;
+        let (interval, until) = if now < fast_until {
+            (self.fast_interval, Some(fast_until))
+        } else {
+            (self.slow_interval, None)
+        };
+        let params = AdvertisementParameters {
+            interval_min: interval,
+            interval_max: interval,
+            ..Default::default()
+        };
+        (params, until)
+    }
+}
 
-#[gatt_server]
+// What Apple's accessory design guidelines recommend
+const ADV_SCHEDULE: AdvSchedule = AdvSchedule {
+    fast_interval: Duration::from_millis(20),
+    slow_interval: Duration::from_micros(417_500),
+    fast_for: Duration::from_secs(30),
+};
+
+#[gatt_service(uuid = "408813df-5dd4-1f87-ec11-cdb001100300")]
+struct KnobService {
+    /// Detents per second the knob is turned at, signed, clockwise positive
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100301", read, notify)]
+    velocity: i8,
+}
+
+/// The velocity drops to zero once no detent came in for this long.
+const VELOCITY_DECAY: Duration = Duration::from_millis(300);
+/// Minimum change in detents per second before a new velocity is notified.
+const VELOCITY_HYSTERESIS: u8 = 2;
+
+/// Connection parameters asked for after connecting, within the ranges
+/// Apple accepts for HID devices. The latency lets the knob skip connection
+/// events while it has nothing to send, without delaying a key press.
+/// A host that vanishes is dropped by the controller once nothing was heard
+/// from it for the supervision timeout, that ends up as a regular
+/// disconnect with [`bt_hci::param::Status::CONN_TIMEOUT`].
+const CONN_PARAMS: ConnectParams = ConnectParams {
+    min_connection_interval: Duration::from_micros(15_000),
+    max_connection_interval: Duration::from_micros(30_000),
+    max_latency: 4,
+    min_event_length: Duration::from_secs(0),
+    max_event_length: Duration::from_secs(0),
+    supervision_timeout: Duration::from_secs(2),
+};
+
+/// Set `SVK_NAME` at build time to tell multiple knobs apart.
+pub const NAME: &str = match option_env!("SVK_NAME") {
+    Some(name) => name,
+    None => "Simple Volume Knob",
+};
+// Limit of the GAP device name in trouble-host
+const _: () = core::assert!(NAME.len() <= 22, "SVK_NAME can be at most 22 bytes long");
+
+/// Services advertised along with the name, whichever of them are built.
+const ADV_SERVICE_UUIDS: &[[u8; 2]] = &[
+    service::HUMAN_INTERFACE_DEVICE.to_le_bytes(),
+    #[cfg(not(feature = "no-battery-service"))]
+    service::BATTERY.to_le_bytes(),
+];
+/// Room left for the name in the 31 byte advertisement after the flags,
+/// the service UUIDs and the length + type header of the name itself.
+const ADV_NAME_MAX: usize = 31 - 3 - (2 + 2 * ADV_SERVICE_UUIDS.len()) - 2;
+/// Names set over GATT have to be advertised whole, unlike [`NAME`].
+pub const DEVICE_NAME_MAX: usize = ADV_NAME_MAX;
+const _: () = core::assert!(DEVICE_NAME_MAX <= 22);
+
+/// Whether `name` can be set over GATT, UTF-8 and short enough for the
+/// advertisement. Zeroes pad the stored name, so they can't be in it.
+pub const fn name_allowed(name: &[u8]) -> bool {
+    gatt::name_allowed(name, DEVICE_NAME_MAX)
+}
+
+/// The name stored by [`Storage::store_name`], `None` when there's none.
+fn stored_name(stored: &[u8; DEVICE_NAME_MAX]) -> Option<&str> {
+    let len = stored
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(DEVICE_NAME_MAX);
+    let name = &stored[..len];
+    name_allowed(name).then(|| core::str::from_utf8(name).unwrap())
+}
+
+// The macro doesn't take `cfg` on the services, so there's a server for
+// each combination of the optional ones
+#[cfg(not(any(feature = "no-battery-service", feature = "no-device-info")))]
+#[gatt_server(connections_max = CONNECTIONS_MAX)]
 struct Server {
     battery_service: BatteryService,
-    _device_info: DeviceInformationService,
+    device_info: DeviceInformationService,
     hid: HidService,
+    config: ConfigService,
+    diagnostics: DiagnosticsService,
+    knob: KnobService,
+}
+#[cfg(all(feature = "no-battery-service", not(feature = "no-device-info")))]
+#[gatt_server(connections_max = CONNECTIONS_MAX)]
+struct Server {
+    device_info: DeviceInformationService,
+    hid: HidService,
+    config: ConfigService,
+    diagnostics: DiagnosticsService,
+    knob: KnobService,
+}
+#[cfg(all(not(feature = "no-battery-service"), feature = "no-device-info"))]
+#[gatt_server(connections_max = CONNECTIONS_MAX)]
+struct Server {
+    battery_service: BatteryService,
+    hid: HidService,
+    config: ConfigService,
+    diagnostics: DiagnosticsService,
+    knob: KnobService,
+}
+#[cfg(all(feature = "no-battery-service", feature = "no-device-info"))]
+#[gatt_server(connections_max = CONNECTIONS_MAX)]
+struct Server {
+    hid: HidService,
+    config: ConfigService,
+    diagnostics: DiagnosticsService,
+    knob: KnobService,
+}
+
+/// The battery service, unless it's left out with `no-battery-service`.
+fn battery_service<'a>(server: &'a Server<'_>) -> Option<&'a BatteryService> {
+    #[cfg(not(feature = "no-battery-service"))]
+    return Some(&server.battery_service);
+    #[cfg(feature = "no-battery-service")]
+    {
+        let _ = server;
+        None
+    }
+}
+
+/// The device information service, unless it's left out with
+/// `no-device-info`.
+fn device_info<'a>(server: &'a Server<'_>) -> Option<&'a DeviceInformationService> {
+    #[cfg(not(feature = "no-device-info"))]
+    return Some(&server.device_info);
+    #[cfg(feature = "no-device-info")]
+    {
+        let _ = server;
+        None
+    }
 }
 
 #[gatt_service(uuid = service::BATTERY)]
 struct BatteryService {
-    /// Battery Level
-    #[descriptor(uuid = descriptors::VALID_RANGE, read, value = [0, 100])]
-    #[descriptor(uuid = descriptors::MEASUREMENT_DESCRIPTION, name = "hello", read, value = "Battery Level")]
-    #[characteristic(uuid = characteristic::BATTERY_LEVEL, read, notify, value = 100)]
+    /// Battery Level, only ever set through [`battery::clamp_level`]. The
+    /// Valid Range is the lower and upper bound as u8 like the level, the
+    /// description is UTF-8 without a terminator
+    #[descriptor(uuid = descriptors::VALID_RANGE, read, value = battery::LEVEL_RANGE)]
+    #[descriptor(uuid = descriptors::MEASUREMENT_DESCRIPTION, read, value = "Battery Level")]
+    #[characteristic(uuid = characteristic::BATTERY_LEVEL, read, notify, value = battery::LEVEL_RANGE[1])]
     level: u8,
-    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100000", write, read, notify)]
+    /// Whether the battery is charging
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100000", read, notify)]
     status: bool,
 }
 
-const MANFUCATURER: [u8; 7] = *b"RatLabs";
-const MODEL_NUMBER_DATA: [u8; 7] = *b"SVK-1.0";
+#[gatt_service(uuid = "408813df-5dd4-1f87-ec11-cdb001100100")]
+struct ConfigService {
+    /// Volume steps sent per detent, 1 to 10
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100101", read, write, value = 1)]
+    steps_per_detent: u8,
+    /// 0 only logs warnings and errors, 1 adds info and 2 debug logs
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100102", read, write, value = log::LOG_DEBUG)]
+    log_level: u8,
+    /// Write [`FACTORY_RESET`] to forget all hosts and settings and reboot,
+    /// [`TEST_BURST`] to send [`TEST_BURST_SCRIPT`] or
+    /// [`RESTART_ADVERTISING`] to drop the connection and advertise again
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100103", write)]
+    command: u8,
+    /// Actions for clockwise, counter clockwise and 1 to 4 clicks in a row,
+    /// see [`knob::action_allowed`]. 0 keeps the default.
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100104", read, write)]
+    clockwise_action: u8,
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100105", read, write)]
+    counter_clockwise_action: u8,
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100106", read, write)]
+    click_action: u8,
+    /// 1 swaps the directions, on top of the `invert-direction` feature
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100107", read, write)]
+    invert_direction: bool,
+    /// All of the others, the mode and the button debounce time at once,
+    /// laid out like [`Settings::encode`]. A write has to be valid as a whole
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100108", read, write)]
+    settings: [u8; SETTINGS_LEN],
+    /// How long keys are held in ms, 20 to 200, or 0 to adapt it to how
+    /// quickly the host takes reports
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100109", read, write, value = PRESS_DEFAULT_MS)]
+    press_duration: u8,
+    /// Seconds between reads of the host's RSSI, 0 turns them off
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010a", read, write, value = RSSI_INTERVAL_DEFAULT_SECS)]
+    rssi_interval: u8,
+    /// The knob locks once the RSSI stays below this many dBm and unlocks
+    /// once the host comes back, 0 turns it off
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010b", read, write)]
+    lock_rssi: i8,
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010c", read, write)]
+    double_click_action: u8,
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010d", read, write)]
+    triple_click_action: u8,
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010e", read, write)]
+    quadruple_click_action: u8,
+    /// Battery voltages in mV read as 0 and 100 %, each a little endian
+    /// u16. Full has to be above empty
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb00110010f", read, write)]
+    battery_calibration: [u8; 4],
+    /// Advertised and GAP name, UTF-8 up to [`DEVICE_NAME_MAX`] bytes. The
+    /// knob disconnects and advertises it right away, the GAP name is fixed
+    /// while it runs and only changes on the next boot
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100110", read, write)]
+    device_name: HeaplessString<DEVICE_NAME_MAX>,
+}
+
+/// Read only counters for debugging knobs in the field.
+#[gatt_service(uuid = "408813df-5dd4-1f87-ec11-cdb001100200")]
+struct DiagnosticsService {
+    /// Uptime in seconds, right detents, left detents, disconnects, stuck
+    /// encoder pins and dropped key reports since boot, each a little
+    /// endian u32
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100201", read, notify)]
+    counters: [u8; DIAGNOSTICS_LEN],
+    /// See [`security_status`], readable on any link to debug pairing
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100202", read)]
+    security: u8,
+    /// Signal strength of the host in dBm, [`RSSI_UNAVAILABLE`] until it
+    /// was read on this connection
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100203", read, notify, value = RSSI_UNAVAILABLE)]
+    rssi: i8,
+    /// The last decoded rotations, jitter included, see
+    /// [`diagnostics::rotation_log`]
+    #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100204", read, value = [0; ROTATION_LOG_BYTES])]
+    rotation_log: [u8; ROTATION_LOG_BYTES],
+}
+
+/// The RSSI value HCI reports when there's none, the same on the
+/// characteristic.
+pub const RSSI_UNAVAILABLE: i8 = 127;
+pub const RSSI_INTERVAL_DEFAULT_SECS: u8 = 5;
+/// Polling looks at its interval this often while it's turned off.
+const RSSI_OFF_RECHECK: Duration = Duration::from_secs(5);
+/// Readings in a row below the lock threshold before the knob locks, one
+/// weak reading is just noise.
+const RSSI_WEAK_READINGS: u8 = 3;
+/// How far above the lock threshold the host has to come back to unlock.
+const RSSI_UNLOCK_HYSTERESIS: i8 = 6;
+
+/// Seconds between RSSI reads, 0 turns them off.
+pub static RSSI_INTERVAL_SECS: AtomicU8 = AtomicU8::new(RSSI_INTERVAL_DEFAULT_SECS);
+/// RSSI in dBm below which the knob locks itself, 0 turns it off.
+pub static LOCK_RSSI: AtomicI8 = AtomicI8::new(0);
+// The knob was locked by the host walking away, not by a click
+static PROXIMITY_LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// With the `secure-only` feature, hosts have this long to authenticate
+/// after connecting, long enough to confirm a passkey.
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A report the host doesn't take within this is tried again.
+const NOTIFY_TIMEOUT: Duration = Duration::from_millis(200);
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_millis(20);
+/// Tries at sending a key press before it's dropped.
+const NOTIFY_ATTEMPTS: u8 = 3;
+
+/// How often subscribed hosts get the diagnostics counters.
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Values of the security status characteristic
+const SECURITY_UNBONDED: u8 = 0;
+const SECURITY_BONDED_UNENCRYPTED: u8 = 1;
+const SECURITY_BONDED_ENCRYPTED: u8 = 2;
+const SECURITY_BONDED_AUTHENTICATED: u8 = 3;
+
+/// Command written to the config service to wipe the flash and reboot.
+const FACTORY_RESET: u8 = 0xA5;
+/// Command written to the config service to check the host reacts to keys.
+const TEST_BURST: u8 = 0x01;
+/// Command written to the config service to disconnect and advertise
+/// again, for debugging connections without a power cycle.
+const RESTART_ADVERTISING: u8 = 0x5A;
+/// Keys sent on [`TEST_BURST`], each step `count` times.
+const TEST_BURST_SCRIPT: [(KeyPressed, u8); 3] = [
+    (KeyPressed::VolUp, 3),
+    (KeyPressed::VolDown, 3),
+    (KeyPressed::Mute, 1),
+];
+/// Between the steps of [`TEST_BURST_SCRIPT`], so the host shows each one.
+const TEST_BURST_PAUSE: Duration = Duration::from_millis(500);
+
+/// With the `demo` feature, [`TEST_BURST_SCRIPT`] is also sent on its own
+/// this long after connecting and after every burst.
+const DEMO_INTERVAL: Duration = Duration::from_secs(10);
+
+static TEST_BURST_REQUESTED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Set `SVK_MANUFACTURER` and `SVK_MODEL` at build time to rebrand the knob.
+pub const MANUFACTURER_STR: &str = match option_env!("SVK_MANUFACTURER") {
+    Some(manufacturer) => manufacturer,
+    None => "RatLabs",
+};
+const MODEL_NUMBER_STR: &str = match option_env!("SVK_MODEL") {
+    Some(model) => model,
+    None => "SVK-1.0",
+};
+// So they're read back whole in a single read with the default MTU
+const _: () = core::assert!(
+    MANUFACTURER_STR.len() <= 20,
+    "SVK_MANUFACTURER can be at most 20 bytes long"
+);
+const _: () = core::assert!(
+    MODEL_NUMBER_STR.len() <= 20,
+    "SVK_MODEL can be at most 20 bytes long"
+);
+pub const MANUFACTURER_DATA: [u8; MANUFACTURER_STR.len()] = fixed_str(MANUFACTURER_STR);
+#[deprecated(note = "misspelled, use `MANUFACTURER_DATA`")]
+pub const MANFUCATURER: [u8; MANUFACTURER_DATA.len()] = MANUFACTURER_DATA;
+pub const MODEL_NUMBER_DATA: [u8; MODEL_NUMBER_STR.len()] = fixed_str(MODEL_NUMBER_STR);
+const FIRMWARE_REVISION_STR: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+const FIRMWARE_REVISION_DATA: [u8; FIRMWARE_REVISION_STR.len()] = fixed_str(FIRMWARE_REVISION_STR);
+const HARDWARE_REVISION_DATA: [u8; 6] = fixed_str("Pico W");
+/// Hex of the 8 byte flash unique ID, filled in at startup.
+pub const SERIAL_NUMBER_LEN: usize = 16;
+
+/// Copies `s` into an array, cutting it off or padding it with spaces to fit.
+const fn fixed_str<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut buf = [b' '; N];
+    let mut i = 0;
+    while i < N && i < bytes.len() {
+        buf[i] = bytes[i];
+        i += 1;
+    }
+    buf
+}
+
+pub fn serial_number(unique_id: [u8; 8]) -> [u8; SERIAL_NUMBER_LEN] {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut buf = [0u8; SERIAL_NUMBER_LEN];
+    for (chunk, byte) in buf.chunks_exact_mut(2).zip(unique_id) {
+        chunk[0] = HEX[(byte >> 4) as usize];
+        chunk[1] = HEX[(byte & 0xf) as usize];
+    }
+    buf
+}
 
 #[gatt_service(uuid = service::DEVICE_INFORMATION)]
 struct DeviceInformationService {
-    #[characteristic(uuid = characteristic::MANUFACTURER_NAME_STRING, read, value = MANFUCATURER)]
-    manufacturer_name: [u8; 7],
+    #[characteristic(uuid = characteristic::MANUFACTURER_NAME_STRING, read, value = MANUFACTURER_DATA)]
+    manufacturer_name: [u8; MANUFACTURER_DATA.len()],
     #[characteristic(uuid = characteristic::MODEL_NUMBER_STRING, read, value = MODEL_NUMBER_DATA)]
-    model_number: [u8; 7],
+    model_number: [u8; MODEL_NUMBER_DATA.len()],
+    #[characteristic(uuid = characteristic::FIRMWARE_REVISION_STRING, read, value = FIRMWARE_REVISION_DATA)]
+    firmware_revision: [u8; FIRMWARE_REVISION_DATA.len()],
+    #[characteristic(uuid = characteristic::HARDWARE_REVISION_STRING, read, value = HARDWARE_REVISION_DATA)]
+    hardware_revision: [u8; HARDWARE_REVISION_DATA.len()],
+    #[characteristic(uuid = characteristic::SERIAL_NUMBER_STRING, read)]
+    serial_number: [u8; SERIAL_NUMBER_LEN],
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum KeyPressed {
     VolUp,
     VolDown,
     Mute,
+    PlayPause,
+    NextTrack,
+    PrevTrack,
+    /// Mute sent as a keyboard key, for apps ignoring consumer control.
+    KeyboardMute,
+    /// Right arrow
+    NextSlide,
+    /// Left arrow
+    PrevSlide,
+    /// Blanks the screen in most presentation apps.
+    BlankScreen,
+    /// Page Down, for presentation apps and remotes ignoring the arrows
+    NextPage,
+    /// Page Up
+    PrevPage,
+    /// One notch of the mouse wheel.
+    ScrollUp,
+    ScrollDown,
     None,
 }
 
 type InputRaport = [u8; 2];
 
 impl KeyPressed {
-    pub fn as_report(&self) -> InputRaport {
-        let value = match self {
-            KeyPressed::VolUp => 0b0000_0001,
-            KeyPressed::VolDown => 0b0000_0010,
-            KeyPressed::Mute => 0b0000_0100,
-            KeyPressed::None => 0b0000_0000,
+    pub const fn as_report(&self) -> InputRaport {
+        let key = match self {
+            KeyPressed::KeyboardMute => Some(hid::KEYBOARD_MUTE),
+            KeyPressed::NextSlide => Some(hid::KEYBOARD_RIGHT_ARROW),
+            KeyPressed::PrevSlide => Some(hid::KEYBOARD_LEFT_ARROW),
+            KeyPressed::BlankScreen => Some(hid::KEYBOARD_B),
+            KeyPressed::NextPage => Some(hid::KEYBOARD_PAGE_DOWN),
+            KeyPressed::PrevPage => Some(hid::KEYBOARD_PAGE_UP),
+            _ => None,
+        };
+        if let Some(key) = key {
+            return [hid::HID_REPORT_KEYBOARD_ID, key];
+        }
+
+        // The wheel is relative, a signed count of notches
+        match self {
+            KeyPressed::ScrollUp => return [hid::HID_REPORT_MOUSE_ID, 1],
+            KeyPressed::ScrollDown => return [hid::HID_REPORT_MOUSE_ID, -1i8 as u8],
+            _ => {}
+        }
+
+        let usage = match self {
+            KeyPressed::VolUp => hid::USAGE_VOLUME_UP,
+            KeyPressed::VolDown => hid::USAGE_VOLUME_DOWN,
+            KeyPressed::Mute => hid::USAGE_MUTE,
+            KeyPressed::PlayPause => hid::USAGE_PLAY_PAUSE,
+            KeyPressed::NextTrack => hid::USAGE_NEXT_TRACK,
+            KeyPressed::PrevTrack => hid::USAGE_PREV_TRACK,
+            // Nothing pressed
+            _ => return [hid::HID_REPORT_INPUT_ID, 0],
         };
-        [hid::HID_REPORT_INPUT_ID, value]
+        [hid::HID_REPORT_INPUT_ID, hid::consumer_bit(usage)]
     }
 
-    async fn send<P: PacketPool>(
+    #[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+    const ALL: [KeyPressed; 15] = [
+        KeyPressed::VolUp,
+        KeyPressed::VolDown,
+        KeyPressed::Mute,
+        KeyPressed::PlayPause,
+        KeyPressed::NextTrack,
+        KeyPressed::PrevTrack,
+        KeyPressed::KeyboardMute,
+        KeyPressed::NextSlide,
+        KeyPressed::PrevSlide,
+        KeyPressed::BlankScreen,
+        KeyPressed::NextPage,
+        KeyPressed::PrevPage,
+        KeyPressed::ScrollUp,
+        KeyPressed::ScrollDown,
+        KeyPressed::None,
+    ];
+
+    /// Decodes an action byte of the config service. 0 and unknown
+    /// values give `None`, the byte values must never change.
+    pub const fn from_action(action: u8) -> Option<Self> {
+        Some(match action {
+            1 => KeyPressed::VolUp,
+            2 => KeyPressed::VolDown,
+            3 => KeyPressed::Mute,
+            4 => KeyPressed::PlayPause,
+            5 => KeyPressed::NextTrack,
+            6 => KeyPressed::PrevTrack,
+            7 => KeyPressed::KeyboardMute,
+            8 => KeyPressed::NextSlide,
+            9 => KeyPressed::PrevSlide,
+            10 => KeyPressed::BlankScreen,
+            11 => KeyPressed::ScrollUp,
+            12 => KeyPressed::ScrollDown,
+            13 => KeyPressed::NextPage,
+            14 => KeyPressed::PrevPage,
+            _ => return None,
+        })
+    }
+
+    fn report(&self, server: &Server<'_>) -> Characteristic<InputRaport> {
+        match self.as_report()[0] {
+            hid::HID_REPORT_KEYBOARD_ID => server.hid.keyboard_input,
+            hid::HID_REPORT_MOUSE_ID => server.hid.mouse_input,
+            _ => server.hid.input,
+        }
+    }
+
+    async fn press<P: PacketPool>(
         &self,
         conn: &GattConnection<'_, '_, P>,
         server: &Server<'_>,
     ) -> Result<(), trouble_host::Error> {
-        let report = server.hid.input;
+        notify_report(self.report(server), conn, &self.as_report(), false).await
+    }
+
+    async fn release<P: PacketPool>(
+        &self,
+        conn: &GattConnection<'_, '_, P>,
+        server: &Server<'_>,
+    ) -> Result<(), trouble_host::Error> {
+        // Nothing pressed, or no movement, is all zeroes in every report
+        let id = self.as_report()[0];
+        notify_report(self.report(server), conn, &[id, 0], true).await
+    }
+}
+
+/// Notifies a key report, waiting out a host or controller that can't take
+/// it for a moment. A press is dropped after [`NOTIFY_ATTEMPTS`] and
+/// counted. A release is tried until it goes out or the link is gone,
+/// so a key can't stay held on the host.
+async fn notify_report<P: PacketPool>(
+    report: Characteristic<InputRaport>,
+    conn: &GattConnection<'_, '_, P>,
+    value: &InputRaport,
+    release: bool,
+) -> Result<(), trouble_host::Error> {
+    let mut attempt: u8 = 0;
+    loop {
+        attempt = attempt.saturating_add(1);
+        match with_timeout(NOTIFY_TIMEOUT, report.notify(conn, value)).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) if !is_recoverable(&e) => return Err(e),
+            Ok(Err(e)) => debug!("[hid] report not sent, attempt {}: {:?}", attempt, e),
+            Err(_) => debug!("[hid] report timed out, attempt {}", attempt),
+        }
+        if !release && attempt >= NOTIFY_ATTEMPTS {
+            warn!("[hid] host isn't taking reports, dropping {:?}", value);
+            diagnostics::count(&DROPPED_REPORTS);
+            return Ok(());
+        }
+        Timer::after(NOTIFY_RETRY_DELAY).await;
+    }
+}
+
+// The report bits and keys have to match what the report descriptor
+// declares
+#[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+const _: () = {
+    let consumer = [
+        (KeyPressed::VolUp, hid::USAGE_VOLUME_UP),
+        (KeyPressed::VolDown, hid::USAGE_VOLUME_DOWN),
+        (KeyPressed::Mute, hid::USAGE_MUTE),
+        (KeyPressed::PlayPause, hid::USAGE_PLAY_PAUSE),
+        (KeyPressed::NextTrack, hid::USAGE_NEXT_TRACK),
+        (KeyPressed::PrevTrack, hid::USAGE_PREV_TRACK),
+    ];
+    let mut i = 0;
+    while i < consumer.len() {
+        let (key, usage) = consumer[i];
+        let report = key.as_report();
+        core::assert!(
+            report[0] == hid::HID_REPORT_INPUT_ID && report[1] == hid::consumer_bit(usage),
+            "consumer report bit doesn't match the descriptor"
+        );
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < KeyPressed::ALL.len() {
+        let report = KeyPressed::ALL[i].as_report();
+        if report[0] == hid::HID_REPORT_KEYBOARD_ID {
+            core::assert!(
+                report[1] <= hid::keyboard_usage_max(),
+                "keyboard key outside of the descriptor's usage range"
+            );
+        }
+        i += 1;
+    }
 
-        report.notify(conn, &self.as_report()).await?;
+    // Nothing pressed is a report of its own
+    core::assert!(matches!(
+        KeyPressed::None.as_report(),
+        [hid::HID_REPORT_INPUT_ID, 0]
+    ));
+};
 
-        Timer::after_millis(50).await;
+// Commands written to the HID Control Point
+const HID_CONTROL_SUSPEND: u8 = 0x00;
+const HID_CONTROL_EXIT_SUSPEND: u8 = 0x01;
 
-        report.notify(conn, &KeyPressed::None.as_report()).await
+// Values of the Protocol Mode characteristic
+const PROTOCOL_MODE_BOOT: u8 = 0x00;
+const PROTOCOL_MODE_REPORT: u8 = 0x01;
+
+/// Custom logic run on every connection, for forks that want to do more
+/// than the knob does without touching the connection loop. Keys queued
+/// with [`knob::send_key`] go out once the connection's tasks run, e.g. a
+/// welcome sequence from [`ConnHooks::on_connect`]. The watchdog isn't
+/// fed while a hook runs, so they have to be done within a few seconds.
+// Only used on the single threaded executor, like `led::StatusLed`
+#[allow(async_fn_in_trait)]
+pub trait ConnHooks {
+    /// Called once a host connected and got the initial state, before any
+    /// of the connection's tasks run.
+    async fn on_connect(&mut self, _conn: &Connection<'_, DefaultPacketPool>) {}
+
+    /// Called when the connection ends, before the knob goes back to
+    /// advertising, idles, switches hosts or powers off.
+    async fn on_disconnect(&mut self, _conn: &Connection<'_, DefaultPacketPool>) {}
+}
+
+/// Does nothing on either.
+pub struct NoHooks;
+
+impl ConnHooks for NoHooks {}
+
+/// How hosts pair with the knob and what link they need afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PairingPolicy {
+    /// Pairs without a passkey, for headless builds. Only encrypts the
+    /// link, there's no protection against a man in the middle.
+    JustWorks,
+    /// Confirms the passkey without asking anyone, it's only logged.
+    AutoConfirm,
+    /// Waits for a click of the knob to confirm the passkey shown by the
+    /// host. Needs the encoder button, so not with `input-pot`.
+    ButtonConfirm,
+}
+
+impl PairingPolicy {
+    fn io_capabilities(self) -> IoCapabilities {
+        match self {
+            PairingPolicy::JustWorks => IoCapabilities::NoInputNoOutput,
+            PairingPolicy::AutoConfirm | PairingPolicy::ButtonConfirm => {
+                IoCapabilities::DisplayYesNo
+            }
+        }
+    }
+
+    /// Whether a link at `level` may read, write and get reports.
+    fn secure(self, level: SecurityLevel) -> bool {
+        match self {
+            // Just Works can't authenticate
+            PairingPolicy::JustWorks => level.encrypted(),
+            PairingPolicy::AutoConfirm | PairingPolicy::ButtonConfirm => level.authenticated(),
+        }
     }
 }
 
+/// With [`PairingPolicy::ButtonConfirm`], the host is given this long to
+/// have the passkey confirmed, within the 30 s SMP timeout.
+const PASSKEY_CONFIRM_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Set while a passkey waits for [`PASSKEY_CONFIRMED`], a click signals
+/// that instead of sending its key.
+pub static AWAITING_CONFIRMATION: AtomicBool = AtomicBool::new(false);
+pub static PASSKEY_CONFIRMED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// With `absolute-volume`, set while the host is subscribed to the volume
+/// report. The pot sends steps while it isn't.
+pub static ABSOLUTE_VOLUME: AtomicBool = AtomicBool::new(false);
+/// Volume level in percent sent in the volume report.
+pub static VOLUME_LEVEL: Signal<ThreadModeRawMutex, u8> = Signal::new();
+
+/// Set while the host is suspended, it doesn't want any reports then.
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// A host polling the input reports instead of subscribing reads the last
+/// one sent, `notify` stores it in the attribute table whether or not
+/// anybody is subscribed.
 #[gatt_service(uuid = service::HUMAN_INTERFACE_DEVICE)]
 struct HidService {
     #[characteristic(uuid = characteristic::HID_INFORMATION, read, value = [0x01, 0x01, 0x00, 0x03])]
     hid_info: [u8; 4],
     #[characteristic(uuid = characteristic::REPORT_MAP, read, value = hid::HID_REPORT_DESCRIPTOR)]
-    report_map: [u8; 31],
+    report_map: [u8; hid::HID_REPORT_DESCRIPTOR.len()],
     #[characteristic(uuid = characteristic::HID_CONTROL_POINT, write_without_response)]
     hid_control_point: u8,
-    #[characteristic(uuid = characteristic::PROTOCOL_MODE, read, write_without_response, value = 1)]
+    #[characteristic(uuid = characteristic::PROTOCOL_MODE, read, write_without_response, value = PROTOCOL_MODE_REPORT)]
     protocol_mode: u8,
-    #[descriptor(uuid = descriptors::REPORT_REFERENCE, read, value = [0u8, hid::HID_REPORT_INPUT_ID])]
+    #[descriptor(uuid = descriptors::REPORT_REFERENCE, read, value = [hid::HID_REPORT_INPUT_ID, hid::HID_REPORT_TYPE_INPUT])]
     #[characteristic(uuid = characteristic::REPORT, read, notify, value = [hid::HID_REPORT_INPUT_ID, 0u8])]
     input: InputRaport,
+    #[descriptor(uuid = descriptors::REPORT_REFERENCE, read, value = [hid::HID_REPORT_KEYBOARD_ID, hid::HID_REPORT_TYPE_INPUT])]
+    #[characteristic(uuid = characteristic::REPORT, read, notify, value = [hid::HID_REPORT_KEYBOARD_ID, 0u8])]
+    keyboard_input: InputRaport,
+    #[descriptor(uuid = descriptors::REPORT_REFERENCE, read, value = [hid::HID_REPORT_MOUSE_ID, hid::HID_REPORT_TYPE_INPUT])]
+    #[characteristic(uuid = characteristic::REPORT, read, notify, value = [hid::HID_REPORT_MOUSE_ID, 0u8])]
+    mouse_input: InputRaport,
+    #[descriptor(uuid = descriptors::REPORT_REFERENCE, read, value = [hid::HID_REPORT_VOLUME_ID, hid::HID_REPORT_TYPE_INPUT])]
+    #[characteristic(uuid = characteristic::REPORT, read, notify, value = [hid::HID_REPORT_VOLUME_ID, 0u8])]
+    volume_input: InputRaport,
 }
 
-pub async fn run_bluetooth<C, RNG>(controller: C, mut rng: RNG)
-where
-    C: Controller,
+/// Runs the BLE stack forever, this never returns.
+pub async fn run_bluetooth<C, RNG>(
+    controller: C,
+    mut rng: RNG,
+    storage: &mut Storage<'_>,
+    policy: PairingPolicy,
+    mut hooks: impl ConnHooks,
+) where
+    C: Controller
+        + ControllerCmdAsync<LeConnUpdate>
+        + ControllerCmdSync<LeReadLocalSupportedFeatures>
+        + ControllerCmdSync<ReadRssi>,
     RNG: RngCore + CryptoRng,
 {
-    let mut bond_info: Option<BondInformation> = None;
+    let mut bonds: Bonds = storage.load_bonds();
+    let stored = storage.load_name();
+    let name = stored_name(&stored).unwrap_or(NAME);
+    info!("Device name = {}", name);
 
     let address: Address = Address::random([0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff]);
     info!("Device address = {:?}", address);
@@ -107,7 +818,17 @@ where
     let stack = trouble_host::new(controller, &mut resources)
         .set_random_address(address)
         .set_random_generator_seed(&mut rng)
-        .set_io_capabilities(IoCapabilities::DisplayYesNo);
+        .set_io_capabilities(policy.io_capabilities());
+
+    info!("Pairing policy: {:?}", policy);
+    for bond in bonds.iter() {
+        info!("Restoring bond: {:?}", bond.identity);
+        if let Err(e) = stack.add_bond_information(bond.clone()) {
+            error!("[ble] error restoring bond: {:?}", e);
+            fatal::halt(FatalError::BondRestore).await;
+        }
+    }
+    info!("Active host slot: {}", bonds.active_slot());
 
     let Host {
         mut peripheral,
@@ -117,144 +838,700 @@ where
 
     info!("Starting advertising and GATT service");
 
-    let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: NAME,
+    let server = match Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name,
         appearance: &appearance::human_interface_device::KEYBOARD,
-    }))
-    .unwrap();
+    })) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("[ble] error building the GATT server: {}", e);
+            fatal::halt(FatalError::ServerBuild).await
+        }
+    };
+    server
+        .set(
+            &server.config.steps_per_detent,
+            &knob::STEPS_PER_DETENT.load(Ordering::Relaxed),
+        )
+        .unwrap();
+    let serial = serial_number(storage.unique_id());
+    info!(
+        "Firmware {}, serial {}",
+        FIRMWARE_REVISION_STR,
+        core::str::from_utf8(&serial).unwrap()
+    );
+    if let Some(device_info) = device_info(&server) {
+        server.set(&device_info.serial_number, &serial).unwrap();
+    }
+    server
+        .set(
+            &server.config.invert_direction,
+            &knob::INVERT_DIRECTION.load(Ordering::Relaxed),
+        )
+        .unwrap();
+    server
+        .set(
+            &server.config.press_duration,
+            &PRESS_MS.load(Ordering::Relaxed),
+        )
+        .unwrap();
+    server
+        .set(
+            &server.config.rssi_interval,
+            &RSSI_INTERVAL_SECS.load(Ordering::Relaxed),
+        )
+        .unwrap();
+    server
+        .set(&server.config.lock_rssi, &LOCK_RSSI.load(Ordering::Relaxed))
+        .unwrap();
+    server
+        .set(
+            &server.config.device_name,
+            &HeaplessString::try_from(name).unwrap(),
+        )
+        .unwrap();
+    server
+        .set(
+            &server.config.battery_calibration,
+            &encode_calibration(
+                battery::EMPTY_MV.load(Ordering::Relaxed),
+                battery::FULL_MV.load(Ordering::Relaxed),
+            ),
+        )
+        .unwrap();
+    for (event, characteristic) in action_characteristics(&server) {
+        server
+            .set(
+                &characteristic,
+                &knob::ACTIONS[event as usize].load(Ordering::Relaxed),
+            )
+            .unwrap();
+    }
 
-    let _ = join(ble_task(runner), async {
-        loop {
-            match advertise(NAME, &mut peripheral, &server).await {
-                Ok(conn) => {
-                    conn.raw().set_bondable(bond_info.is_none()).unwrap();
+    let error = select(ble_task(runner), async {
+        let mut adv_failures: u8 = 0;
+        let connections = async {
+            // Where the fast advertising window began
+            let mut adv_started = Instant::now();
+            loop {
+                // A name set over GATT is advertised from the next cycle on
+                let stored = storage.load_name();
+                let adv_name = stored_name(&stored).unwrap_or(NAME);
+                let advertised = beat_until(select4(
+                    advertise(
+                        adv_name,
+                        &mut peripheral,
+                        &server,
+                        bonds.active(),
+                        &ADV_SCHEDULE,
+                        adv_started,
+                    ),
+                    SWITCH_HOST.wait(),
+                    Timer::after(ADV_TIMEOUT),
+                    // Advertising goes on, starting over would reset the
+                    // directed advertising and the timeout
+                    store_mode_changes(storage),
+                ))
+                .await;
+                match advertised {
+                    Either4::First(Ok(conn)) => {
+                        adv_failures = 0;
+                        CONN_STATE.signal(ConnState::Connected);
+                        // Drop rotations queued up while nobody was listening
+                        KEY_PRESS_CHANNEL.clear();
+                        SUSPENDED.store(false, Ordering::Relaxed);
+                        // Only an empty slot takes a new host
+                        conn.raw().set_bondable(bonds.active().is_none()).unwrap();
+                        request_conn_params(&stack, &conn).await;
+                        update_security_status(&server, &conn, &bonds);
+                        update_absolute_volume(&server, &conn);
+                        if let Err(e) = send_initial_state(&server, &conn).await {
+                            warn!("[conn] error sending initial state: {:?}", e);
+                        }
+                        hooks.on_connect(conn.raw()).await;
 
-                    let a = gatt_events_task(&server, &conn, &mut bond_info);
-                    let b = key_receiver_task(&server, &conn);
+                        let a = gatt_events_task(&server, &conn, &mut bonds, storage, policy);
+                        let b = key_receiver_task(&server, &conn, policy);
+                        let c = join3(
+                            join5(
+                                battery_level_task(&server, &conn),
+                                diagnostics_task(&server, &conn),
+                                authentication_task(&conn, policy),
+                                test_burst_task(),
+                                velocity_task(&server, &conn),
+                            ),
+                            rssi_task(&stack, &server, &conn),
+                            volume_level_task(&server, &conn, policy),
+                        );
+                        let d = idle_task(&conn);
 
-                    select(a, b).await;
-                }
-                Err(e) => {
-                    let e = defmt::Debug2Format(&e);
-                    panic!("[adv] error: {:?}", e);
+                        // Powering off is waited for here as well, so the hooks
+                        // see that disconnect like any other
+                        let ended =
+                            select3(select4(a, b, c, d), SWITCH_HOST.wait(), POWER_OFF.wait())
+                                .await;
+                        hooks.on_disconnect(conn.raw()).await;
+                        match ended {
+                            Either3::First(Either4::Fourth(_)) => {
+                                // Stay quiet until the knob is touched again
+                                CONN_STATE.signal(ConnState::Idle);
+                                ACTIVITY.reset();
+                                beat_until(ACTIVITY.wait()).await;
+                                info!("[idle] woken up");
+                            }
+                            Either3::Second(_) => {
+                                conn.raw().disconnect();
+                                switch_host(&mut bonds, storage);
+                            }
+                            Either3::Third(_) => {
+                                conn.raw().disconnect();
+                                break None;
+                            }
+                            _ => {}
+                        }
+                        ABSOLUTE_VOLUME.store(false, Ordering::Relaxed);
+                        // However the connection ended, a click is a tap again
+                        end_confirmation();
+                        adv_started = Instant::now();
+                    }
+                    Either4::Second(_) => {
+                        switch_host(&mut bonds, storage);
+                        adv_started = Instant::now();
+                    }
+                    Either4::Third(_) => {
+                        info!("[adv] nobody connected, sleeping");
+                        CONN_STATE.signal(ConnState::Idle);
+                        ACTIVITY.reset();
+                        // Without the encoder (input-pot) nothing sleeps,
+                        // the radio just stays quiet until the pot is moved
+                        SLEEP.signal(());
+                        beat_until(ACTIVITY.wait()).await;
+                        SLEEP.reset();
+                        info!("[adv] woken up");
+                        adv_started = Instant::now();
+                    }
+                    Either4::Fourth(never) => never,
+                    Either4::First(Err(e)) => {
+                        adv_failures += 1;
+                        // Controller errors mean the link to the cyw43 itself is broken
+                        let fatal = if matches!(e, BleHostError::Controller(_)) {
+                            Some(FatalError::ControllerFailed)
+                        } else {
+                            (adv_failures >= ADV_MAX_FAILURES)
+                                .then_some(FatalError::AdvertisingFailed)
+                        };
+                        let e = defmt::Debug2Format(&e);
+                        if let Some(fatal) = fatal {
+                            error!("[adv] unrecoverable error: {:?}", e);
+                            break Some(fatal);
+                        }
+                        let delay = adv_retry_delay_ms(adv_failures, rng.next_u32());
+                        warn!("[adv] error: {:?}, retrying in {} ms", e, delay);
+                        CONN_STATE.signal(ConnState::Error);
+                        beat_until(Timer::after_millis(delay)).await;
+                    }
                 }
             }
+        };
+        // Dropping the advertiser ends it, a connection ends on its own
+        if let Either::First(Some(error)) = select(connections, POWER_OFF.wait()).await {
+            return error;
         }
+        info!("[power] powering off, radio stopped");
+        CONN_STATE.signal(ConnState::Idle);
+        Timer::after(POWER_OFF_GRACE).await;
+        RADIO_STOPPED.signal(());
+        // Stopped for good, not stuck
+        beat_until(pending()).await
     })
     .await;
+    match error {
+        Either::First(error) | Either::Second(error) => fatal::halt(error).await,
+    }
+}
+
+/// Waits for `fut`, beating [`BLE_HEARTBEAT`] until it's done. Only for
+/// the waits of the connection loop, it stops beating when the loop gets
+/// stuck anywhere else.
+async fn beat_until<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let mut heartbeat = Ticker::every(HEARTBEAT_INTERVAL);
+    loop {
+        BLE_HEARTBEAT.beat();
+        if let Either::First(output) = select(fut.as_mut(), heartbeat.next()).await {
+            return output;
+        }
+    }
 }
 
-async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) {
+async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) -> FatalError {
     loop {
         if let Err(e) = runner.run().await {
             let e = defmt::Debug2Format(&e);
-            panic!("[ble_task] error: {:?}", e);
+            error!("[ble_task] error: {:?}", e);
+            return FatalError::HostStopped;
         }
     }
 }
 
 async fn advertise<'values, 'server, C: Controller>(
-    name: &'values str,
+    name: &str,
     peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
     server: &'server Server<'values>,
+    bond: Option<&BondInformation>,
+    schedule: &AdvSchedule,
+    started: Instant,
 ) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
+    if let Some(bond) = bond {
+        let peer = identity_address(&bond.identity);
+        let advertiser = peripheral
+            .advertise(
+                &Default::default(),
+                Advertisement::ConnectableNonscannableDirected { peer },
+            )
+            .await?;
+        debug!("[adv] advertising directed to {:?}", peer);
+        event::record(FwEvent::AdvStart { directed: true });
+        CONN_STATE.signal(ConnState::Reconnecting);
+        if let Ok(conn) = with_timeout(DIRECTED_ADV_TIMEOUT, advertiser.accept()).await {
+            let conn = conn?.with_attribute_server(server)?;
+            event::record(FwEvent::Connected);
+            return Ok(conn);
+        }
+        info!("[adv] bonded host didn't reconnect");
+    }
+
+    // The GAP device name always holds the full name
+    let name = if name.len() > ADV_NAME_MAX {
+        AdStructure::ShortenedLocalName(&name.as_bytes()[..name.floor_char_boundary(ADV_NAME_MAX)])
+    } else {
+        AdStructure::CompleteLocalName(name.as_bytes())
+    };
+
     let mut advertiser_data = [0; 31];
     let len = AdStructure::encode_slice(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::CompleteLocalName(name.as_bytes()),
-            AdStructure::ServiceUuids16(&[
-                service::HUMAN_INTERFACE_DEVICE.to_le_bytes(),
-                service::BATTERY.to_le_bytes(),
-            ]),
+            name,
+            AdStructure::ServiceUuids16(ADV_SERVICE_UUIDS),
         ],
         &mut advertiser_data[..],
     )?;
-    let advertiser = peripheral
-        .advertise(
-            &Default::default(),
-            Advertisement::ConnectableScannableUndirected {
-                adv_data: &advertiser_data[..len],
-                scan_data: &[],
-            },
-        )
-        .await?;
-    info!("[adv] advertising");
-    let conn = advertiser.accept().await?.with_attribute_server(server)?;
-    info!("[adv] connection established");
-    Ok(conn)
+    loop {
+        let (params, change_at) = schedule.params(started, Instant::now());
+        let advertiser = peripheral
+            .advertise(
+                &params,
+                Advertisement::ConnectableScannableUndirected {
+                    adv_data: &advertiser_data[..len],
+                    scan_data: &[],
+                },
+            )
+            .await?;
+        debug!("[adv] interval {} ms", params.interval_min.as_millis());
+        event::record(FwEvent::AdvStart { directed: false });
+        CONN_STATE.signal(ConnState::Advertising);
+        let accepted = match change_at {
+            Some(at) => with_deadline(at, advertiser.accept()).await,
+            None => Ok(advertiser.accept().await),
+        };
+        // Dropping the advertiser stops it, it's started over slower
+        let Ok(conn) = accepted else {
+            continue;
+        };
+        let conn = conn?;
+        // Resolves the private addresses of hosts that shared their IRK
+        if let Some(bond) = bond
+            && !cfg!(feature = "accept-any-host")
+            && !bond.identity.match_address(&conn.peer_address())
+        {
+            warn!(
+                "[adv] rejecting {:?}, not the bonded host",
+                conn.peer_address()
+            );
+            conn.disconnect();
+            continue;
+        }
+        let conn = conn.with_attribute_server(server)?;
+        event::record(FwEvent::Connected);
+        return Ok(conn);
+    }
+}
+
+fn switch_host(bonds: &mut Bonds, storage: &mut Storage<'_>) {
+    bonds.switch();
+    match bonds.active() {
+        Some(bond) => info!(
+            "[bond] switched to slot {}: {:?}",
+            bonds.active_slot(),
+            bond.identity
+        ),
+        None => info!("[bond] switched to empty slot {}", bonds.active_slot()),
+    }
+    storage.store_bonds(bonds);
+}
+
+/// Asks the host for shorter connection intervals, if it refuses the
+/// connection just goes on with the parameters it picked.
+async fn request_conn_params<C, P>(stack: &Stack<'_, C, P>, conn: &GattConnection<'_, '_, P>)
+where
+    C: Controller
+        + ControllerCmdAsync<LeConnUpdate>
+        + ControllerCmdSync<LeReadLocalSupportedFeatures>,
+    P: PacketPool,
+{
+    match conn
+        .raw()
+        .update_connection_params(stack, &CONN_PARAMS)
+        .await
+    {
+        Ok(_) => info!("[conn] connection parameter update requested"),
+        Err(e) => {
+            let e = defmt::Debug2Format(&e);
+            warn!("[conn] connection parameter update rejected: {:?}", e);
+        }
+    }
+}
+
+/// Guesses the kind of an identity address, the bond doesn't store it.
+/// Random static addresses have the two most significant bits set.
+fn identity_address(identity: &Identity) -> Address {
+    let kind = if identity.bd_addr.raw()[5] & 0b1100_0000 == 0b1100_0000 {
+        AddrKind::RANDOM
+    } else {
+        AddrKind::PUBLIC
+    };
+    Address {
+        kind,
+        addr: identity.bd_addr,
+    }
 }
 
-async fn gatt_events_task<P: PacketPool>(
+// The server is built for the default packet pool, its CCCD table can't
+// be looked up for connections of any other
+async fn gatt_events_task(
     server: &Server<'_>,
-    conn: &GattConnection<'_, '_, P>,
-    bond_info: &mut Option<BondInformation>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    bonds: &mut Bonds,
+    storage: &mut Storage<'_>,
+    policy: PairingPolicy,
 ) -> Result<(), Error> {
+    // Until when a click can confirm the passkey, events keep being
+    // handled while waiting for it
+    let mut confirm_deadline: Option<Instant> = None;
+    // Wakes the loop up to beat while no events come in
+    let mut heartbeat = Ticker::every(HEARTBEAT_INTERVAL);
     let reason = loop {
-        match conn.next().await {
-            GattConnectionEvent::Disconnected { reason } => break reason,
+        BLE_HEARTBEAT.beat();
+        let confirmation = async {
+            match confirm_deadline {
+                Some(at) => with_deadline(at, PASSKEY_CONFIRMED.wait()).await.is_ok(),
+                None => pending().await,
+            }
+        };
+        let event = match select4(
+            conn.next(),
+            MODE_CHANGED.wait(),
+            confirmation,
+            heartbeat.next(),
+        )
+        .await
+        {
+            Either4::First(event) => event,
+            Either4::Second(_) => {
+                store_mode(storage);
+                continue;
+            }
+            Either4::Fourth(_) => continue,
+            Either4::Third(confirmed) => {
+                confirm_deadline = None;
+                end_confirmation();
+                let result = if confirmed {
+                    info!("[auth] passkey confirmed");
+                    conn.pass_key_confirm()
+                } else {
+                    warn!("[auth] passkey not confirmed in time, cancelling");
+                    conn.pass_key_cancel()
+                };
+                // Until pairing completes or fails
+                CONN_STATE.signal(ConnState::Pairing);
+                recover(result, "[auth] error answering passkey")?;
+                continue;
+            }
+        };
+        ACTIVITY.signal(());
+        match event {
+            GattConnectionEvent::Disconnected { reason } => {
+                diagnostics::count(&DISCONNECTS);
+                break reason;
+            }
+            GattConnectionEvent::ConnectionParamsUpdated {
+                conn_interval,
+                peripheral_latency,
+                supervision_timeout,
+            } => {
+                info!(
+                    "[conn] parameters updated: interval {} us, latency {}, timeout {} ms",
+                    conn_interval.as_micros(),
+                    peripheral_latency,
+                    supervision_timeout.as_millis()
+                );
+            }
             GattConnectionEvent::PassKeyDisplay(key) => {
-                info!("[gatt] passkey display: {}", key);
+                CONN_STATE.signal(ConnState::Pairing);
+                event::record(FwEvent::PairingStarted {
+                    passkey: Some(key.value()),
+                });
             }
-            GattConnectionEvent::PassKeyConfirm(_) => {
-                // Always confirm
-                info!("[auth] PassKeyConfirm event");
-                conn.pass_key_confirm()?;
+            GattConnectionEvent::PassKeyConfirm(key) => {
+                CONN_STATE.signal(ConnState::Pairing);
+                event::record(FwEvent::PairingStarted {
+                    passkey: Some(key.value()),
+                });
+                if policy == PairingPolicy::ButtonConfirm {
+                    confirm_deadline = Some(request_confirmation());
+                } else {
+                    recover(conn.pass_key_confirm(), "[auth] error answering passkey")?;
+                }
             }
             GattConnectionEvent::PassKeyInput => {
-                info!("[auth] PassKeyInput event");
+                CONN_STATE.signal(ConnState::Pairing);
+                event::record(FwEvent::PairingStarted { passkey: None });
             }
 
             GattConnectionEvent::PairingComplete {
                 security_level,
                 bond,
             } => {
-                info!(
-                    "[auth] pairing complete: {:?}, bond: {:?}",
-                    security_level, bond
-                );
-                *bond_info = bond;
+                confirm_deadline = None;
+                end_confirmation();
+                event::record(FwEvent::PairingComplete {
+                    level: security_level,
+                    bonded: bond.is_some(),
+                });
+                CONN_STATE.signal(ConnState::Connected);
+                if let Some(bond) = bond
+                    && bonds.set_active_bond(bond)
+                {
+                    storage.store_bonds(bonds);
+                }
+                update_security_status(server, conn, bonds);
+                // Keys pressed before this were dropped, make sure the
+                // host starts from nothing pressed
+                if policy.secure(security_level)
+                    && let Err(e) = send_initial_state(server, conn).await
+                {
+                    warn!("[auth] error sending state: {:?}", e);
+                }
             }
             GattConnectionEvent::PairingFailed(err) => {
-                error!("[auth] pairing error: {:?}", err);
+                // The host may give up before the click came
+                confirm_deadline = None;
+                end_confirmation();
+                event::record(FwEvent::PairingFailed { error: err });
+                CONN_STATE.signal(ConnState::Connected);
+            }
+            GattConnectionEvent::Gatt { event } => {
+                let result = handle_gatt_event(event, server, conn, storage, policy).await;
+                recover(result, "[gatt] error handling event")?;
             }
-            GattConnectionEvent::Gatt { event } => handle_gatt_event(event, server, conn).await?,
             _ => {}
         }
     };
-    info!("[gatt] disconnected: {:?}", reason);
+    event::record(FwEvent::Disconnected { reason });
     Ok(())
 }
 
-async fn handle_gatt_event<P: PacketPool>(
-    event: GattEvent<'_, '_, P>,
+/// Status byte telling whether the active slot holds a bond and how the
+/// link to it is secured.
+fn security_status(bonded: bool, level: SecurityLevel) -> u8 {
+    match (bonded, level) {
+        (false, _) => SECURITY_UNBONDED,
+        (true, SecurityLevel::NoEncryption) => SECURITY_BONDED_UNENCRYPTED,
+        (true, SecurityLevel::Encrypted) => SECURITY_BONDED_ENCRYPTED,
+        (true, SecurityLevel::EncryptedAuthenticated) => SECURITY_BONDED_AUTHENTICATED,
+    }
+}
+
+fn update_security_status<P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
+    bonds: &Bonds,
+) {
+    let level = conn
+        .raw()
+        .security_level()
+        .unwrap_or(SecurityLevel::NoEncryption);
+    let status = security_status(bonds.active().is_some(), level);
+    info!("[auth] security status: {}, link {:?}", status, level);
+    if let Err(e) = server.set(&server.diagnostics.security, &status) {
+        warn!("[auth] error updating security status: {:?}", e);
+    }
+}
+
+/// Stores the mode every time a long press switched it.
+async fn store_mode_changes(storage: &mut Storage<'_>) -> ! {
+    loop {
+        MODE_CHANGED.wait().await;
+        store_mode(storage);
+    }
+}
+
+fn store_mode(storage: &mut Storage<'_>) {
+    let mut settings = storage.load_settings();
+    settings.mode = knob::mode() as u8;
+    storage.store_settings(&settings);
+}
+
+/// Whether the link can go on after `e`. Only a connection that's gone is
+/// fatal, anything else, like the controller running out of buffers for a
+/// moment, is worth trying again on the next event.
+fn is_recoverable(e: &Error) -> bool {
+    !matches!(e, Error::Disconnected | Error::ChannelClosed)
+}
+
+/// Logs and swallows a recoverable error, passes a fatal one on.
+fn recover(result: Result<(), Error>, context: &str) -> Result<(), Error> {
+    match result {
+        Err(e) if is_recoverable(&e) => {
+            warn!("{}: {:?}, continuing", context, e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+async fn handle_gatt_event(
+    event: GattEvent<'_, '_, DefaultPacketPool>,
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    storage: &mut Storage<'_>,
+    policy: PairingPolicy,
 ) -> Result<(), Error> {
-    let level = server.battery_service.level;
+    let level = battery_service(server).map(|battery| battery.level);
+    let steps_per_detent = server.config.steps_per_detent;
+    let hid_control_point = server.hid.hid_control_point;
+    let mut new_steps = None;
+    let mut control_point = None;
+    let mut new_log_level = None;
+    let mut factory_reset = false;
+    let mut test_burst = false;
+    let mut restart_advertising = false;
+    let mut new_action = None;
+    let mut new_invert = None;
+    let mut new_press = None;
+    let mut new_rssi_interval = None;
+    let mut new_lock_rssi = None;
+    let mut new_calibration = None;
+    let mut new_name = None;
+    let mut new_settings = None;
+    // CCCD writes included, they're only applied once accepted
+    let write = matches!(event, GattEvent::Write(_));
     let result = match &event {
         GattEvent::Read(event) => {
-            if event.handle() == level.handle {
+            if let Some(level) = level
+                && event.handle() == level.handle
+            {
                 let value = server.get(&level);
                 info!("[gatt] Read Event to Level Characteristic: {:?}", value);
             }
-            if conn.raw().security_level()?.authenticated() {
+            if event.handle() == server.diagnostics.counters.handle {
+                server.set(&server.diagnostics.counters, &diagnostics::snapshot())?;
+            }
+            if event.handle() == server.diagnostics.rotation_log.handle {
+                server.set(
+                    &server.diagnostics.rotation_log,
+                    &diagnostics::rotation_log(),
+                )?;
+            }
+            if event.handle() == server.config.settings.handle {
+                server.set(&server.config.settings, &current_settings().encode())?;
+            }
+            if event.handle() == server.diagnostics.security.handle
+                || policy.secure(conn.raw().security_level()?)
+            {
                 None
             } else {
                 Some(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
             }
         }
         GattEvent::Write(event) => {
-            if event.handle() == level.handle {
+            if level.is_some_and(|level| event.handle() == level.handle) {
                 info!(
                     "[gatt] Write Event to Level Characteristic: {:?}",
                     event.data()
                 );
             }
-            if conn.raw().security_level()?.authenticated() {
-                None
-            } else {
+            if event.handle() == steps_per_detent.handle
+                && let [steps] = event.data()
+            {
+                new_steps = Some(*steps);
+            }
+            if event.handle() == hid_control_point.handle
+                && let [command] = event.data()
+            {
+                control_point = Some(*command);
+            }
+            if event.handle() == server.config.log_level.handle
+                && let [level] = event.data()
+            {
+                new_log_level = Some(*level);
+            }
+            if let Some((knob_event, _)) = action_characteristics(server)
+                .into_iter()
+                .find(|(_, c)| c.handle == event.handle())
+                && let [action] = event.data()
+            {
+                new_action = Some((knob_event, *action));
+            }
+            if event.handle() == server.config.invert_direction.handle
+                && let [invert] = event.data()
+            {
+                new_invert = Some(*invert != 0);
+            }
+            if event.handle() == server.config.press_duration.handle
+                && let [ms] = event.data()
+            {
+                new_press = Some(*ms);
+            }
+            if event.handle() == server.config.rssi_interval.handle
+                && let [secs] = event.data()
+            {
+                new_rssi_interval = Some(*secs);
+            }
+            if event.handle() == server.config.lock_rssi.handle
+                && let [rssi] = event.data()
+            {
+                new_lock_rssi = Some(*rssi as i8);
+            }
+            if event.handle() == server.config.battery_calibration.handle
+                && let Ok(data) = event.data().try_into()
+            {
+                new_calibration = Some(decode_calibration(data));
+            }
+            if event.handle() == server.config.device_name.handle && name_allowed(event.data()) {
+                let mut name = [0u8; DEVICE_NAME_MAX];
+                name[..event.data().len()].copy_from_slice(event.data());
+                new_name = Some(name);
+            }
+            factory_reset =
+                event.handle() == server.config.command.handle && event.data() == [FACTORY_RESET];
+            if event.handle() == server.config.settings.handle
+                && let Ok(data) = event.data().try_into()
+            {
+                new_settings = Some(Settings::decode(data));
+            }
+            test_burst =
+                event.handle() == server.config.command.handle && event.data() == [TEST_BURST];
+            restart_advertising = event.handle() == server.config.command.handle
+                && event.data() == [RESTART_ADVERTISING];
+            if !policy.secure(conn.raw().security_level()?) {
                 Some(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+            } else if test_burst && !subscribed_to_test_burst(server, conn) {
+                warn!("[gatt] test burst requested without a subscription to its reports");
+                Some(AttErrorCode::CCCD_IMPROPERLY_CONFIGURED)
+            } else {
+                validate_write(server, event.handle(), event.data())
             }
         }
         _ => None,
@@ -269,15 +1546,826 @@ async fn handle_gatt_event<P: PacketPool>(
         Ok(reply) => reply.send().await,
         Err(e) => warn!("[gatt] error sending response: {:?}", e),
     }
+    if write {
+        update_absolute_volume(server, conn);
+    }
+
+    if result.is_none()
+        && let Some(steps) = new_steps
+    {
+        info!("[gatt] steps per detent set to {}", steps);
+        knob::STEPS_PER_DETENT.store(steps, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.steps_per_detent = steps;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(invert) = new_invert
+    {
+        info!("[gatt] invert direction set to {}", invert);
+        knob::INVERT_DIRECTION.store(invert, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.invert_direction = invert;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(ms) = new_press
+    {
+        info!("[gatt] press duration set to {} ms", ms);
+        PRESS_MS.store(ms, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.press_ms = ms;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(secs) = new_rssi_interval
+    {
+        info!("[gatt] RSSI interval set to {} s", secs);
+        RSSI_INTERVAL_SECS.store(secs, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.rssi_interval_secs = secs;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(rssi) = new_lock_rssi
+    {
+        info!("[gatt] lock RSSI set to {} dBm", rssi);
+        LOCK_RSSI.store(rssi, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.lock_rssi = rssi;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some((empty_mv, full_mv)) = new_calibration
+    {
+        info!("[gatt] battery calibrated to {} - {} mV", empty_mv, full_mv);
+        battery::EMPTY_MV.store(empty_mv, Ordering::Relaxed);
+        battery::FULL_MV.store(full_mv, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.battery_empty_mv = empty_mv;
+        settings.battery_full_mv = full_mv;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some((event, action)) = new_action
+    {
+        info!("[gatt] {:?} action set to {}", event, action);
+        knob::ACTIONS[event as usize].store(action, Ordering::Relaxed);
+        let mut settings = storage.load_settings();
+        settings.actions[event as usize] = action;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(settings) = new_settings
+    {
+        info!("[gatt] settings set to {:?}", settings);
+        apply_settings(server, &settings)?;
+        storage.store_settings(&settings);
+    }
+    if result.is_none()
+        && let Some(name) = new_name
+    {
+        info!("[gatt] device name set to {}", stored_name(&name));
+        storage.store_name(&name);
+        // The connection loop advertises the new name once this one is
+        // gone. The GAP name is only read when the server is built, it
+        // follows on the next boot
+        Timer::after_millis(100).await;
+        conn.raw().disconnect();
+    }
+    // Authentication was checked along with the value
+    if result.is_none() && factory_reset {
+        warn!("[gatt] factory reset requested");
+        storage.factory_reset();
+        // Give the controller a moment to get the write response out
+        Timer::after_millis(100).await;
+        SCB::sys_reset();
+    }
+    if result.is_none() && test_burst {
+        TEST_BURST_REQUESTED.signal(());
+    }
+    // The disconnect ends this connection's tasks like any other, the
+    // connection loop goes back to advertising
+    if result.is_none() && restart_advertising {
+        event::record(FwEvent::AdvRestartRequested);
+        // Give the controller a moment to get the write response out
+        Timer::after_millis(100).await;
+        conn.raw().disconnect();
+    }
+    if result.is_none()
+        && let Some(level) = new_log_level
+    {
+        // Logged before it applies, so turning logs off still shows up
+        defmt::info!("[gatt] log level set to {}", level);
+        LOG_LEVEL.store(level, Ordering::Relaxed);
+    }
+    if result.is_none() {
+        match control_point {
+            Some(HID_CONTROL_SUSPEND) => {
+                info!("[hid] host suspended");
+                SUSPENDED.store(true, Ordering::Relaxed);
+            }
+            Some(HID_CONTROL_EXIT_SUSPEND) => {
+                info!("[hid] host exited suspend");
+                SUSPENDED.store(false, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The remappable knob events and their characteristics.
+fn action_characteristics(server: &Server<'_>) -> [(KnobEvent, Characteristic<u8>); KNOB_EVENTS] {
+    [
+        (KnobEvent::Clockwise, server.config.clockwise_action),
+        (
+            KnobEvent::CounterClockwise,
+            server.config.counter_clockwise_action,
+        ),
+        (KnobEvent::Click, server.config.click_action),
+        (KnobEvent::DoubleClick, server.config.double_click_action),
+        (KnobEvent::TripleClick, server.config.triple_click_action),
+        (
+            KnobEvent::QuadrupleClick,
+            server.config.quadruple_click_action,
+        ),
+    ]
+}
+
+/// Checks a write to `handle` before it's accepted, characteristics without
+/// a validator take any value.
+fn validate_write(server: &Server<'_>, handle: u16, data: &[u8]) -> Option<AttErrorCode> {
+    let error = match action_characteristics(server)
+        .into_iter()
+        .find(|(_, c)| c.handle == handle)
+    {
+        Some((event, _)) => validate_action(event, data),
+        None => match handle {
+            h if h == server.hid.protocol_mode.handle => validate_protocol_mode(data),
+            h if h == server.hid.hid_control_point.handle => validate_control_point(data),
+            h if h == server.config.steps_per_detent.handle => validate_steps_per_detent(data),
+            h if h == server.config.log_level.handle => validate_log_level(data),
+            h if h == server.config.command.handle => validate_command(data),
+            h if h == server.config.invert_direction.handle => gatt::validate_bool(data),
+            h if h == server.config.settings.handle => validate_settings(data),
+            h if h == server.config.press_duration.handle => validate_press_duration(data),
+            h if h == server.config.lock_rssi.handle => validate_lock_rssi(data),
+            h if h == server.config.battery_calibration.handle => validate_calibration(data),
+            h if h == server.config.device_name.handle => {
+                gatt::validate_name(data, DEVICE_NAME_MAX)
+            }
+            _ => None,
+        },
+    };
+    if let Some(e) = error {
+        warn!(
+            "[gatt] rejecting write of {=[u8]:x} to handle {}: {:?}",
+            data, handle, e
+        );
+    }
+    error.map(att_error)
+}
+
+const fn att_error(reject: Reject) -> AttErrorCode {
+    match reject {
+        Reject::Length => AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH,
+        Reject::NotAllowed => AttErrorCode::VALUE_NOT_ALLOWED,
+        Reject::OutOfRange => AttErrorCode::OUT_OF_RANGE,
+        Reject::NotSupported => AttErrorCode::REQUEST_NOT_SUPPORTED,
+    }
+}
+
+const fn validate_control_point(data: &[u8]) -> Option<Reject> {
+    gatt::validate_one_of(data, &[HID_CONTROL_SUSPEND, HID_CONTROL_EXIT_SUSPEND])
+}
+
+const fn validate_steps_per_detent(data: &[u8]) -> Option<Reject> {
+    gatt::validate_in_range(data, 1, knob::STEPS_PER_DETENT_MAX)
+}
+
+const fn validate_press_duration(data: &[u8]) -> Option<Reject> {
+    match data {
+        [PRESS_ADAPTIVE] => None,
+        _ => gatt::validate_in_range(data, PRESS_MIN_MS, PRESS_MAX_MS),
+    }
+}
+
+const fn validate_lock_rssi(data: &[u8]) -> Option<Reject> {
+    match gatt::single(data) {
+        Ok(rssi) if lock_rssi_allowed(rssi as i8) => None,
+        Ok(_) => Some(Reject::OutOfRange),
+        Err(e) => Some(e),
+    }
+}
+
+/// Only a negative RSSI makes sense to lock below, but 0 turns it off.
+pub const fn lock_rssi_allowed(rssi: i8) -> bool {
+    rssi <= 0
+}
+
+/// The battery calibration characteristic, empty and full mV.
+const fn encode_calibration(empty_mv: u16, full_mv: u16) -> [u8; 4] {
+    let [e0, e1] = empty_mv.to_le_bytes();
+    let [f0, f1] = full_mv.to_le_bytes();
+    [e0, e1, f0, f1]
+}
+
+const fn decode_calibration(buf: &[u8; 4]) -> (u16, u16) {
+    (
+        u16::from_le_bytes([buf[0], buf[1]]),
+        u16::from_le_bytes([buf[2], buf[3]]),
+    )
+}
+
+/// Full has to be above empty.
+const fn validate_calibration(data: &[u8]) -> Option<Reject> {
+    let &[e0, e1, f0, f1] = data else {
+        return Some(Reject::Length);
+    };
+    let (empty_mv, full_mv) = decode_calibration(&[e0, e1, f0, f1]);
+    if battery::calibration_allowed(empty_mv, full_mv) {
+        None
+    } else {
+        Some(Reject::OutOfRange)
+    }
+}
+
+const fn validate_action(event: KnobEvent, data: &[u8]) -> Option<Reject> {
+    match gatt::single(data) {
+        Ok(action) if knob::action_allowed(event, action) => None,
+        Ok(_) => Some(Reject::NotAllowed),
+        Err(e) => Some(e),
+    }
+}
+
+const fn validate_button_debounce(data: &[u8]) -> Option<Reject> {
+    gatt::validate_in_range(
+        data,
+        knob::BUTTON_DEBOUNCE_MIN_MS,
+        knob::BUTTON_DEBOUNCE_MAX_MS,
+    )
+}
+
+const fn validate_settings(data: &[u8]) -> Option<Reject> {
+    let (Some(data), SETTINGS_LEN) = (data.first_chunk::<SETTINGS_LEN>(), data.len()) else {
+        return Some(Reject::Length);
+    };
+    let settings = Settings::decode(data);
+    if let Some(e) = validate_steps_per_detent(&[settings.steps_per_detent]) {
+        return Some(e);
+    }
+    let mut i = 0;
+    while i < KNOB_EVENTS {
+        if let Some(e) = validate_action(KnobEvent::ALL[i], &[settings.actions[i]]) {
+            return Some(e);
+        }
+        i += 1;
+    }
+    // Decoding takes any non-zero byte as true
+    if let Some(e) = gatt::validate_bool(&[data[1 + KNOB_EVENTS]]) {
+        return Some(e);
+    }
+    if !knob::mode_allowed(settings.mode) {
+        return Some(Reject::NotAllowed);
+    }
+    if let Some(e) = validate_press_duration(&[settings.press_ms]) {
+        return Some(e);
+    }
+    if let Some(e) = validate_lock_rssi(&[settings.lock_rssi as u8]) {
+        return Some(e);
+    }
+    if let Some(e) = validate_button_debounce(&[settings.button_debounce_ms]) {
+        return Some(e);
+    }
+    validate_calibration(&encode_calibration(
+        settings.battery_empty_mv,
+        settings.battery_full_mv,
+    ))
+}
+
+/// The settings in effect right now.
+fn current_settings() -> Settings {
+    Settings {
+        steps_per_detent: knob::STEPS_PER_DETENT.load(Ordering::Relaxed),
+        actions: core::array::from_fn(|i| knob::ACTIONS[i].load(Ordering::Relaxed)),
+        invert_direction: knob::INVERT_DIRECTION.load(Ordering::Relaxed),
+        mode: knob::mode() as u8,
+        press_ms: PRESS_MS.load(Ordering::Relaxed),
+        rssi_interval_secs: RSSI_INTERVAL_SECS.load(Ordering::Relaxed),
+        lock_rssi: LOCK_RSSI.load(Ordering::Relaxed),
+        battery_empty_mv: battery::EMPTY_MV.load(Ordering::Relaxed),
+        battery_full_mv: battery::FULL_MV.load(Ordering::Relaxed),
+        button_debounce_ms: knob::BUTTON_DEBOUNCE_MS.load(Ordering::Relaxed),
+    }
+}
+
+/// Puts validated settings into effect, keeping the single characteristics
+/// in sync.
+fn apply_settings(server: &Server<'_>, settings: &Settings) -> Result<(), Error> {
+    knob::STEPS_PER_DETENT.store(settings.steps_per_detent, Ordering::Relaxed);
+    server.set(&server.config.steps_per_detent, &settings.steps_per_detent)?;
+    knob::INVERT_DIRECTION.store(settings.invert_direction, Ordering::Relaxed);
+    server.set(&server.config.invert_direction, &settings.invert_direction)?;
+    for (event, characteristic) in action_characteristics(server) {
+        let action = settings.actions[event as usize];
+        knob::ACTIONS[event as usize].store(action, Ordering::Relaxed);
+        server.set(&characteristic, &action)?;
+    }
+    knob::restore_mode(settings.mode);
+    PRESS_MS.store(settings.press_ms, Ordering::Relaxed);
+    server.set(&server.config.press_duration, &settings.press_ms)?;
+    RSSI_INTERVAL_SECS.store(settings.rssi_interval_secs, Ordering::Relaxed);
+    server.set(&server.config.rssi_interval, &settings.rssi_interval_secs)?;
+    LOCK_RSSI.store(settings.lock_rssi, Ordering::Relaxed);
+    server.set(&server.config.lock_rssi, &settings.lock_rssi)?;
+    battery::EMPTY_MV.store(settings.battery_empty_mv, Ordering::Relaxed);
+    battery::FULL_MV.store(settings.battery_full_mv, Ordering::Relaxed);
+    server.set(
+        &server.config.battery_calibration,
+        &encode_calibration(settings.battery_empty_mv, settings.battery_full_mv),
+    )?;
+    knob::BUTTON_DEBOUNCE_MS.store(settings.button_debounce_ms, Ordering::Relaxed);
     Ok(())
 }
 
-async fn key_receiver_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+const fn validate_command(data: &[u8]) -> Option<Reject> {
+    gatt::validate_one_of(data, &[FACTORY_RESET, TEST_BURST, RESTART_ADVERTISING])
+}
+
+const fn validate_log_level(data: &[u8]) -> Option<Reject> {
+    match gatt::validate_in_range(data, log::LOG_QUIET, log::LOG_DEBUG) {
+        Some(Reject::OutOfRange) => Some(Reject::NotAllowed),
+        error => error,
+    }
+}
+
+const fn validate_protocol_mode(data: &[u8]) -> Option<Reject> {
+    match data {
+        // There is no boot protocol for consumer control
+        [PROTOCOL_MODE_BOOT] => Some(Reject::NotSupported),
+        _ => gatt::validate_one_of(data, &[PROTOCOL_MODE_REPORT]),
+    }
+}
+
+// The validators built on the firmware's own limits, at the edges of what
+// they allow and right past them. `gatt` tests the checks they're made of.
+const _: () = {
+    const OK: Option<Reject> = None;
+    const LENGTH: Option<Reject> = Some(Reject::Length);
+    const NOT_ALLOWED: Option<Reject> = Some(Reject::NotAllowed);
+    const RANGE: Option<Reject> = Some(Reject::OutOfRange);
+
+    core::assert!(matches!(validate_press_duration(&[PRESS_ADAPTIVE]), OK));
+    core::assert!(matches!(validate_press_duration(&[PRESS_MIN_MS]), OK));
+    core::assert!(matches!(validate_press_duration(&[PRESS_MAX_MS]), OK));
+    core::assert!(matches!(
+        validate_press_duration(&[PRESS_MIN_MS - 1]),
+        RANGE
+    ));
+    core::assert!(matches!(
+        validate_press_duration(&[PRESS_MAX_MS + 1]),
+        RANGE
+    ));
+    core::assert!(matches!(validate_press_duration(&[]), LENGTH));
+
+    core::assert!(matches!(validate_lock_rssi(&[0]), OK));
+    core::assert!(matches!(validate_lock_rssi(&[i8::MIN as u8]), OK));
+    core::assert!(matches!(validate_lock_rssi(&[1]), RANGE));
+
+    core::assert!(matches!(
+        validate_calibration(&encode_calibration(4200, 4201)),
+        OK
+    ));
+    core::assert!(matches!(
+        validate_calibration(&encode_calibration(4200, 4200)),
+        RANGE
+    ));
+    core::assert!(matches!(validate_calibration(&[0; 3]), LENGTH));
+
+    core::assert!(matches!(
+        validate_action(KnobEvent::Clockwise, &[knob::ACTION_SWITCH_HOST]),
+        NOT_ALLOWED
+    ));
+    core::assert!(matches!(
+        validate_action(KnobEvent::Click, &[knob::ACTION_TOGGLE_LOCK]),
+        OK
+    ));
+
+    core::assert!(matches!(validate_log_level(&[log::LOG_DEBUG]), OK));
+    core::assert!(matches!(
+        validate_log_level(&[log::LOG_DEBUG + 1]),
+        NOT_ALLOWED
+    ));
+    core::assert!(matches!(
+        validate_protocol_mode(&[PROTOCOL_MODE_BOOT]),
+        Some(Reject::NotSupported)
+    ));
+
+    let valid = Settings::DEFAULT;
+    core::assert!(matches!(validate_settings(&valid.encode()), OK));
+    let mut data = valid.encode();
+    data[0] = knob::STEPS_PER_DETENT_MAX + 1;
+    core::assert!(matches!(validate_settings(&data), RANGE));
+    let mut data = valid.encode();
+    data[1 + KNOB_EVENTS] = 2;
+    core::assert!(matches!(validate_settings(&data), NOT_ALLOWED));
+    let mut data = valid.encode();
+    data[2 + KNOB_EVENTS] = u8::MAX;
+    core::assert!(matches!(validate_settings(&data), NOT_ALLOWED));
+    let data = Settings {
+        battery_empty_mv: 4200,
+        battery_full_mv: 3300,
+        ..valid
+    }
+    .encode();
+    core::assert!(matches!(validate_settings(&data), RANGE));
+    let data = Settings {
+        button_debounce_ms: knob::BUTTON_DEBOUNCE_MAX_MS + 1,
+        ..valid
+    }
+    .encode();
+    core::assert!(matches!(validate_settings(&data), RANGE));
+    core::assert!(matches!(validate_settings(&[0; SETTINGS_LEN - 1]), LENGTH));
+    core::assert!(matches!(validate_settings(&[0; SETTINGS_LEN + 1]), LENGTH));
+};
+
+/// Sends keys as HID reports to the connected host.
+struct BleKeys<'a, 'values, 'stack, 'server, P: PacketPool> {
+    server: &'a Server<'values>,
+    conn: &'a GattConnection<'stack, 'server, P>,
+    policy: PairingPolicy,
+}
+
+impl<P: PacketPool> KeySender for BleKeys<'_, '_, '_, '_, P> {
+    type Error = Error;
+
+    fn blocked(&self) -> Option<&'static str> {
+        if SUSPENDED.load(Ordering::Relaxed) {
+            Some("host suspended")
+        } else if !self
+            .conn
+            .raw()
+            .security_level()
+            .is_ok_and(|level| self.policy.secure(level))
+        {
+            Some("link not secure")
+        } else {
+            None
+        }
+    }
+
+    fn recoverable(&self, e: &Error) -> bool {
+        is_recoverable(e)
+    }
+
+    async fn press(&mut self, key: KeyPressed) -> Result<(), Error> {
+        key.press(self.conn, self.server).await
+    }
+
+    async fn release(&mut self, key: KeyPressed) -> Result<(), Error> {
+        key.release(self.conn, self.server).await
+    }
+}
+
+async fn key_receiver_task<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    policy: PairingPolicy,
+) {
+    let mut keys = BleKeys {
+        server,
+        conn,
+        policy,
+    };
+    let e = transport::forward_keys(&mut keys).await;
+    debug!("[key_receiver_task] link gone: {:?}", e);
+}
+
+/// Disconnects once nothing happened for [`IDLE_TIMEOUT`].
+async fn idle_task<P: PacketPool>(conn: &GattConnection<'_, '_, P>) {
+    // A fresh connection is a burst of activity as well
+    RADIO_POWER.signal(RadioPower::Full);
+    let mut last_activity = Instant::now();
+    let mut power_save = false;
+    loop {
+        let timeout = if power_save {
+            IDLE_TIMEOUT
+        } else {
+            POWER_SAVE_TIMEOUT
+        };
+        match with_deadline(last_activity + timeout, ACTIVITY.wait()).await {
+            Ok(_) => {
+                last_activity = Instant::now();
+                if power_save {
+                    power_save = false;
+                    RADIO_POWER.signal(RadioPower::Full);
+                }
+            }
+            Err(_) if !power_save => {
+                power_save = true;
+                RADIO_POWER.signal(RadioPower::Save);
+            }
+            Err(_) => break,
+        }
+    }
+    info!("[idle] no activity, disconnecting");
+    conn.raw().disconnect();
+}
+
+/// Gives a new host the current battery level and released reports,
+/// instead of whatever was left over from the last connection.
+async fn send_initial_state<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+) -> Result<(), Error> {
+    if let Some(battery) = battery_service(server) {
+        let level = battery.level;
+        if let Some(value) = BATTERY_LEVEL.try_take() {
+            server.set(&level, &battery::clamp_level(value))?;
+        }
+        level.notify(conn, &server.get(&level)?).await?;
+        let status = battery.status;
+        if let Some(charging) = CHARGING.try_take() {
+            server.set(&status, &charging)?;
+        }
+        status.notify(conn, &server.get(&status)?).await?;
+    }
+
+    server
+        .hid
+        .input
+        .notify(conn, &[hid::HID_REPORT_INPUT_ID, 0])
+        .await?;
+    server
+        .hid
+        .keyboard_input
+        .notify(conn, &[hid::HID_REPORT_KEYBOARD_ID, 0])
+        .await?;
+    server
+        .hid
+        .mouse_input
+        .notify(conn, &[hid::HID_REPORT_MOUSE_ID, 0])
+        .await
+}
+
+/// Starts waiting for the knob to be clicked, the LED prompts for the
+/// click meanwhile. Returns by when it has to come.
+fn request_confirmation() -> Instant {
+    info!("[auth] click the knob to confirm the passkey");
+    CONN_STATE.signal(ConnState::AwaitingConfirmation);
+    PASSKEY_CONFIRMED.reset();
+    AWAITING_CONFIRMATION.store(true, Ordering::Relaxed);
+    Instant::now() + PASSKEY_CONFIRM_TIMEOUT
+}
+
+/// Stops waiting for the click, a click is a tap again afterwards.
+fn end_confirmation() {
+    AWAITING_CONFIRMATION.store(false, Ordering::Relaxed);
+}
+
+/// Disconnects hosts that don't authenticate in time, if `secure-only` is enabled.
+async fn authentication_task<P: PacketPool>(
+    conn: &GattConnection<'_, '_, P>,
+    policy: PairingPolicy,
+) {
+    if !cfg!(feature = "secure-only") {
+        return;
+    }
+
+    let authenticated = async {
+        while !conn
+            .raw()
+            .security_level()
+            .is_ok_and(|level| policy.secure(level))
+        {
+            Timer::after_millis(100).await;
+        }
+    };
+    if with_timeout(AUTHENTICATION_TIMEOUT, authenticated)
+        .await
+        .is_err()
+    {
+        warn!("[auth] host didn't authenticate in time, disconnecting");
+        conn.raw().disconnect();
+    }
+}
+
+async fn diagnostics_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    loop {
+        Timer::after(DIAGNOSTICS_INTERVAL).await;
+        let counters = diagnostics::snapshot();
+        if let Err(e) = server.diagnostics.counters.notify(conn, &counters).await {
+            warn!("[diagnostics] error notifying counters: {:?}", e);
+        }
+    }
+}
+
+/// Reads the host's RSSI every [`RSSI_INTERVAL_SECS`] for the RSSI
+/// characteristic, and locks the knob while the host is too far away.
+async fn rssi_task<C, P>(
+    stack: &Stack<'_, C, P>,
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+) where
+    C: Controller + ControllerCmdSync<ReadRssi>,
+    P: PacketPool,
+{
+    // Whatever was read on the last connection is stale
+    if let Err(e) = server.set(&server.diagnostics.rssi, &RSSI_UNAVAILABLE) {
+        warn!("[rssi] error resetting RSSI: {:?}", e);
+    }
+    let mut weak: u8 = 0;
+    loop {
+        let secs = RSSI_INTERVAL_SECS.load(Ordering::Relaxed);
+        if secs == 0 {
+            Timer::after(RSSI_OFF_RECHECK).await;
+            continue;
+        }
+        Timer::after_secs(secs as u64).await;
+
+        let rssi = match conn.raw().rssi(stack).await {
+            Ok(rssi) => rssi,
+            Err(e) => {
+                let e = defmt::Debug2Format(&e);
+                warn!("[rssi] error reading RSSI: {:?}", e);
+                RSSI_UNAVAILABLE
+            }
+        };
+        debug!("[rssi] {} dBm", rssi);
+        if let Err(e) = server.diagnostics.rssi.notify(conn, &rssi).await {
+            warn!("[rssi] error notifying RSSI: {:?}", e);
+        }
+
+        let threshold = LOCK_RSSI.load(Ordering::Relaxed);
+        if threshold == 0 || rssi == RSSI_UNAVAILABLE {
+            continue;
+        }
+        // Unlocked by a click in the meantime
+        if !knob::is_locked() {
+            PROXIMITY_LOCKED.store(false, Ordering::Relaxed);
+        }
+        weak = if rssi < threshold {
+            weak.saturating_add(1)
+        } else {
+            0
+        };
+        if weak >= RSSI_WEAK_READINGS && !knob::is_locked() {
+            info!("[rssi] host walked away at {} dBm, locking", rssi);
+            knob::set_locked(true);
+            PROXIMITY_LOCKED.store(true, Ordering::Relaxed);
+        } else if PROXIMITY_LOCKED.load(Ordering::Relaxed)
+            && rssi >= threshold.saturating_add(RSSI_UNLOCK_HYSTERESIS)
+        {
+            info!("[rssi] host is back at {} dBm, unlocking", rssi);
+            knob::set_locked(false);
+            PROXIMITY_LOCKED.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keeps the velocity characteristic up to date, notifying changes past
+/// [`VELOCITY_HYSTERESIS`] and the knob coming to a stop.
+async fn velocity_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    // A turn from before the connection is long over
+    knob::VELOCITY.reset();
+    let mut reported: i8 = 0;
     loop {
-        let key_press = KEY_PRESS_CHANNEL.receiver().receive().await;
-        if key_press.send(conn, server).await.is_err() {
-            info!("[key_receiver_task] error sending key press");
-            break;
+        // Only a moving knob decays
+        let velocity = if reported == 0 {
+            knob::VELOCITY.wait().await
+        } else {
+            with_timeout(VELOCITY_DECAY, knob::VELOCITY.wait())
+                .await
+                .unwrap_or(0)
         };
+        let stopped = velocity == 0 && reported != 0;
+        if !stopped && reported.abs_diff(velocity) < VELOCITY_HYSTERESIS {
+            continue;
+        }
+        reported = velocity;
+        if let Err(e) = server.knob.velocity.notify(conn, &velocity).await {
+            warn!("[knob] error notifying velocity: {:?}", e);
+        }
+    }
+}
+
+/// Whether the host gets notifications of `report`.
+fn subscribed(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    report: Characteristic<InputRaport>,
+) -> bool {
+    let Some(table) = server.get_cccd_table(conn.raw()) else {
+        return false;
+    };
+    report.cccd_handle.is_some_and(|handle| {
+        table
+            .inner()
+            .iter()
+            .any(|(h, cccd)| *h == handle && cccd.should_notify())
+    })
+}
+
+/// Whether the host gets every report [`TEST_BURST_SCRIPT`] is sent on.
+fn subscribed_to_test_burst(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+) -> bool {
+    TEST_BURST_SCRIPT
+        .iter()
+        .all(|(key, _)| subscribed(server, conn, key.report(server)))
+}
+
+/// Keeps [`ABSOLUTE_VOLUME`] telling whether the host takes the volume
+/// report, only hosts supporting the Volume control subscribe to it.
+fn update_absolute_volume(server: &Server<'_>, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+    let absolute =
+        cfg!(feature = "absolute-volume") && subscribed(server, conn, server.hid.volume_input);
+    // No atomic swap on the M0+, only this loop changes it
+    if ABSOLUTE_VOLUME.load(Ordering::Relaxed) != absolute {
+        ABSOLUTE_VOLUME.store(absolute, Ordering::Relaxed);
+        info!(
+            "[hid] absolute volume {}",
+            if absolute { "on" } else { "off, sending steps" }
+        );
+    }
+}
+
+/// Sends the levels of [`VOLUME_LEVEL`] in the volume report.
+async fn volume_level_task<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    policy: PairingPolicy,
+) {
+    // Only levels set on this connection
+    VOLUME_LEVEL.reset();
+    loop {
+        let level = VOLUME_LEVEL.wait().await;
+        if SUSPENDED.load(Ordering::Relaxed)
+            || !conn
+                .raw()
+                .security_level()
+                .is_ok_and(|level| policy.secure(level))
+        {
+            debug!("[hid] not sending volume level {}", level);
+            continue;
+        }
+        let report = [hid::HID_REPORT_VOLUME_ID, level];
+        if let Err(e) = notify_report(server.hid.volume_input, conn, &report, false).await {
+            warn!("[hid] error sending volume level: {:?}", e);
+        }
+    }
+}
+
+/// Sends [`TEST_BURST_SCRIPT`] through the knob's send path when it's
+/// requested, and every [`DEMO_INTERVAL`] with the `demo` feature. Without
+/// it nothing is ever sent that the knob or the host didn't ask for.
+async fn test_burst_task() {
+    // Only a burst requested on this connection
+    TEST_BURST_REQUESTED.reset();
+    loop {
+        if !cfg!(feature = "demo") {
+            TEST_BURST_REQUESTED.wait().await;
+        } else if with_timeout(DEMO_INTERVAL, TEST_BURST_REQUESTED.wait())
+            .await
+            .is_err()
+        {
+            info!("[demo] sending the test burst");
+        }
+        info!("[gatt] test burst started");
+        for (i, (key, count)) in TEST_BURST_SCRIPT.into_iter().enumerate() {
+            if i > 0 {
+                Timer::after(TEST_BURST_PAUSE).await;
+            }
+            for _ in 0..count {
+                knob::send_key(key);
+            }
+        }
+        info!("[gatt] test burst done");
+    }
+}
+
+/// Pushes battery level and charging changes to the host, if it subscribed to them.
+async fn battery_level_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let Some(battery) = battery_service(server) else {
+        return;
+    };
+    loop {
+        // The signals keep the last value, so a reading taken while
+        // disconnected is delivered as soon as a host connects.
+        match select(BATTERY_LEVEL.wait(), CHARGING.wait()).await {
+            Either::First(level) => {
+                let level = battery::clamp_level(level);
+                if let Err(e) = battery.level.notify(conn, &level).await {
+                    warn!("[battery] error notifying level: {:?}", e);
+                }
+            }
+            Either::Second(charging) => {
+                if let Err(e) = battery.status.notify(conn, &charging).await {
+                    warn!("[battery] error notifying charging: {:?}", e);
+                }
+            }
+        }
     }
 }
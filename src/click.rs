@@ -0,0 +1,244 @@
+use embassy_time::{Duration, Instant};
+
+/// Presses held longer than this are not a tap.
+pub const TAP_MAX: Duration = Duration::from_millis(500);
+/// Default for how soon after the last tap another one adds to the count.
+/// Fewer than [`MAX_TAPS`] taps are only reported once it passes.
+pub const TAP_WINDOW: Duration = Duration::from_millis(300);
+/// Taps counted in a row, the last one is reported right away.
+pub const MAX_TAPS: u8 = 4;
+/// Holding the button this long is a long press, reported on release.
+pub const LONG_PRESS: Duration = Duration::from_millis(800);
+/// Holding the button this long is a hold, reported while still held.
+pub const HOLD: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ClickEvent {
+    /// 1 to [`MAX_TAPS`] taps in a row.
+    Taps(u8),
+    /// Held for [`LONG_PRESS`], reported on release so it can still turn
+    /// into a hold.
+    Long,
+    Hold,
+}
+
+/// Counts taps of the button and tells them apart from long presses and
+/// holds.
+///
+/// Fed the debounced button level on every edge, and again at
+/// [`Self::deadline`] so the timeouts are noticed without an edge.
+pub struct ClickClassifier {
+    // Another tap released within this of the last one adds to the count
+    window: Duration,
+    pressed: bool,
+    pressed_at: Option<Instant>,
+    // Release of the last tap, while more taps can still add to it
+    tapped_at: Option<Instant>,
+    taps: u8,
+    // The current press can't turn into any event anymore
+    spent: bool,
+}
+
+/// Whether `duration` passed from `at` to `now`.
+const fn passed(at: Instant, now: Instant, duration: Duration) -> bool {
+    now.as_ticks().saturating_sub(at.as_ticks()) >= duration.as_ticks()
+}
+
+impl ClickClassifier {
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pressed: false,
+            pressed_at: None,
+            tapped_at: None,
+            taps: 0,
+            spent: false,
+        }
+    }
+
+    pub const fn feed(&mut self, pressed: bool, now: Instant) -> Option<ClickEvent> {
+        match (self.pressed, pressed) {
+            (false, true) => {
+                self.pressed = true;
+                self.pressed_at = Some(now);
+                self.spent = false;
+                // A press after the window starts a new count, even when the
+                // deadline wasn't ticked yet
+                match self.tapped_at {
+                    Some(at) if passed(at, now, self.window) => {
+                        self.tapped_at = None;
+                        Some(ClickEvent::Taps(core::mem::replace(&mut self.taps, 0)))
+                    }
+                    _ => None,
+                }
+            }
+            (true, false) => {
+                self.pressed = false;
+                let Some(at) = self.pressed_at.take() else {
+                    return None;
+                };
+                if self.spent {
+                    return None;
+                }
+                if passed(at, now, LONG_PRESS) {
+                    // Taps right before don't count on their own anymore
+                    self.tapped_at = None;
+                    self.taps = 0;
+                    return Some(ClickEvent::Long);
+                }
+                if passed(at, now, TAP_MAX) {
+                    return None;
+                }
+                self.taps += 1;
+                if self.taps == MAX_TAPS {
+                    // There's nothing more it could turn into
+                    self.tapped_at = None;
+                    return Some(ClickEvent::Taps(core::mem::replace(&mut self.taps, 0)));
+                }
+                self.tapped_at = Some(now);
+                None
+            }
+            (true, true) => {
+                let Some(at) = self.pressed_at else {
+                    return None;
+                };
+                if self.spent || !passed(at, now, HOLD) {
+                    return None;
+                }
+                self.spent = true;
+                self.tapped_at = None;
+                self.taps = 0;
+                Some(ClickEvent::Hold)
+            }
+            (false, false) => {
+                let Some(at) = self.tapped_at else {
+                    return None;
+                };
+                if !passed(at, now, self.window) {
+                    return None;
+                }
+                self.tapped_at = None;
+                Some(ClickEvent::Taps(core::mem::replace(&mut self.taps, 0)))
+            }
+        }
+    }
+
+    /// Feeds the level seen last, for when [`Self::deadline`] passed.
+    pub const fn tick(&mut self, now: Instant) -> Option<ClickEvent> {
+        self.feed(self.pressed, now)
+    }
+
+    /// When [`Self::tick`] has to be called next.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.pressed, self.pressed_at, self.tapped_at) {
+            (true, Some(at), _) if !self.spent => Some(at + HOLD),
+            (false, _, Some(at)) => Some(at + self.window),
+            _ => None,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Keeps the current press from turning into any event, e.g. when the
+    /// knob was turned while holding it.
+    pub fn cancel(&mut self) {
+        self.spent = true;
+    }
+}
+
+// `==` isn't const
+const fn is_taps(event: Option<ClickEvent>, taps: u8) -> bool {
+    matches!(event, Some(ClickEvent::Taps(n)) if n == taps)
+}
+
+// A tap, two of them, a long press and a hold, each after the one before
+// was done with
+const _: () = {
+    const fn ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+    let tap = TAP_MAX.as_millis() / 2;
+    let window = TAP_WINDOW.as_millis();
+    let mut clicks = ClickClassifier::new(TAP_WINDOW);
+
+    // Single tap, only reported once the window passed
+    core::assert!(clicks.feed(true, ms(0)).is_none());
+    core::assert!(clicks.feed(false, ms(tap)).is_none());
+    core::assert!(clicks.tick(ms(tap + window / 2)).is_none());
+    core::assert!(is_taps(clicks.tick(ms(tap + window)), 1));
+
+    // Double tap
+    let t = 10_000;
+    core::assert!(clicks.feed(true, ms(t)).is_none());
+    core::assert!(clicks.feed(false, ms(t + tap)).is_none());
+    core::assert!(clicks.feed(true, ms(t + tap + window / 2)).is_none());
+    core::assert!(clicks.feed(false, ms(t + 2 * tap + window / 2)).is_none());
+    core::assert!(is_taps(
+        clicks.tick(ms(t + 2 * tap + window / 2 + window)),
+        2
+    ));
+
+    // Long press, reported on release
+    let t = 20_000;
+    let long = LONG_PRESS.as_millis();
+    core::assert!(clicks.feed(true, ms(t)).is_none());
+    core::assert!(clicks.tick(ms(t + long)).is_none());
+    core::assert!(matches!(
+        clicks.feed(false, ms(t + long)),
+        Some(ClickEvent::Long)
+    ));
+    // Too long for a tap, too short for a long press
+    core::assert!(clicks.feed(true, ms(t + 5_000)).is_none());
+    core::assert!(
+        clicks
+            .feed(false, ms(t + 5_000 + TAP_MAX.as_millis()))
+            .is_none()
+    );
+    core::assert!(clicks.tick(ms(t + 10_000)).is_none());
+
+    // Hold, reported while still held, the release is nothing anymore
+    let t = 40_000;
+    let hold = HOLD.as_millis();
+    core::assert!(clicks.feed(true, ms(t)).is_none());
+    core::assert!(clicks.tick(ms(t + hold - 1)).is_none());
+    core::assert!(matches!(clicks.tick(ms(t + hold)), Some(ClickEvent::Hold)));
+    core::assert!(clicks.tick(ms(t + 2 * hold)).is_none());
+    core::assert!(clicks.feed(false, ms(t + 2 * hold)).is_none());
+};
+
+// The window is up right at `TAP_WINDOW` after the release, a press a ms
+// before it still adds to the count
+const _: () = {
+    const fn ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+    let window = TAP_WINDOW.as_millis();
+    let mut clicks = ClickClassifier::new(TAP_WINDOW);
+
+    // Ticked a ms before the window is up, and right at it
+    core::assert!(clicks.feed(true, ms(0)).is_none());
+    core::assert!(clicks.feed(false, ms(100)).is_none());
+    core::assert!(clicks.tick(ms(100 + window - 1)).is_none());
+    core::assert!(is_taps(clicks.tick(ms(100 + window)), 1));
+
+    // Pressed again a ms before the window is up
+    let t = 10_000;
+    core::assert!(clicks.feed(true, ms(t)).is_none());
+    core::assert!(clicks.feed(false, ms(t + 100)).is_none());
+    core::assert!(clicks.feed(true, ms(t + 100 + window - 1)).is_none());
+    core::assert!(clicks.feed(false, ms(t + 200 + window)).is_none());
+    core::assert!(is_taps(clicks.tick(ms(t + 200 + 2 * window)), 2));
+
+    // Pressed again right at the window, and a ms after it, before the
+    // deadline was ticked. Either press starts a new count.
+    let t = 20_000;
+    core::assert!(clicks.feed(true, ms(t)).is_none());
+    core::assert!(clicks.feed(false, ms(t + 100)).is_none());
+    core::assert!(is_taps(clicks.feed(true, ms(t + 100 + window)), 1));
+    core::assert!(clicks.feed(false, ms(t + 200 + window)).is_none());
+    core::assert!(is_taps(clicks.feed(true, ms(t + 200 + 2 * window + 1)), 1));
+    core::assert!(clicks.feed(false, ms(t + 300 + 2 * window + 1)).is_none());
+    core::assert!(is_taps(clicks.tick(ms(t + 300 + 3 * window + 1)), 1));
+};
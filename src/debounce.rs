@@ -0,0 +1,172 @@
+use defmt::*;
+use embassy_rp::gpio::{DormantWake, DormantWakeConfig, Input};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Edges on the same pin closer together than this are bounces.
+const BOUNCE_WINDOW: Duration = Duration::from_millis(2);
+/// A clean edge takes this share off the debounce time.
+const DECAY_DIVISOR: u64 = 8;
+
+pub const fn is_bounce(since_last_edge: Duration) -> bool {
+    since_last_edge.as_ticks() < BOUNCE_WINDOW.as_ticks()
+}
+
+/// Debounce time after a bounce was detected at `current`.
+pub const fn lengthen(current: Duration, max: Duration) -> Duration {
+    // Start from something that can be doubled when starting at zero
+    let us = match current.as_micros().saturating_mul(2) {
+        us if us < 100 => 100,
+        us => us,
+    };
+    if us >= max.as_micros() {
+        max
+    } else {
+        Duration::from_micros(us)
+    }
+}
+
+/// Debounce time after a clean edge at `current`, easing back to `min` so
+/// a pin that bounced for a while doesn't stay slow.
+pub const fn shorten(current: Duration, min: Duration) -> Duration {
+    let us = current.as_micros();
+    let step = match us / DECAY_DIVISOR {
+        0 => 1,
+        step => step,
+    };
+    let us = us.saturating_sub(step);
+    if us <= min.as_micros() {
+        min
+    } else {
+        Duration::from_micros(us)
+    }
+}
+
+// `==` isn't const
+const fn micros(duration: Duration) -> u64 {
+    duration.as_micros()
+}
+
+const _: () = {
+    let min = Duration::from_micros(100);
+    let max = Duration::from_millis(5);
+
+    // Edges inside the window are bounces, from its end on they're clean
+    core::assert!(is_bounce(Duration::from_ticks(0)));
+    core::assert!(is_bounce(Duration::from_ticks(
+        BOUNCE_WINDOW.as_ticks() - 1
+    )));
+    core::assert!(!is_bounce(BOUNCE_WINDOW));
+    core::assert!(!is_bounce(Duration::from_millis(50)));
+
+    // Lengthening doubles from a floor, up to the maximum
+    core::assert!(micros(lengthen(Duration::from_ticks(0), max)) == 100);
+    core::assert!(micros(lengthen(Duration::from_micros(400), max)) == 800);
+    core::assert!(micros(lengthen(Duration::from_millis(4), max)) == micros(max));
+    core::assert!(micros(lengthen(max, max)) == micros(max));
+
+    // Clean edges take it back down step by step, never below the minimum
+    core::assert!(micros(shorten(Duration::from_micros(800), min)) == 700);
+    core::assert!(micros(shorten(min, min)) == micros(min));
+    core::assert!(micros(shorten(Duration::from_ticks(0), Duration::from_ticks(0))) == 0);
+    let mut debounce = max;
+    let mut edges = 0;
+    while micros(debounce) > micros(min) {
+        let shortened = shorten(debounce, min);
+        core::assert!(micros(shortened) < micros(debounce));
+        debounce = shortened;
+        edges += 1;
+    }
+    core::assert!(micros(debounce) == micros(min) && edges > 1);
+};
+
+/// Debounces an input without delaying clean edges. Only an edge coming
+/// right after another one waits for the pin to settle, starting at a short
+/// debounce time that grows every time the pin is seen bouncing and eases
+/// back with every clean edge.
+///
+/// Meant for the encoder pins, where a bounce that slips through is undone
+/// by the quadrature decoder. The fastest turning this keeps up with is set
+/// by [`BOUNCE_WINDOW`]: each pin changes twice per detent, so on a 20
+/// detent encoder real edges start being treated as bounces above
+/// 1 / (2 ms * 2 * 20) = 12.5 turns per second, 750 RPM. Past that they are
+/// still tracked as long as the debounce time stays below the time between
+/// edges. This is worked out from the timings, not measured on hardware.
+pub struct AdaptiveDebouncer<'d> {
+    input: Input<'d>,
+    // Level after the last debounced edge
+    level: bool,
+    // How long a bouncing pin is given to settle
+    debounce: Duration,
+    min: Duration,
+    max: Duration,
+    last_edge: Option<Instant>,
+}
+
+impl<'d> AdaptiveDebouncer<'d> {
+    pub fn new(input: Input<'d>, min: Duration, max: Duration) -> Self {
+        Self {
+            level: input.is_high(),
+            input,
+            debounce: min,
+            min,
+            max,
+            last_edge: None,
+        }
+    }
+
+    /// The debounced level.
+    pub fn is_high(&self) -> bool {
+        self.level
+    }
+
+    /// The current effective debounce time.
+    pub fn debounce(&self) -> Duration {
+        self.debounce
+    }
+
+    /// Lets the pin wake the chip from dormant sleep while the guard lives.
+    pub fn dormant_wake(&mut self, config: DormantWakeConfig) -> DormantWake<'_> {
+        self.input.dormant_wake(config)
+    }
+
+    /// Cancel safe, an edge seen by a dropped call is picked up by the next one.
+    pub async fn wait_for_any_edge(&mut self) {
+        loop {
+            let mut bounced = false;
+            if self.input.is_high() == self.level {
+                if self.level {
+                    self.input.wait_for_falling_edge().await;
+                } else {
+                    self.input.wait_for_rising_edge().await;
+                }
+
+                let now = Instant::now();
+                bounced = self.last_edge.is_some_and(|last| is_bounce(now - last));
+                self.last_edge = Some(now);
+            }
+
+            if bounced {
+                self.on_bounce();
+                Timer::after(self.debounce).await;
+            } else {
+                self.debounce = shorten(self.debounce, self.min);
+            }
+            if self.input.is_high() != self.level {
+                self.level = !self.level;
+                return;
+            }
+            // Settled back to where it was, nothing happened
+        }
+    }
+
+    fn on_bounce(&mut self) {
+        let lengthened = lengthen(self.debounce, self.max);
+        if lengthened != self.debounce {
+            self.debounce = lengthened;
+            debug!(
+                "[debounce] bounce detected, debounce now {} us",
+                self.debounce.as_micros()
+            );
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::ThreadModeRawMutex};
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, Ordering};
+
+/// Size of a [`snapshot`], six little endian `u32`s.
+pub const DIAGNOSTICS_LEN: usize = 24;
+
+pub static RIGHT_DETENTS: AtomicU32 = AtomicU32::new(0);
+pub static LEFT_DETENTS: AtomicU32 = AtomicU32::new(0);
+pub static DISCONNECTS: AtomicU32 = AtomicU32::new(0);
+/// Times an encoder pin was found stuck.
+pub static PIN_FAULTS: AtomicU32 = AtomicU32::new(0);
+/// Key reports given up on because the host didn't take them.
+pub static DROPPED_REPORTS: AtomicU32 = AtomicU32::new(0);
+
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Uptime in seconds, right detents, left detents, disconnects, pin faults
+/// and dropped reports.
+pub fn snapshot() -> [u8; DIAGNOSTICS_LEN] {
+    let values = [
+        Instant::now().as_secs() as u32,
+        RIGHT_DETENTS.load(Ordering::Relaxed),
+        LEFT_DETENTS.load(Ordering::Relaxed),
+        DISCONNECTS.load(Ordering::Relaxed),
+        PIN_FAULTS.load(Ordering::Relaxed),
+        DROPPED_REPORTS.load(Ordering::Relaxed),
+    ];
+    let mut buf = [0u8; DIAGNOSTICS_LEN];
+    for (chunk, value) in buf.chunks_exact_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    buf
+}
+
+/// Decoded rotations kept in [`rotation_log`].
+pub const ROTATION_LOG_LEN: usize = 16;
+/// Size of a [`rotation_log`], per rotation the direction and a little
+/// endian `u32` of ms since boot.
+pub const ROTATION_LOG_BYTES: usize = ROTATION_LOG_LEN * 5;
+// Directions in the log, slots never filled stay 0
+const LOG_RIGHT: u8 = 1;
+const LOG_LEFT: u8 = 2;
+
+/// The last [`ROTATION_LOG_LEN`] rotations, older ones are overwritten.
+struct RotationLog {
+    entries: [(u8, u32); ROTATION_LOG_LEN],
+    /// Where the next one goes, also the oldest one once it's full.
+    next: usize,
+}
+
+impl RotationLog {
+    const fn new() -> Self {
+        Self {
+            entries: [(0, 0); ROTATION_LOG_LEN],
+            next: 0,
+        }
+    }
+
+    const fn push(&mut self, direction: u8, ms: u32) {
+        self.entries[self.next] = (direction, ms);
+        self.next = (self.next + 1) % ROTATION_LOG_LEN;
+    }
+
+    /// Oldest first, empty slots leading.
+    const fn encode(&self) -> [u8; ROTATION_LOG_BYTES] {
+        let mut buf = [0u8; ROTATION_LOG_BYTES];
+        let mut i = 0;
+        while i < ROTATION_LOG_LEN {
+            let (direction, ms) = self.entries[(self.next + i) % ROTATION_LOG_LEN];
+            let ms = ms.to_le_bytes();
+            buf[i * 5] = direction;
+            let mut j = 0;
+            while j < 4 {
+                buf[i * 5 + 1 + j] = ms[j];
+                j += 1;
+            }
+            i += 1;
+        }
+        buf
+    }
+}
+
+const _: () = {
+    let mut log = RotationLog::new();
+    log.push(LOG_RIGHT, 1000);
+    log.push(LOG_LEFT, 0x0102_0304);
+    let buf = log.encode();
+    // Not filled yet, the two are at the end
+    core::assert!(buf[0] == 0);
+    let at = (ROTATION_LOG_LEN - 2) * 5;
+    core::assert!(buf[at] == LOG_RIGHT && buf[at + 1] == 0xe8 && buf[at + 2] == 0x03);
+    core::assert!(buf[at + 5] == LOG_LEFT && buf[at + 6] == 0x04 && buf[at + 9] == 0x01);
+
+    // Wrapped around, the first two are gone
+    let mut i = 0;
+    while i < ROTATION_LOG_LEN {
+        log.push(LOG_RIGHT, 2000 + i as u32);
+        i += 1;
+    }
+    let buf = log.encode();
+    core::assert!(buf[0] == LOG_RIGHT && buf[1] == 0xd0 && buf[2] == 0x07);
+    let last = (ROTATION_LOG_LEN - 1) * 5;
+    core::assert!(
+        u32::from_le_bytes([buf[last + 1], buf[last + 2], buf[last + 3], buf[last + 4]]) == 2015
+    );
+};
+
+// Only touched from the executor, the lock is just a check
+static ROTATION_LOG: Mutex<ThreadModeRawMutex, RefCell<RotationLog>> =
+    Mutex::new(RefCell::new(RotationLog::new()));
+
+/// Adds a decoded rotation to the log.
+pub fn record_rotation(clockwise: bool) {
+    let direction = if clockwise { LOG_RIGHT } else { LOG_LEFT };
+    let ms = Instant::now().as_millis() as u32;
+    ROTATION_LOG.lock(|log| log.borrow_mut().push(direction, ms));
+}
+
+/// The last rotations as (direction, ms since boot), oldest first. 1 is
+/// clockwise, 2 counter clockwise and 0 a slot not filled since boot.
+pub fn rotation_log() -> [u8; ROTATION_LOG_BYTES] {
+    ROTATION_LOG.lock(|log| log.borrow().encode())
+}
@@ -0,0 +1,59 @@
+//! Transitions worth following in the logs. They all go through
+//! [`record`], so they're logged the same way and easy to pick out.
+
+use bt_hci::param::Status;
+use trouble_host::prelude::{Error, SecurityLevel};
+
+use crate::{bluetooth::KeyPressed, click::ClickEvent, knob::KnobMode, log::info};
+
+#[derive(Debug, Clone, PartialEq, defmt::Format)]
+pub enum FwEvent {
+    /// Directed advertising only reaches the bonded host.
+    AdvStart {
+        directed: bool,
+    },
+    Connected,
+    /// The host asked the knob to drop it and advertise again, the
+    /// [`FwEvent::Disconnected`] following it is this.
+    AdvRestartRequested,
+    /// The host asked to pair, with the passkey if there's one to compare.
+    PairingStarted {
+        passkey: Option<u32>,
+    },
+    PairingComplete {
+        level: SecurityLevel,
+        bonded: bool,
+    },
+    PairingFailed {
+        error: Error,
+    },
+    /// [`Status::CONN_TIMEOUT`] means the host went away without a word.
+    Disconnected {
+        reason: Status,
+    },
+    RotLeft {
+        key: KeyPressed,
+        steps: u8,
+    },
+    RotRight {
+        key: KeyPressed,
+        steps: u8,
+    },
+    Click {
+        click: ClickEvent,
+    },
+    ModeChange {
+        mode: KnobMode,
+    },
+}
+
+/// Logs `event`, as a warning when something went wrong.
+pub fn record(event: FwEvent) {
+    match event {
+        FwEvent::PairingFailed { .. }
+        | FwEvent::Disconnected {
+            reason: Status::CONN_TIMEOUT,
+        } => defmt::warn!("[event] {:?}", event),
+        _ => info!("[event] {:?}", event),
+    }
+}
@@ -0,0 +1,112 @@
+//! Errors the knob can't carry on from, blinked on the status LED for
+//! units in the field without a debugger.
+//!
+//! The LED blinks long once per category, then short once per code, and
+//! repeats that [`BLINK_ROUNDS`] times before the knob resets:
+//!
+//! | Category  | Code | Meaning                                            |
+//! |-----------|------|----------------------------------------------------|
+//! | 1 (cyw43) | 1    | The controller failed a command, the link to it is broken |
+//! | 2 (BLE)   | 1    | The host stack stopped with an error               |
+//! | 2 (BLE)   | 2    | Advertising failed `ADV_MAX_FAILURES` times in a row |
+//! | 2 (BLE)   | 3    | The GATT server couldn't be built                  |
+//! | 3 (flash) | 1    | A stored bond couldn't be restored                 |
+//!
+//! A cyw43 error may keep the onboard LED from blinking at all, an
+//! external LED shows it regardless.
+
+use cortex_m::peripheral::SCB;
+use defmt::error;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    led::{self, ERROR_CODE},
+    watchdog::{BLE_HEARTBEAT, HEARTBEAT_INTERVAL},
+};
+
+/// How many times the code is blinked before resetting, a single round is
+/// easily missed if nobody was looking at the LED right then.
+pub const BLINK_ROUNDS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Category {
+    Cyw43 = 1,
+    Ble = 2,
+    Flash = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FatalError {
+    ControllerFailed,
+    HostStopped,
+    AdvertisingFailed,
+    ServerBuild,
+    BondRestore,
+}
+
+impl FatalError {
+    pub const fn category(self) -> Category {
+        match self {
+            FatalError::ControllerFailed => Category::Cyw43,
+            FatalError::HostStopped | FatalError::AdvertisingFailed | FatalError::ServerBuild => {
+                Category::Ble
+            }
+            FatalError::BondRestore => Category::Flash,
+        }
+    }
+
+    /// Counted from 1 within the category.
+    pub const fn code(self) -> u8 {
+        match self {
+            FatalError::ControllerFailed => 1,
+            FatalError::HostStopped => 1,
+            FatalError::AdvertisingFailed => 2,
+            FatalError::ServerBuild => 3,
+            FatalError::BondRestore => 1,
+        }
+    }
+}
+
+const _: () = {
+    let errors = [
+        FatalError::ControllerFailed,
+        FatalError::HostStopped,
+        FatalError::AdvertisingFailed,
+        FatalError::ServerBuild,
+        FatalError::BondRestore,
+    ];
+    // Every error blinks its own pattern
+    let mut i = 0;
+    while i < errors.len() {
+        core::assert!(errors[i].code() >= 1);
+        let mut j = i + 1;
+        while j < errors.len() {
+            core::assert!(
+                errors[i].category() as u8 != errors[j].category() as u8
+                    || errors[i].code() != errors[j].code()
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+/// Blinks `error` on the status LED [`BLINK_ROUNDS`] times, then resets
+/// like a panic would, so the knob still comes back on its own. Beats in
+/// place of the BLE loop meanwhile, so the watchdog doesn't cut the code
+/// short, but still resets the knob if this gets stuck.
+pub async fn halt(error: FatalError) -> ! {
+    let (category, code) = (error.category(), error.code());
+    error!(
+        "[fatal] {:?}, blinking {} long and {} short {} times before resetting",
+        error, category as u8, code, BLINK_ROUNDS
+    );
+    ERROR_CODE.signal(error);
+    let blinking = led::error_rounds_ms(category as u8, code, BLINK_ROUNDS);
+    let reset_at = Instant::now() + Duration::from_millis(blinking);
+    while Instant::now() < reset_at {
+        BLE_HEARTBEAT.beat();
+        Timer::after(HEARTBEAT_INTERVAL).await;
+    }
+    SCB::sys_reset();
+}
@@ -0,0 +1,33 @@
+use embassy_rp::pwm::{Config, Pwm, SetDutyCycle};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// How long the motor or piezo is driven for a tick.
+const PULSE: Duration = Duration::from_millis(15);
+/// Duty cycle while pulsing, sets how strong a tick feels.
+const DUTY_PERCENT: u8 = 60;
+/// The PWM runs at 125 MHz / (TOP + 1), 20 kHz stays above hearing for a
+/// motor. A piezo needs an audible one instead, e.g. 31_249 for 4 kHz.
+const TOP: u16 = 6249;
+
+/// Signaled for every key sent, the haptic task ticks once for it.
+pub static HAPTIC_PULSE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Drives a haptic motor or piezo on a PWM output, ticking on every
+/// [`HAPTIC_PULSE`].
+#[embassy_executor::task]
+pub async fn haptic_task(mut pwm: Pwm<'static>) {
+    let mut config = Config::default();
+    config.top = TOP;
+    pwm.set_config(&config);
+
+    loop {
+        HAPTIC_PULSE.wait().await;
+        // Infallible errors
+        pwm.set_duty_cycle_percent(DUTY_PERCENT).unwrap();
+        Timer::after(PULSE).await;
+        pwm.set_duty_cycle_fully_off().unwrap();
+        // Keys sent during the tick, like an accelerated detent, felt as one
+        HAPTIC_PULSE.reset();
+    }
+}
@@ -2,31 +2,312 @@
 // Adopted for Rust by Szczurek
 
 // HID Usage Tables: 1.6.0
-// Descriptor size: 31 (bytes)
+// Descriptor size: 63 (bytes), 84 with `absolute-volume`, 26 with the
+// presenter profile and 52 with the scroll profile
 // +----------+-------+-------------------+
 // | ReportId | Kind  | ReportSizeInBytes |
 // +----------+-------+-------------------+
 // |        1 | Input |                 1 |
 // +----------+-------+-------------------+
-pub const HID_REPORT_DESCRIPTOR: [u8; 31] = [
-    0x05, 0x0C, // UsagePage(Consumer[0x000C])
-    0x09, 0x01, // UsageId(Consumer Control[0x0001])
+// |        2 | Input |                 1 |
+// +----------+-------+-------------------+
+// |        3 | Input |                 1 |
+// +----------+-------+-------------------+
+// |        4 | Input |                 1 |
+// +----------+-------+-------------------+
+//
+// The presenter profile only has the keyboard collection, the scroll
+// profile the mouse and keyboard ones. Reports of a missing collection
+// are never sent.
+#[cfg(all(
+    any(feature = "profile-volume", feature = "profile-media"),
+    not(feature = "absolute-volume")
+))]
+pub const HID_REPORT_DESCRIPTOR: [u8; CONSUMER_COLLECTION.len() + KEYBOARD_COLLECTION.len()] =
+    concat(CONSUMER_COLLECTION, KEYBOARD_COLLECTION);
+#[cfg(all(
+    any(feature = "profile-volume", feature = "profile-media"),
+    feature = "absolute-volume"
+))]
+pub const HID_REPORT_DESCRIPTOR: [u8; CONSUMER_COLLECTION.len()
+    + KEYBOARD_COLLECTION.len()
+    + VOLUME_COLLECTION.len()] = concat(
+    concat::<_, _, { CONSUMER_COLLECTION.len() + KEYBOARD_COLLECTION.len() }>(
+        CONSUMER_COLLECTION,
+        KEYBOARD_COLLECTION,
+    ),
+    VOLUME_COLLECTION,
+);
+#[cfg(feature = "profile-presenter")]
+pub const HID_REPORT_DESCRIPTOR: [u8; KEYBOARD_COLLECTION.len()] = KEYBOARD_COLLECTION;
+#[cfg(feature = "profile-scroll")]
+pub const HID_REPORT_DESCRIPTOR: [u8; MOUSE_COLLECTION.len() + KEYBOARD_COLLECTION.len()] =
+    concat(MOUSE_COLLECTION, KEYBOARD_COLLECTION);
+
+// Consumer usages sent for the keys. Change one here to retarget it, e.g.
+// Fast Forward (0xB3) in place of Scan Next Track, the consumer collection
+// and the report bits are both built from `CONSUMER_USAGES`.
+/// Volume Increment
+pub const USAGE_VOLUME_UP: u8 = 0xE9;
+/// Volume Decrement
+pub const USAGE_VOLUME_DOWN: u8 = 0xEA;
+/// Mute
+pub const USAGE_MUTE: u8 = 0xE2;
+/// Play/Pause
+pub const USAGE_PLAY_PAUSE: u8 = 0xCD;
+/// Scan Next Track
+pub const USAGE_NEXT_TRACK: u8 = 0xB5;
+/// Scan Previous Track
+pub const USAGE_PREV_TRACK: u8 = 0xB6;
+/// Volume, a linear control set to a level instead of stepped
+pub const USAGE_VOLUME: u8 = 0xE0;
+
+/// The usages of the consumer report, one bit each in this order.
+const CONSUMER_USAGES: [u8; 6] = [
+    USAGE_VOLUME_UP,
+    USAGE_VOLUME_DOWN,
+    USAGE_MUTE,
+    USAGE_PLAY_PAUSE,
+    USAGE_NEXT_TRACK,
+    USAGE_PREV_TRACK,
+];
+
+#[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+const CONSUMER_COLLECTION: [u8; 25 + 2 * CONSUMER_USAGES.len()] = consumer_collection();
+
+/// The consumer collection, a bit per usage of [`CONSUMER_USAGES`] padded
+/// to a byte.
+#[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+const fn consumer_collection<const N: usize>() -> [u8; N] {
+    let count = CONSUMER_USAGES.len();
+    core::assert!(count < 8, "the consumer report is a single padded byte");
+    let mut out = [0u8; N];
+    let mut at = put(
+        &mut out,
+        0,
+        &[
+            0x05,
+            0x0C, // UsagePage(Consumer[0x000C])
+            0x09,
+            0x01, // UsageId(Consumer Control[0x0001])
+            0xA1,
+            0x01, // Collection(Application)
+            0x85,
+            HID_REPORT_INPUT_ID, // ReportId
+        ],
+    );
+    let mut i = 0;
+    while i < count {
+        // UsageId
+        at = put(&mut out, at, &[0x09, CONSUMER_USAGES[i]]);
+        i += 1;
+    }
+    at = put(
+        &mut out,
+        at,
+        &[
+            0x15,
+            0x00, // LogicalMinimum(0)
+            0x25,
+            0x01, // LogicalMaximum(1)
+            0x95,
+            count as u8, // ReportCount
+            0x75,
+            0x01, // ReportSize(1)
+            0x81,
+            0x02, // Input(Data, Variable, Absolute)
+            0x95,
+            0x01, // ReportCount(1)
+            0x75,
+            8 - count as u8, // ReportSize, the padding
+            0x81,
+            0x03, // Input(Constant, Variable, Absolute)
+            0xC0, // EndCollection()
+        ],
+    );
+    core::assert!(at == N);
+    out
+}
+
+/// Copies `bytes` into `out` at `at`, returns where they end.
+#[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+const fn put(out: &mut [u8], at: usize, bytes: &[u8]) -> usize {
+    let mut i = 0;
+    while i < bytes.len() {
+        out[at + i] = bytes[i];
+        i += 1;
+    }
+    at + bytes.len()
+}
+
+const KEYBOARD_COLLECTION: [u8; 26] = [
+    0x05, 0x01, // UsagePage(Generic Desktop[0x0001])
+    0x09, 0x06, // UsageId(Keyboard[0x0006])
     0xA1, 0x01, // Collection(Application)
-    0x85, 0x01, //     ReportId(1)
-    0x09, 0xE9, //     UsageId(Volume Increment[0x00E9])
-    0x09, 0xEA, //     UsageId(Volume Decrement[0x00EA])
-    0x09, 0xE2, //     UsageId(Mute[0x00E2])
+    0x85, 0x02, //     ReportId(2)
+    0x05, 0x07, //     UsagePage(Keyboard/Keypad[0x0007])
+    0x19, 0x00, //     UsageIdMin(0x0000)
+    0x29, 0x81, //     UsageIdMax(Keyboard Volume Down[0x0081])
     0x15, 0x00, //     LogicalMinimum(0)
-    0x25, 0x01, //     LogicalMaximum(1)
-    0x95, 0x03, //     ReportCount(3)
-    0x75, 0x01, //     ReportSize(1)
+    0x26, 0x81, 0x00, //     LogicalMaximum(129)
+    0x95, 0x01, //     ReportCount(1)
+    0x75, 0x08, //     ReportSize(8)
+    0x81,
+    0x00, //     Input(Data, Array, Absolute, NoWrap, Linear, PreferredState, NoNullPosition, BitField)
+    0xC0, // EndCollection()
+];
+
+/// With `absolute-volume`, the level in percent in a report of its own.
+/// Not every host takes it, the pot falls back to steps when the host
+/// didn't subscribe to it.
+#[cfg(feature = "absolute-volume")]
+const VOLUME_COLLECTION: [u8; 21] = [
+    0x05,
+    0x0C, // UsagePage(Consumer[0x000C])
+    0x09,
+    0x01, // UsageId(Consumer Control[0x0001])
+    0xA1,
+    0x01, // Collection(Application)
+    0x85,
+    0x04, //     ReportId(4)
+    0x09,
+    USAGE_VOLUME, //     UsageId(Volume[0x00E0])
+    0x15,
+    0x00, //     LogicalMinimum(0)
+    0x25,
+    0x64, //     LogicalMaximum(100)
+    0x95,
+    0x01, //     ReportCount(1)
+    0x75,
+    0x08, //     ReportSize(8)
     0x81,
     0x02, //     Input(Data, Variable, Absolute, NoWrap, Linear, PreferredState, NoNullPosition, BitField)
-    0x95, 0x01, //     ReportCount(1)
-    0x75, 0x05, //     ReportSize(5)
+    0xC0, // EndCollection()
+];
+
+// A mouse with nothing but a vertical wheel
+#[cfg(feature = "profile-scroll")]
+const MOUSE_COLLECTION: [u8; 26] = [
+    0x05, 0x01, // UsagePage(Generic Desktop[0x0001])
+    0x09, 0x02, // UsageId(Mouse[0x0002])
+    0xA1, 0x01, // Collection(Application)
+    0x85, 0x03, //     ReportId(3)
+    0x09, 0x01, //     UsageId(Pointer[0x0001])
+    0xA1, 0x00, //     Collection(Physical)
+    0x09, 0x38, //         UsageId(Wheel[0x0038])
+    0x15, 0x81, //         LogicalMinimum(-127)
+    0x25, 0x7F, //         LogicalMaximum(127)
+    0x95, 0x01, //         ReportCount(1)
+    0x75, 0x08, //         ReportSize(8)
     0x81,
-    0x03, //     Input(Constant, Variable, Absolute, NoWrap, Linear, PreferredState, NoNullPosition, BitField)
+    0x06, //         Input(Data, Variable, Relative, NoWrap, Linear, PreferredState, NoNullPosition, BitField)
+    0xC0, //     EndCollection()
     0xC0, // EndCollection()
 ];
 
+/// Size of the data of the short item starting with `prefix`.
+const fn item_size(prefix: u8) -> usize {
+    match prefix & 0b11 {
+        3 => 4,
+        size => size as usize,
+    }
+}
+
+/// Bit of `usage` in the consumer report. Fails the build for a usage
+/// missing from [`CONSUMER_USAGES`].
+pub const fn consumer_bit(usage: u8) -> u8 {
+    let mut i = 0;
+    while i < CONSUMER_USAGES.len() {
+        if CONSUMER_USAGES[i] == usage {
+            return 1 << i;
+        }
+        i += 1;
+    }
+    core::panic!("usage missing from the consumer report")
+}
+
+// Two keys on the same usage couldn't be told apart
+const _: () = {
+    let mut i = 0;
+    while i < CONSUMER_USAGES.len() {
+        let mut j = i + 1;
+        while j < CONSUMER_USAGES.len() {
+            core::assert!(
+                CONSUMER_USAGES[i] != CONSUMER_USAGES[j],
+                "consumer usage listed twice"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+// The descriptor declares the usages in the order of their report bits.
+// Walks the built collection like a host would.
+#[cfg(any(feature = "profile-volume", feature = "profile-media"))]
+const _: () = {
+    let mut i = 0;
+    let mut in_collection = false;
+    let mut bit = 0;
+    while i < CONSUMER_COLLECTION.len() {
+        let prefix = CONSUMER_COLLECTION[i];
+        match prefix {
+            // Collection, the usage before it names the collection itself
+            0xA1 => in_collection = true,
+            // UsageId with one byte of data
+            0x09 if in_collection => {
+                core::assert!(CONSUMER_COLLECTION[i + 1] == CONSUMER_USAGES[bit]);
+                bit += 1;
+            }
+            _ => {}
+        }
+        i += 1 + item_size(prefix);
+    }
+    core::assert!(bit == CONSUMER_USAGES.len());
+};
+
+/// Highest key the keyboard report can carry.
+pub const fn keyboard_usage_max() -> u8 {
+    let mut i = 0;
+    while i < KEYBOARD_COLLECTION.len() {
+        let prefix = KEYBOARD_COLLECTION[i];
+        // UsageIdMax with one byte of data
+        if prefix == 0x29 {
+            return KEYBOARD_COLLECTION[i + 1];
+        }
+        i += 1 + item_size(prefix);
+    }
+    core::panic!("no usage range in the keyboard collection")
+}
+
+#[cfg(not(feature = "profile-presenter"))]
+const fn concat<const A: usize, const B: usize, const N: usize>(a: [u8; A], b: [u8; B]) -> [u8; N] {
+    core::assert!(A + B == N);
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < A {
+        out[i] = a[i];
+        i += 1;
+    }
+    while i < N {
+        out[i] = b[i - A];
+        i += 1;
+    }
+    out
+}
+
 pub const HID_REPORT_INPUT_ID: u8 = 1;
+pub const HID_REPORT_KEYBOARD_ID: u8 = 2;
+pub const HID_REPORT_MOUSE_ID: u8 = 3;
+pub const HID_REPORT_VOLUME_ID: u8 = 4;
+
+/// Report type of an input report in the Report Reference descriptor.
+pub const HID_REPORT_TYPE_INPUT: u8 = 1;
+
+// Keyboard/Keypad usages sent in the keyboard report
+pub const KEYBOARD_B: u8 = 0x05;
+pub const KEYBOARD_RIGHT_ARROW: u8 = 0x4F;
+pub const KEYBOARD_LEFT_ARROW: u8 = 0x50;
+pub const KEYBOARD_PAGE_UP: u8 = 0x4B;
+pub const KEYBOARD_PAGE_DOWN: u8 = 0x4E;
+pub const KEYBOARD_MUTE: u8 = 0x7F;
@@ -0,0 +1,1059 @@
+use async_debounce::Debouncer;
+use core::{
+    future::pending,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+use cortex_m::peripheral::SCB;
+use defmt::*;
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
+use embassy_rp::{
+    Peri, clocks,
+    gpio::{AnyPin, DormantWakeConfig, Input, Pull},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_timeout};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+use portable_atomic::AtomicU64;
+
+use crate::{
+    ACTIVITY, KEY_PRESS_CHANNEL, KEY_PRESS_CHANNEL_LEN, SLEEP, SWITCH_HOST,
+    bluetooth::{AWAITING_CONFIRMATION, KeyPressed, PASSKEY_CONFIRMED},
+    click::{ClickClassifier, ClickEvent, MAX_TAPS, TAP_WINDOW},
+    debounce::AdaptiveDebouncer,
+    diagnostics::{self, LEFT_DETENTS, PIN_FAULTS, RIGHT_DETENTS},
+    encoder::{DetentMode, Direction, Pin, QuadratureDecoder, StuckPinDetector},
+    event::{self, FwEvent},
+    haptic::HAPTIC_PULSE,
+    led::{self, BLINK, LOCK_STATE, ROTATED},
+    log::{debug, info},
+    pio_encoder::PioEncoder,
+    power::{self, POWER_OFF, RADIO_STOPPED},
+    watchdog::{HEARTBEAT_INTERVAL, KNOB_HEARTBEAT},
+};
+
+/// Time given to turn the knob both ways in [`self_test`].
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Blinks acknowledging a hold right before powering off.
+const POWER_OFF_BLINKS: u8 = 3;
+/// Detents closer together than this are accelerated.
+const ACCEL_THRESHOLD_MS: u32 = 100;
+/// Most steps a single detent can turn into, so a fast spin can't flood the link.
+const ACCEL_MAX_STEPS: u8 = 4;
+/// Most keys a single detent sends, whatever acceleration, steps per
+/// detent and the coarse multiplier add up to.
+const MAX_BURST: u8 = 32;
+
+// Two knobs bursting at once still fit in the channel, nothing is dropped
+const _: () = core::assert!(2 * MAX_BURST as usize <= KEY_PRESS_CHANNEL_LEN);
+
+/// Volume steps sent per detent, configurable over GATT.
+pub static STEPS_PER_DETENT: AtomicU8 = AtomicU8::new(1);
+pub const STEPS_PER_DETENT_MAX: u8 = 10;
+
+/// How long the button has to hold still before a press or release counts,
+/// configurable over GATT as part of the bulk settings.
+pub static BUTTON_DEBOUNCE_MS: AtomicU8 = AtomicU8::new(BUTTON_DEBOUNCE_DEFAULT_MS);
+pub const BUTTON_DEBOUNCE_DEFAULT_MS: u8 = 20;
+pub const BUTTON_DEBOUNCE_MIN_MS: u8 = 5;
+pub const BUTTON_DEBOUNCE_MAX_MS: u8 = 100;
+
+/// Flips the direction on top of [`KnobConfig::invert`], configurable over GATT.
+pub static INVERT_DIRECTION: AtomicBool = AtomicBool::new(false);
+
+/// What turning the knob does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KnobMode {
+    /// Volume up and down, accelerated.
+    Volume,
+    /// Next and previous track, one per detent.
+    Media,
+    /// Next and previous slide, one per detent.
+    Presenter,
+    /// Mouse wheel, down when turning clockwise.
+    Scroll,
+}
+
+impl KnobMode {
+    /// The mode a long press switches to.
+    const fn toggled(self) -> Self {
+        match self {
+            KnobMode::Volume => KnobMode::Media,
+            KnobMode::Media => KnobMode::Volume,
+            // The presenter descriptor has no consumer keys to switch to
+            KnobMode::Presenter => KnobMode::Presenter,
+            // Neither has the mouse
+            KnobMode::Scroll => KnobMode::Scroll,
+        }
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => KnobMode::Media,
+            2 => KnobMode::Presenter,
+            3 => KnobMode::Scroll,
+            _ => KnobMode::Volume,
+        }
+    }
+}
+
+// Defaults picked by the `profile-*` feature
+#[cfg(feature = "profile-volume")]
+pub const DEFAULT_MODE: KnobMode = KnobMode::Volume;
+#[cfg(feature = "profile-volume")]
+const DEFAULT_CLICK: KeyPressed = KeyPressed::Mute;
+#[cfg(feature = "profile-volume")]
+const DEFAULT_DOUBLE_CLICK: TapAction = TapAction::SwitchHost;
+#[cfg(feature = "profile-media")]
+pub const DEFAULT_MODE: KnobMode = KnobMode::Media;
+#[cfg(feature = "profile-media")]
+const DEFAULT_CLICK: KeyPressed = KeyPressed::PlayPause;
+#[cfg(feature = "profile-media")]
+const DEFAULT_DOUBLE_CLICK: TapAction = TapAction::Key(KeyPressed::NextTrack);
+#[cfg(feature = "profile-presenter")]
+pub const DEFAULT_MODE: KnobMode = KnobMode::Presenter;
+#[cfg(feature = "profile-presenter")]
+const DEFAULT_CLICK: KeyPressed = KeyPressed::BlankScreen;
+#[cfg(feature = "profile-presenter")]
+const DEFAULT_DOUBLE_CLICK: TapAction = TapAction::SwitchHost;
+#[cfg(feature = "profile-scroll")]
+pub const DEFAULT_MODE: KnobMode = KnobMode::Scroll;
+#[cfg(feature = "profile-scroll")]
+const DEFAULT_CLICK: KeyPressed = KeyPressed::KeyboardMute;
+#[cfg(feature = "profile-scroll")]
+const DEFAULT_DOUBLE_CLICK: TapAction = TapAction::SwitchHost;
+
+static MODE: AtomicU8 = AtomicU8::new(DEFAULT_MODE as u8);
+/// Rotation velocity of the main knob on every detent, see [`velocity`].
+/// The BLE task decays it to zero once no detent came in for a while.
+pub static VELOCITY: Signal<ThreadModeRawMutex, i8> = Signal::new();
+/// Set by the clicks toggling the lock, a triple click by default, or by the
+/// host walking away. The knob ignores everything but those clicks then.
+/// Only kept in RAM, so a reboot unlocks.
+static LOCKED: AtomicBool = AtomicBool::new(false);
+/// Signaled when a long press switched the mode, so it's stored.
+pub static MODE_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Physical events whose key can be remapped over GATT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KnobEvent {
+    /// Turning clockwise by one detent.
+    Clockwise,
+    CounterClockwise,
+    /// A single click of the button.
+    Click,
+    DoubleClick,
+    TripleClick,
+    QuadrupleClick,
+}
+
+pub const KNOB_EVENTS: usize = 6;
+
+impl KnobEvent {
+    pub const ALL: [KnobEvent; KNOB_EVENTS] = [
+        KnobEvent::Clockwise,
+        KnobEvent::CounterClockwise,
+        KnobEvent::Click,
+        KnobEvent::DoubleClick,
+        KnobEvent::TripleClick,
+        KnobEvent::QuadrupleClick,
+    ];
+
+    /// The click of `taps` taps in a row.
+    pub const fn taps(taps: u8) -> Option<Self> {
+        match taps {
+            1 => Some(KnobEvent::Click),
+            2 => Some(KnobEvent::DoubleClick),
+            3 => Some(KnobEvent::TripleClick),
+            4 => Some(KnobEvent::QuadrupleClick),
+            _ => None,
+        }
+    }
+
+    const fn is_click(self) -> bool {
+        !matches!(self, KnobEvent::Clockwise | KnobEvent::CounterClockwise)
+    }
+}
+
+const _: () = core::assert!(
+    MAX_TAPS as usize == KNOB_EVENTS - 2,
+    "every tap count needs its KnobEvent"
+);
+
+// Action bytes only clicks take, past the keys of `KeyPressed::from_action`
+pub const ACTION_SWITCH_HOST: u8 = 0x80;
+pub const ACTION_TOGGLE_MODE: u8 = 0x81;
+pub const ACTION_TOGGLE_LOCK: u8 = 0x82;
+
+/// What a number of taps in a row does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TapAction {
+    Key(KeyPressed),
+    /// Moves on to the next bonded host.
+    SwitchHost,
+    /// Like a long press.
+    ToggleMode,
+    /// Only this keeps working while the knob is locked.
+    ToggleLock,
+}
+
+impl TapAction {
+    const fn from_action(action: u8) -> Option<Self> {
+        match action {
+            ACTION_SWITCH_HOST => Some(TapAction::SwitchHost),
+            ACTION_TOGGLE_MODE => Some(TapAction::ToggleMode),
+            ACTION_TOGGLE_LOCK => Some(TapAction::ToggleLock),
+            action => match KeyPressed::from_action(action) {
+                Some(key) => Some(TapAction::Key(key)),
+                None => None,
+            },
+        }
+    }
+}
+
+/// Whether `event` can be remapped to the action byte `action`.
+pub const fn action_allowed(event: KnobEvent, action: u8) -> bool {
+    match action {
+        0 => true,
+        action if event.is_click() => TapAction::from_action(action).is_some(),
+        action => KeyPressed::from_action(action).is_some(),
+    }
+}
+
+/// Action byte per [`KnobEvent`], decoded by [`KeyPressed::from_action`]
+/// and for clicks the `ACTION_*` bytes as well. 0 keeps what the mode and
+/// [`KnobConfig`] do.
+pub static ACTIONS: [AtomicU8; KNOB_EVENTS] = [const { AtomicU8::new(0) }; KNOB_EVENTS];
+
+/// The key `event` is remapped to, if any.
+fn remapped(event: KnobEvent) -> Option<KeyPressed> {
+    KeyPressed::from_action(ACTIONS[event as usize].load(Ordering::Relaxed))
+}
+
+pub fn mode() -> KnobMode {
+    KnobMode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+pub fn set_mode(mode: KnobMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Whether `value` is a mode this profile can be in.
+pub const fn mode_allowed(value: u8) -> bool {
+    let mode = KnobMode::from_u8(value);
+    mode as u8 == value
+        && (mode as u8 == DEFAULT_MODE as u8 || mode as u8 == DEFAULT_MODE.toggled() as u8)
+}
+
+/// Restores a mode stored by an earlier boot. One the profile can't
+/// switch to, left over from a build with another profile, is ignored.
+pub fn restore_mode(value: u8) {
+    if mode_allowed(value) {
+        set_mode(KnobMode::from_u8(value));
+    } else {
+        warn!("Stored mode {} doesn't fit this profile, ignoring", value);
+    }
+}
+
+pub struct KnobPins {
+    /// Encoder A
+    pub a: Peri<'static, AnyPin>,
+    /// Encoder B
+    pub b: Peri<'static, AnyPin>,
+    /// The push switch on the encoder shaft, active low
+    pub button: Peri<'static, AnyPin>,
+}
+
+pub struct KnobConfig {
+    /// Pull applied to both encoder pins.
+    pub pull: Pull,
+    /// Clean edges of the encoder pins are taken right away. Once a pin
+    /// bounces it's given `min_debounce` to settle, growing towards
+    /// `max_debounce` the more it bounces.
+    pub min_debounce: Duration,
+    pub max_debounce: Duration,
+    /// Swaps the volume up and down directions, defaults to the
+    /// `invert-direction` feature.
+    pub invert: bool,
+    /// Where the encoder has its detents, a wrong one turns every detent
+    /// into several steps or only every few detents into one.
+    pub detent_mode: DetentMode,
+    /// Turning back within this of clicking into a detent is taken as the
+    /// encoder bouncing back, not as a turn.
+    pub glitch_dwell: Duration,
+    /// A detent turning back within this of the last one sent is dropped
+    /// as jitter, so a knob resting between detents can't warble the
+    /// volume up and down. Zero turns it off.
+    pub jitter_window: Duration,
+    /// What 1 to [`MAX_TAPS`] taps of the button in a row do, unless
+    /// remapped over GATT. `None` does nothing.
+    pub taps: [Option<TapAction>; MAX_TAPS as usize],
+    /// How soon after the last tap another one adds to the count.
+    pub tap_window: Duration,
+    /// Turning the knob while holding the button keeps repeating the key
+    /// until the button is released or the knob is turned back.
+    pub hold_to_repeat: bool,
+    pub repeat_interval: Duration,
+    /// Volume steps are multiplied by this while the button is held, for
+    /// coarse changes. 1 turns it off, `hold_to_repeat` takes precedence.
+    pub coarse_multiplier: u8,
+    /// Pins the knob to a mode, ignoring long presses and the GATT
+    /// remapping, for a second knob. It has to be one the profile's
+    /// descriptor has the keys for.
+    pub fixed_mode: Option<KnobMode>,
+    /// Puts the chip into dormant sleep when nobody connected, waking up
+    /// on this knob's pins, and powers it off after a hold until this
+    /// knob's button is pressed. Only one knob may do this.
+    pub sleep: bool,
+}
+
+impl Default for KnobConfig {
+    fn default() -> Self {
+        Self {
+            pull: Pull::Up,
+            min_debounce: Duration::from_micros(100),
+            max_debounce: Duration::from_millis(5),
+            invert: cfg!(feature = "invert-direction"),
+            detent_mode: DetentMode::Full,
+            glitch_dwell: Duration::from_micros(500),
+            jitter_window: Duration::from_millis(40),
+            taps: [
+                Some(TapAction::Key(DEFAULT_CLICK)),
+                Some(DEFAULT_DOUBLE_CLICK),
+                Some(TapAction::ToggleLock),
+                None,
+            ],
+            tap_window: TAP_WINDOW,
+            hold_to_repeat: false,
+            repeat_interval: Duration::from_millis(150),
+            coarse_multiplier: 4,
+            fixed_mode: None,
+            sleep: true,
+        }
+    }
+}
+
+impl KnobConfig {
+    /// For a second knob next to the main one, sending next and previous
+    /// track whatever mode the main one is in.
+    pub fn second() -> Self {
+        Self {
+            fixed_mode: Some(KnobMode::Media),
+            sleep: false,
+            ..Self::default()
+        }
+    }
+
+    fn mode(&self) -> KnobMode {
+        self.fixed_mode.unwrap_or_else(mode)
+    }
+
+    fn remapped(&self, event: KnobEvent) -> Option<KeyPressed> {
+        match self.fixed_mode {
+            Some(_) => None,
+            None => remapped(event),
+        }
+    }
+
+    fn tap_action(&self, taps: u8) -> Option<TapAction> {
+        let event = KnobEvent::taps(taps)?;
+        let action = match self.fixed_mode {
+            Some(_) => 0,
+            None => ACTIONS[event as usize].load(Ordering::Relaxed),
+        };
+        tap_action(&self.taps, taps, action)
+    }
+}
+
+/// What `taps` taps in a row do, with the click remapped to the action
+/// byte `action` over GATT, 0 for not remapped.
+const fn tap_action(
+    configured: &[Option<TapAction>; MAX_TAPS as usize],
+    taps: u8,
+    action: u8,
+) -> Option<TapAction> {
+    if KnobEvent::taps(taps).is_none() {
+        return None;
+    }
+    match TapAction::from_action(action) {
+        Some(remapped) => Some(remapped),
+        None => configured[taps as usize - 1],
+    }
+}
+
+// 1 to `MAX_TAPS` taps counted by the classifier pick their own action,
+// and a remapped click replaces it
+const _: () = {
+    let configured = [
+        Some(TapAction::Key(KeyPressed::Mute)),
+        Some(TapAction::SwitchHost),
+        Some(TapAction::ToggleLock),
+        None,
+    ];
+    let mut clicks = ClickClassifier::new(TAP_WINDOW);
+    let mut t = 0;
+    let mut n = 1;
+    while n <= MAX_TAPS {
+        let mut counted = None;
+        let mut i = 0;
+        while i < n {
+            core::assert!(clicks.feed(true, Instant::from_millis(t)).is_none());
+            counted = clicks.feed(false, Instant::from_millis(t + 100));
+            t += 200;
+            i += 1;
+        }
+        // The last one possible is reported right on release
+        if n < MAX_TAPS {
+            counted = clicks.tick(Instant::from_millis(t + TAP_WINDOW.as_millis()));
+        }
+        core::assert!(
+            matches!(counted, Some(ClickEvent::Taps(taps)) if taps == n),
+            "taps weren't counted"
+        );
+        let action = tap_action(&configured, n, 0);
+        core::assert!(match n {
+            1 => matches!(action, Some(TapAction::Key(KeyPressed::Mute))),
+            2 => matches!(action, Some(TapAction::SwitchHost)),
+            3 => matches!(action, Some(TapAction::ToggleLock)),
+            _ => action.is_none(),
+        });
+        core::assert!(matches!(
+            tap_action(&configured, n, ACTION_TOGGLE_MODE),
+            Some(TapAction::ToggleMode)
+        ));
+        t += 10_000;
+        n += 1;
+    }
+    core::assert!(tap_action(&configured, 0, ACTION_TOGGLE_MODE).is_none());
+    core::assert!(tap_action(&configured, MAX_TAPS + 1, ACTION_TOGGLE_MODE).is_none());
+};
+
+/// Number of steps to send for a detent arriving `dt_ms` after the previous one.
+pub const fn accel(dt_ms: u32) -> u8 {
+    if dt_ms >= ACCEL_THRESHOLD_MS {
+        return 1;
+    }
+    let extra = (ACCEL_THRESHOLD_MS - dt_ms) * (ACCEL_MAX_STEPS as u32 - 1) / ACCEL_THRESHOLD_MS;
+    1 + extra as u8
+}
+
+// Slow turns are single steps, the fastest get the most, and turning
+// slower never gives more steps
+const _: () = {
+    core::assert!(accel(ACCEL_THRESHOLD_MS) == 1);
+    core::assert!(accel(u32::MAX) == 1);
+    core::assert!(accel(0) == ACCEL_MAX_STEPS);
+    core::assert!(accel(ACCEL_THRESHOLD_MS / 2) == 2);
+    let mut dt = 0;
+    while dt < ACCEL_THRESHOLD_MS + 10 {
+        core::assert!(accel(dt + 1) <= accel(dt));
+        dt += 1;
+    }
+};
+
+/// Signed detents per second a detent `dt_us` after the previous one
+/// stands for, clockwise positive and clamped to fit an `i8`.
+pub const fn velocity(dt_us: u64, clockwise: bool) -> i8 {
+    let per_sec = match dt_us {
+        0 => i8::MAX as u64,
+        dt_us => 1_000_000 / dt_us,
+    };
+    let per_sec = if per_sec > i8::MAX as u64 {
+        i8::MAX
+    } else {
+        per_sec as i8
+    };
+    if clockwise { per_sec } else { -per_sec }
+}
+
+const _: () = {
+    core::assert!(velocity(100_000, true) == 10);
+    core::assert!(velocity(100_000, false) == -10);
+    // Faster than fits is clamped
+    core::assert!(velocity(1_000, true) == i8::MAX);
+    core::assert!(velocity(0, false) == -i8::MAX);
+    // The first detent after a pause
+    core::assert!(velocity(2_000_000, true) == 0);
+};
+
+/// Whether a detent in `new` direction, `dt_us` after one in `prev`
+/// direction was sent, is jitter rather than the knob being turned back.
+pub const fn is_jitter(prev: Direction, new: Direction, dt_us: u64, window_us: u64) -> bool {
+    let reversed = matches!(
+        (prev, new),
+        (Direction::Left, Direction::Right) | (Direction::Right, Direction::Left)
+    );
+    reversed && dt_us < window_us
+}
+
+const _: () = {
+    let window = 40_000;
+    // Turning back right after a detent
+    core::assert!(is_jitter(Direction::Right, Direction::Left, 5_000, window));
+    core::assert!(is_jitter(Direction::Left, Direction::Right, 39_999, window));
+    // Turning back after a pause
+    core::assert!(!is_jitter(
+        Direction::Right,
+        Direction::Left,
+        window,
+        window
+    ));
+    // Turning quickly in one direction
+    core::assert!(!is_jitter(
+        Direction::Right,
+        Direction::Right,
+        1_000,
+        window
+    ));
+    core::assert!(!is_jitter(Direction::Left, Direction::Left, 1_000, window));
+    // Nothing sent before
+    core::assert!(!is_jitter(Direction::None, Direction::Left, 0, window));
+    // Turned off
+    core::assert!(!is_jitter(Direction::Right, Direction::Left, 0, 0));
+};
+
+/// Keys toggling something on the host and how long after being sent they
+/// can't be sent again, so a bouncy or doubled press can't toggle twice.
+const COOLDOWNS: [(KeyPressed, Duration); 3] = [
+    (KeyPressed::Mute, Duration::from_millis(400)),
+    (KeyPressed::KeyboardMute, Duration::from_millis(400)),
+    (KeyPressed::PlayPause, Duration::from_millis(400)),
+];
+/// When each key of [`COOLDOWNS`] was last sent in µs, 0 if never.
+static LAST_SENT_US: [AtomicU64; COOLDOWNS.len()] = [const { AtomicU64::new(0) }; COOLDOWNS.len()];
+
+/// Whether a key last sent at `last_us`, 0 if never, can go out again at `now_us`.
+pub const fn cooled_down(last_us: u64, now_us: u64, cooldown_us: u64) -> bool {
+    last_us == 0 || now_us.saturating_sub(last_us) >= cooldown_us
+}
+
+const _: () = {
+    let cooldown = 400_000;
+    core::assert!(cooled_down(0, 5, cooldown));
+    // A bounce right after
+    core::assert!(!cooled_down(1_000_000, 1_020_000, cooldown));
+    core::assert!(!cooled_down(1_000_000, 1_399_999, cooldown));
+    core::assert!(cooled_down(1_000_000, 1_400_000, cooldown));
+    // A clock behind the last send never lets it through early
+    core::assert!(!cooled_down(1_000_000, 999_000, cooldown));
+};
+
+/// What happens to a new key while the channel is full and `oldest` is the
+/// next one out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Overflow {
+    /// The oldest key is stale by now, drop it to make room.
+    EvictOldest,
+    /// The two undo each other, drop both.
+    Cancel,
+}
+
+pub const fn on_full(oldest: KeyPressed, newest: KeyPressed) -> Overflow {
+    match (oldest, newest) {
+        (KeyPressed::VolUp, KeyPressed::VolDown)
+        | (KeyPressed::VolDown, KeyPressed::VolUp)
+        | (KeyPressed::ScrollUp, KeyPressed::ScrollDown)
+        | (KeyPressed::ScrollDown, KeyPressed::ScrollUp) => Overflow::Cancel,
+        _ => Overflow::EvictOldest,
+    }
+}
+
+const _: () = {
+    // A quick reversal of a long spin
+    core::assert!(matches!(
+        on_full(KeyPressed::VolUp, KeyPressed::VolDown),
+        Overflow::Cancel
+    ));
+    core::assert!(matches!(
+        on_full(KeyPressed::ScrollDown, KeyPressed::ScrollUp),
+        Overflow::Cancel
+    ));
+    // Still spinning the same way, the newest detent wins
+    core::assert!(matches!(
+        on_full(KeyPressed::VolUp, KeyPressed::VolUp),
+        Overflow::EvictOldest
+    ));
+    core::assert!(matches!(
+        on_full(KeyPressed::Mute, KeyPressed::VolDown),
+        Overflow::EvictOldest
+    ));
+    core::assert!(matches!(
+        on_full(KeyPressed::VolUp, KeyPressed::ScrollDown),
+        Overflow::EvictOldest
+    ));
+};
+
+/// Queues a key press for the transport, unless it's still cooling down.
+/// [`crate::transport::forward_keys`] sends it whichever one it is.
+pub fn send_key(key: KeyPressed) {
+    if let Some(i) = COOLDOWNS.iter().position(|(k, _)| *k == key) {
+        let now = Instant::now().as_micros();
+        let last = LAST_SENT_US[i].load(Ordering::Relaxed);
+        if !cooled_down(last, now, COOLDOWNS[i].1.as_micros()) {
+            debug!(
+                "{:?} dropped, sent {} ms ago",
+                key,
+                now.saturating_sub(last) / 1000
+            );
+            return;
+        }
+        LAST_SENT_US[i].store(now, Ordering::Relaxed);
+    }
+    HAPTIC_PULSE.signal(());
+    // Don't block the knob when no host is draining the channel,
+    // make room as the oldest events are stale by then anyway.
+    if KEY_PRESS_CHANNEL.try_send(key).is_ok() {
+        return;
+    }
+    let Ok(oldest) = KEY_PRESS_CHANNEL.try_receive() else {
+        return;
+    };
+    match on_full(oldest, key) {
+        Overflow::EvictOldest => {
+            warn!("Key press channel full, dropping {:?}", oldest);
+            // Nothing else sends in between, the slot just freed is there
+            let _ = KEY_PRESS_CHANNEL.try_send(key);
+        }
+        Overflow::Cancel => debug!("Key press channel full, {:?} cancels {:?}", key, oldest),
+    }
+}
+
+/// Whether the button is held down for the whole `duration`.
+pub async fn button_held(button: Peri<'_, AnyPin>, duration: Duration) -> bool {
+    let button = Input::new(button, Pull::Up);
+    // Let the pull up settle
+    Timer::after_millis(1).await;
+
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if button.is_high() {
+            return false;
+        }
+        Timer::after_millis(10).await;
+    }
+    true
+}
+
+/// Checks the wiring of the encoder: both pins have to read high at rest,
+/// which only a [`DetentMode::Full`] encoder reliably does, and detents in
+/// both directions have to come in within [`SELF_TEST_TIMEOUT`]. A swapped
+/// pair of pins can't be told apart from a knob turned the other way.
+pub async fn self_test(pins: &mut KnobPins, config: &KnobConfig) -> bool {
+    let a = Input::new(pins.a.reborrow(), config.pull);
+    let b = Input::new(pins.b.reborrow(), config.pull);
+    // Let the pulls settle
+    Timer::after_millis(1).await;
+
+    if config.detent_mode == DetentMode::Full {
+        let mut at_rest = true;
+        for (pin, input) in [(Pin::A, &a), (Pin::B, &b)] {
+            if input.is_low() {
+                error!(
+                    "[self-test] pin {:?} is low at rest, shorted or not pulled up",
+                    pin
+                );
+                at_rest = false;
+            }
+        }
+        if !at_rest {
+            return false;
+        }
+    }
+
+    info!("[self-test] turn the knob both ways");
+    let mut a = AdaptiveDebouncer::new(a, config.min_debounce, config.max_debounce);
+    let mut b = AdaptiveDebouncer::new(b, config.min_debounce, config.max_debounce);
+    let mut decoder = QuadratureDecoder::new(
+        a.is_high(),
+        b.is_high(),
+        config.detent_mode,
+        config.glitch_dwell.as_micros(),
+    );
+    let mut edges = [0u32; 2];
+    let (mut right, mut left) = (false, false);
+    let turned = with_timeout(SELF_TEST_TIMEOUT, async {
+        while !(right && left) {
+            match select(a.wait_for_any_edge(), b.wait_for_any_edge()).await {
+                Either::First(_) => edges[0] += 1,
+                Either::Second(_) => edges[1] += 1,
+            }
+            match decoder.update(a.is_high(), b.is_high(), Instant::now().as_micros()) {
+                Some(Direction::Right) => right = true,
+                Some(Direction::Left) => left = true,
+                _ => {}
+            }
+        }
+    })
+    .await;
+
+    if turned.is_ok() {
+        info!("[self-test] passed");
+        return true;
+    }
+    for (pin, edges) in [(Pin::A, edges[0]), (Pin::B, edges[1])] {
+        if edges == 0 {
+            error!("[self-test] pin {:?} never changed, not connected?", pin);
+        }
+    }
+    error!(
+        "[self-test] failed, turned right: {}, left: {}",
+        right, left
+    );
+    false
+}
+
+pub fn is_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// Locks or unlocks the knob, like [`TapAction::ToggleLock`].
+pub fn set_locked(locked: bool) {
+    LOCKED.store(locked, Ordering::Relaxed);
+    LOCK_STATE.signal(locked);
+}
+
+fn on_click(click: ClickEvent, config: &KnobConfig) {
+    event::record(FwEvent::Click { click });
+    let locked = LOCKED.load(Ordering::Relaxed);
+    match click {
+        ClickEvent::Taps(1) if !locked && AWAITING_CONFIRMATION.load(Ordering::Relaxed) => {
+            info!("Button: confirming passkey");
+            PASSKEY_CONFIRMED.signal(());
+        }
+        ClickEvent::Taps(taps) => match config.tap_action(taps) {
+            Some(TapAction::ToggleLock) => {
+                info!(
+                    "Button: {} taps, {}",
+                    taps,
+                    if locked { "unlocked" } else { "locked" }
+                );
+                set_locked(!locked);
+            }
+            _ if locked => info!("Button: {:?} ignored, locked", click),
+            Some(TapAction::Key(key)) => {
+                debug!("Button: {} taps, {:?}", taps, key);
+                send_key(key);
+            }
+            Some(TapAction::SwitchHost) => {
+                debug!("Button: {} taps, switching host", taps);
+                SWITCH_HOST.signal(());
+            }
+            Some(TapAction::ToggleMode) => toggle_mode(config),
+            None => debug!("Button: nothing to do for {} taps", taps),
+        },
+        _ if locked => info!("Button: {:?} ignored, locked", click),
+        ClickEvent::Hold => POWER_OFF.signal(()),
+        ClickEvent::Long => toggle_mode(config),
+    }
+}
+
+/// Switches to the other mode, a knob with a fixed one stays in it.
+fn toggle_mode(config: &KnobConfig) {
+    if config.fixed_mode.is_some() {
+        return;
+    }
+    let mode = mode().toggled();
+    set_mode(mode);
+    MODE_CHANGED.signal(());
+    event::record(FwEvent::ModeChange { mode });
+    BLINK.signal(match mode {
+        KnobMode::Volume | KnobMode::Presenter | KnobMode::Scroll => 1,
+        KnobMode::Media => 2,
+    });
+}
+
+/// Spawned once per encoder, all of them send to [`KEY_PRESS_CHANNEL`].
+/// With `pio`, the encoder's transitions are the ones it counted instead
+/// of the pins' edges.
+#[embassy_executor::task(pool_size = 2)]
+pub async fn knob_controller(mut pins: KnobPins, config: KnobConfig, mut pio: Option<PioEncoder>) {
+    let mut in1 = AdaptiveDebouncer::new(
+        Input::new(pins.a, config.pull),
+        config.min_debounce,
+        config.max_debounce,
+    );
+    let mut in2 = AdaptiveDebouncer::new(
+        Input::new(pins.b, config.pull),
+        config.min_debounce,
+        config.max_debounce,
+    );
+    let mut button_debounce_ms = BUTTON_DEBOUNCE_MS.load(Ordering::Relaxed);
+    let mut button = Debouncer::new(
+        Input::new(pins.button.reborrow(), Pull::Up),
+        Duration::from_millis(button_debounce_ms as u64),
+    );
+
+    let new_decoder =
+        |a, b| QuadratureDecoder::new(a, b, config.detent_mode, config.glitch_dwell.as_micros());
+    let mut decoder = new_decoder(in1.is_high(), in2.is_high());
+    // Levels of the encoder pins after the last transition
+    let mut levels = (in1.is_high(), in2.is_high());
+    let mut stuck_pins = StuckPinDetector::default();
+    let mut clicks = ClickClassifier::new(config.tap_window);
+    let mut last_detent: Option<Instant> = None;
+    // Direction and time of the last detent that wasn't jitter
+    let mut last_sent = (Direction::None, Instant::MIN);
+    let mut repeat: Option<KeyPressed> = None;
+    // Wakes the loop up to beat while the knob isn't touched
+    let mut heartbeat = Ticker::every(HEARTBEAT_INTERVAL);
+
+    loop {
+        KNOB_HEARTBEAT.beat();
+
+        // The debouncer can't be changed, a new one takes over
+        if BUTTON_DEBOUNCE_MS.load(Ordering::Relaxed) != button_debounce_ms {
+            button_debounce_ms = BUTTON_DEBOUNCE_MS.load(Ordering::Relaxed);
+            drop(button);
+            button = Debouncer::new(
+                Input::new(pins.button.reborrow(), Pull::Up),
+                Duration::from_millis(button_debounce_ms as u64),
+            );
+        }
+
+        let repeat_tick = async {
+            match repeat {
+                Some(_) => Timer::after(config.repeat_interval).await,
+                None => pending().await,
+            }
+        };
+
+        let click_timeout = async {
+            match clicks.deadline() {
+                Some(at) => Timer::at(at).await,
+                None => pending().await,
+            }
+        };
+
+        // A signal wakes a single waiter, so only one knob waits for it
+        let sleep = async {
+            if config.sleep {
+                select(SLEEP.wait(), RADIO_STOPPED.wait()).await
+            } else {
+                pending().await
+            }
+        };
+
+        // The pin that changed and the levels of both after it
+        let encoder_edge = async {
+            match pio.as_mut() {
+                // The pins' edges only wake the task up, the transitions
+                // are those counted by the PIO, which doesn't miss any
+                Some(pio) => loop {
+                    if let Some((a, b)) = pio.next_transition(levels).await {
+                        let pin = if a != levels.0 { Pin::A } else { Pin::B };
+                        break (pin, (a, b));
+                    }
+                    // Infallible errors
+                    select(in1.wait_for_any_edge(), in2.wait_for_any_edge()).await;
+                },
+                None => {
+                    // Infallible errors
+                    let pin = match select(in1.wait_for_any_edge(), in2.wait_for_any_edge()).await {
+                        Either::First(_) => Pin::A,
+                        Either::Second(_) => Pin::B,
+                    };
+                    (pin, (in1.is_high(), in2.is_high()))
+                }
+            }
+        };
+
+        // Infallible errors
+        let edge = select4(
+            encoder_edge,
+            button.wait_for_any_edge(),
+            select3(repeat_tick, heartbeat.next(), sleep),
+            click_timeout,
+        )
+        .await;
+
+        match edge {
+            Either4::First((pin, new_levels)) => {
+                ACTIVITY.signal(());
+                levels = new_levels;
+                if stuck_pins.edge(pin) {
+                    match stuck_pins.stuck() {
+                        Some(pin) => {
+                            warn!("Encoder pin {:?} looks stuck, ignoring rotation", pin);
+                            diagnostics::count(&PIN_FAULTS);
+                        }
+                        None => {
+                            info!("Encoder pins recovered");
+                            // Whatever was decoded while stuck is garbage
+                            decoder = new_decoder(levels.0, levels.1);
+                        }
+                    }
+                }
+                if stuck_pins.stuck().is_some() {
+                    continue;
+                }
+            }
+            Either4::Second(_) => {
+                ACTIVITY.signal(());
+                // The button is active low
+                let pressed = button.is_low().unwrap();
+                if !pressed && repeat.take().is_some() {
+                    info!("Repeat stopped");
+                }
+                if let Some(click) = clicks.feed(pressed, Instant::now()) {
+                    on_click(click, &config);
+                }
+                continue;
+            }
+            Either4::Third(Either3::First(_)) => {
+                if let Some(key) = repeat {
+                    send_key(key);
+                }
+                continue;
+            }
+            Either4::Third(Either3::Second(_)) => continue,
+            Either4::Third(Either3::Third(Either::Second(_))) => {
+                info!("Powering off until the button is pressed");
+                BLINK.signal(POWER_OFF_BLINKS);
+                Timer::after(led::blink_duration(POWER_OFF_BLINKS)).await;
+                // Only the plain pin can wake the chip
+                drop(button);
+                let mut button = Input::new(pins.button.reborrow(), Pull::Up);
+                // Still held from the hold, letting go mustn't wake it up
+                while button.is_low() {
+                    KNOB_HEARTBEAT.beat();
+                    Timer::after_millis(button_debounce_ms as u64).await;
+                }
+                Timer::after_millis(button_debounce_ms as u64).await;
+                power::cut_radio_power();
+                {
+                    let _wake = button.dormant_wake(DormantWakeConfig {
+                        edge_high: false,
+                        edge_low: true,
+                        level_high: false,
+                        level_low: false,
+                    });
+                    clocks::dormant_sleep();
+                }
+                info!("Woken up by the button, starting over");
+                // The cyw43 needs to be brought up again from scratch
+                SCB::sys_reset();
+            }
+            Either4::Third(Either3::Third(Either::First(_))) => {
+                info!("Sleeping until the knob is turned");
+                let wake_on_edges = DormantWakeConfig {
+                    edge_high: true,
+                    edge_low: true,
+                    level_high: false,
+                    level_low: false,
+                };
+                {
+                    let _wake_a = in1.dormant_wake(wake_on_edges);
+                    let _wake_b = in2.dormant_wake(wake_on_edges);
+                    // Stops every clock, the whole executor waits here
+                    clocks::dormant_sleep();
+                }
+                info!("Woken up by the knob");
+                // The edge that woke us up is picked up by the next wait
+                ACTIVITY.signal(());
+                continue;
+            }
+            Either4::Fourth(_) => {
+                if let Some(click) = clicks.tick(Instant::now()) {
+                    on_click(click, &config);
+                }
+                continue;
+            }
+        }
+
+        let Some(direction) = decoder.update(levels.0, levels.1, Instant::now().as_micros()) else {
+            continue;
+        };
+        let up = direction == Direction::Right;
+        diagnostics::count(if up { &RIGHT_DETENTS } else { &LEFT_DETENTS });
+        diagnostics::record_rotation(up);
+
+        let now = Instant::now();
+        let (last_direction, last_at) = last_sent;
+        if is_jitter(
+            last_direction,
+            direction,
+            (now - last_at).as_micros(),
+            config.jitter_window.as_micros(),
+        ) {
+            debug!("Rotation: {:?} dropped as jitter", direction);
+            continue;
+        }
+        if LOCKED.load(Ordering::Relaxed) {
+            debug!("Rotation: {:?} ignored, locked", direction);
+            continue;
+        }
+        if config.fixed_mode.is_none() {
+            VELOCITY.signal(velocity((now - last_at).as_micros(), up));
+        }
+        last_sent = (direction, now);
+
+        // A remapped direction is taken as is, inverting only flips the mode's keys
+        let inverted = config.invert != INVERT_DIRECTION.load(Ordering::Relaxed);
+        let event = if up {
+            KnobEvent::Clockwise
+        } else {
+            KnobEvent::CounterClockwise
+        };
+        let key = config
+            .remapped(event)
+            .unwrap_or(match (config.mode(), up != inverted) {
+                (KnobMode::Volume, true) => KeyPressed::VolUp,
+                (KnobMode::Volume, false) => KeyPressed::VolDown,
+                (KnobMode::Media, true) => KeyPressed::NextTrack,
+                (KnobMode::Media, false) => KeyPressed::PrevTrack,
+                (KnobMode::Presenter, true) => KeyPressed::NextSlide,
+                (KnobMode::Presenter, false) => KeyPressed::PrevSlide,
+                (KnobMode::Scroll, true) => KeyPressed::ScrollDown,
+                (KnobMode::Scroll, false) => KeyPressed::ScrollUp,
+            });
+
+        if config.hold_to_repeat && clicks.is_pressed() {
+            clicks.cancel();
+            repeat = match repeat {
+                // Turning back stops the repeat
+                Some(repeated) if repeated != key => None,
+                _ => Some(key),
+            };
+            info!("Repeat: {:?}", repeat);
+            if repeat.is_some() {
+                send_key(key);
+            }
+            continue;
+        }
+
+        let steps = match key {
+            KeyPressed::VolUp | KeyPressed::VolDown => {
+                let steps = last_detent.map_or(1, |last| accel((now - last).as_millis() as u32))
+                    * STEPS_PER_DETENT.load(Ordering::Relaxed);
+                // The pin itself, not the click state, so a missed release
+                // can't leave the knob coarse
+                let steps = if config.coarse_multiplier > 1 && button.is_low().unwrap() {
+                    // Letting go isn't a click
+                    clicks.cancel();
+                    debug!("Rotation: coarse");
+                    steps.saturating_mul(config.coarse_multiplier)
+                } else {
+                    steps
+                };
+                steps.min(MAX_BURST)
+            }
+            // Skipping several tracks or slides per detent is never wanted
+            _ => 1,
+        };
+        last_detent = Some(now);
+
+        debug!(
+            "Rotation: {}",
+            if inverted { "inverted" } else { "not inverted" }
+        );
+        event::record(if up {
+            FwEvent::RotRight { key, steps }
+        } else {
+            FwEvent::RotLeft { key, steps }
+        });
+        ROTATED.signal(());
+        for _ in 0..steps {
+            send_key(key);
+        }
+    }
+}
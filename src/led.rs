@@ -0,0 +1,332 @@
+use cyw43::Control;
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_rp::{
+    Peri, dma,
+    gpio::Output,
+    peripherals::PIO1,
+    pio::{Common, PioPin, StateMachine},
+    pio_programs::ws2812::{Grb, PioWs2812, PioWs2812Program},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer, with_deadline};
+use smart_leds::RGB8;
+
+use crate::fatal::FatalError;
+
+/// The cyw43 control is shared with other tasks, lock it for every operation.
+pub type SharedControl = Mutex<ThreadModeRawMutex, Control<'static>>;
+
+/// The onboard LED of the Pico W is wired to the cyw43 GPIO 0.
+const LED_GPIO: u8 = 0;
+
+const SLOW_BLINK_MS: u64 = 1000;
+const FAST_BLINK_MS: u64 = 100;
+/// Even blink while a click is waited for to confirm a passkey, slower than
+/// pairing so it's clear the knob wants something.
+const CONFIRM_BLINK_MS: u64 = 300;
+// A short blip every two seconds while the knob is locked
+const LOCKED_ON_MS: u64 = 50;
+const LOCKED_OFF_MS: u64 = 2000;
+/// On and off times while waiting for a bonded host to reconnect, a quick
+/// blip apart from the even blink of advertising to anyone.
+pub const RECONNECTING_ON_MS: u64 = 150;
+pub const RECONNECTING_OFF_MS: u64 = 600;
+// Blinks of a fatal error code, see `crate::fatal`
+const ERROR_LONG_ON_MS: u64 = 600;
+const ERROR_SHORT_ON_MS: u64 = 150;
+const ERROR_BLINK_OFF_MS: u64 = 300;
+const ERROR_CATEGORY_GAP_MS: u64 = 1000;
+const ERROR_REPEAT_GAP_MS: u64 = 2500;
+/// A new state is only shown once it held for this long, so going through
+/// a few states at once, like reconnecting, advertising and connecting,
+/// doesn't flicker.
+pub const STATE_SETTLE: Duration = Duration::from_millis(150);
+
+// Colors of a WS2812, kept dim as it sits right in front of the user
+pub const ADVERTISING_COLOR: RGB8 = RGB8::new(0, 0, 32);
+pub const RECONNECTING_COLOR: RGB8 = RGB8::new(0, 24, 24);
+pub const CONNECTED_COLOR: RGB8 = RGB8::new(0, 32, 0);
+pub const PAIRING_COLOR: RGB8 = RGB8::new(24, 0, 32);
+pub const CONFIRM_COLOR: RGB8 = RGB8::new(32, 20, 0);
+pub const ERROR_COLOR: RGB8 = RGB8::new(32, 0, 0);
+/// Flashed for a moment on every detent.
+pub const ROTATION_COLOR: RGB8 = RGB8::new(32, 32, 32);
+const ROTATION_FLASH_MS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConnState {
+    /// Not connected and not advertising, LED off.
+    Idle,
+    /// Advertising to anyone to pair, slow blink.
+    Advertising,
+    /// Advertising directed to the bonded host, a short blip.
+    Reconnecting,
+    /// Solid.
+    Connected,
+    /// Fast blink.
+    Pairing,
+    /// Waiting for a click to confirm the passkey, a slower even blink.
+    AwaitingConfirmation,
+    /// Something went wrong and is being retried, fast blink.
+    Error,
+}
+
+pub static CONN_STATE: Signal<ThreadModeRawMutex, ConnState> = Signal::new();
+/// Blinks the LED quickly this many times, then goes back to showing [`CONN_STATE`].
+pub static BLINK: Signal<ThreadModeRawMutex, u8> = Signal::new();
+/// Whether the knob is locked, shown in place of [`CONN_STATE`] while it is.
+pub static LOCK_STATE: Signal<ThreadModeRawMutex, bool> = Signal::new();
+/// The knob was turned, only LEDs with [`StatusLed::flash`] show it.
+pub static ROTATED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Blinks the code of a fatal error until the knob resets, nothing else is
+/// shown anymore.
+pub static ERROR_CODE: Signal<ThreadModeRawMutex, FatalError> = Signal::new();
+
+/// Something that can show the connection state, the blink patterns
+/// don't care what's behind it.
+// Async because the cyw43 LED sits behind SPI. Only used on the single
+// threaded executor, so the futures not being `Send` doesn't matter.
+#[allow(async_fn_in_trait)]
+pub trait StatusLed {
+    async fn set(&mut self, on: bool);
+
+    /// Called on every state change before the LED is set, for LEDs that
+    /// can tell the states apart.
+    async fn show(&mut self, _state: ConnState) {}
+
+    /// Shows that the knob was turned.
+    async fn flash(&mut self) {}
+}
+
+/// The onboard LED of the Pico W.
+pub struct Cyw43Led(pub &'static SharedControl);
+
+impl StatusLed for Cyw43Led {
+    async fn set(&mut self, on: bool) {
+        self.0.lock().await.gpio_set(LED_GPIO, on).await;
+    }
+}
+
+/// An LED on a regular GPIO, active high.
+impl StatusLed for Output<'static> {
+    async fn set(&mut self, on: bool) {
+        if on {
+            self.set_high();
+        } else {
+            self.set_low();
+        }
+    }
+}
+
+/// A WS2812 (NeoPixel) showing each state in its own color. cyw43 has
+/// PIO0, this takes state machine 0 of PIO1.
+pub struct Ws2812Led {
+    driver: PioWs2812<'static, PIO1, 0, 1, Grb>,
+    color: RGB8,
+    on: bool,
+}
+
+impl Ws2812Led {
+    pub fn new(
+        common: &mut Common<'static, PIO1>,
+        sm: StateMachine<'static, PIO1, 0>,
+        dma: Peri<'static, impl dma::Channel>,
+        pin: Peri<'static, impl PioPin>,
+    ) -> Self {
+        let program = PioWs2812Program::new(common);
+        let driver = PioWs2812::new(common, sm, dma, pin, &program);
+        Self {
+            driver,
+            color: ADVERTISING_COLOR,
+            on: false,
+        }
+    }
+
+    async fn write(&mut self, color: RGB8) {
+        self.driver.write(&[color]).await;
+    }
+}
+
+impl StatusLed for Ws2812Led {
+    async fn set(&mut self, on: bool) {
+        self.on = on;
+        let color = if on { self.color } else { RGB8::default() };
+        self.write(color).await;
+    }
+
+    async fn show(&mut self, state: ConnState) {
+        self.color = match state {
+            // Off anyway
+            ConnState::Idle => RGB8::default(),
+            ConnState::Advertising => ADVERTISING_COLOR,
+            ConnState::Reconnecting => RECONNECTING_COLOR,
+            ConnState::Connected => CONNECTED_COLOR,
+            ConnState::Pairing => PAIRING_COLOR,
+            ConnState::AwaitingConfirmation => CONFIRM_COLOR,
+            ConnState::Error => ERROR_COLOR,
+        };
+    }
+
+    async fn flash(&mut self) {
+        self.write(ROTATION_COLOR).await;
+        Timer::after_millis(ROTATION_FLASH_MS).await;
+        let on = self.on;
+        self.set(on).await;
+    }
+}
+
+/// How long [`BLINK`] takes to blink `times` times.
+pub const fn blink_duration(times: u8) -> Duration {
+    Duration::from_millis(2 * FAST_BLINK_MS * times as u64)
+}
+
+/// Blinks the LED quickly `times` times, to acknowledge something.
+pub async fn blink_fast(led: &mut impl StatusLed, times: u8) {
+    for _ in 0..times {
+        led.set(true).await;
+        Timer::after_millis(FAST_BLINK_MS).await;
+        led.set(false).await;
+        Timer::after_millis(FAST_BLINK_MS).await;
+    }
+}
+
+/// How long one round of [`blink_error_code`] takes, the gap after it
+/// included.
+pub const fn error_code_ms(long: u8, short: u8) -> u64 {
+    long as u64 * (ERROR_LONG_ON_MS + ERROR_BLINK_OFF_MS)
+        + ERROR_CATEGORY_GAP_MS
+        + short as u64 * (ERROR_SHORT_ON_MS + ERROR_BLINK_OFF_MS)
+        + ERROR_REPEAT_GAP_MS
+}
+
+/// How long the error blinking takes to show a code `rounds` times, the
+/// dark gap it starts with included.
+pub const fn error_rounds_ms(long: u8, short: u8, rounds: u8) -> u64 {
+    ERROR_REPEAT_GAP_MS + rounds as u64 * error_code_ms(long, short)
+}
+
+const _: () = {
+    core::assert!(error_code_ms(0, 0) == ERROR_CATEGORY_GAP_MS + ERROR_REPEAT_GAP_MS);
+    core::assert!(error_code_ms(2, 3) == 2 * 900 + 1000 + 3 * 450 + 2500);
+    core::assert!(error_rounds_ms(2, 3, 0) == 2500);
+    core::assert!(error_rounds_ms(2, 3, 3) == 2500 + 3 * error_code_ms(2, 3));
+};
+
+/// Blinks `long` long and then `short` short times, once.
+async fn blink_error_code(led: &mut impl StatusLed, long: u8, short: u8) {
+    for _ in 0..long {
+        led.set(true).await;
+        Timer::after_millis(ERROR_LONG_ON_MS).await;
+        led.set(false).await;
+        Timer::after_millis(ERROR_BLINK_OFF_MS).await;
+    }
+    Timer::after_millis(ERROR_CATEGORY_GAP_MS).await;
+    for _ in 0..short {
+        led.set(true).await;
+        Timer::after_millis(ERROR_SHORT_ON_MS).await;
+        led.set(false).await;
+        Timer::after_millis(ERROR_BLINK_OFF_MS).await;
+    }
+    Timer::after_millis(ERROR_REPEAT_GAP_MS).await;
+}
+
+async fn show_error(led: &mut impl StatusLed, error: FatalError) -> ! {
+    led.show(ConnState::Error).await;
+    led.set(false).await;
+    Timer::after_millis(ERROR_REPEAT_GAP_MS).await;
+    loop {
+        blink_error_code(led, error.category() as u8, error.code()).await;
+    }
+}
+
+async fn show_state(mut led: impl StatusLed) -> ! {
+    let mut state = ConnState::Idle;
+    let mut locked = false;
+    let mut on = false;
+    // When a blinking LED toggles next, other events don't move it
+    let mut next_toggle = Instant::now();
+    // The state last signaled and when it's shown, if it holds until then
+    let mut settling: Option<(ConnState, Instant)> = None;
+
+    loop {
+        if let Some((new_state, at)) = settling
+            && Instant::now() >= at
+        {
+            settling = None;
+            if new_state != state {
+                state = new_state;
+                led.show(state).await;
+                next_toggle = Instant::now();
+            }
+        }
+
+        // How long the LED stays on and off
+        let blink_ms = match state {
+            _ if locked => Some((LOCKED_ON_MS, LOCKED_OFF_MS)),
+            ConnState::Idle | ConnState::Connected => None,
+            ConnState::Advertising => Some((SLOW_BLINK_MS, SLOW_BLINK_MS)),
+            ConnState::Reconnecting => Some((RECONNECTING_ON_MS, RECONNECTING_OFF_MS)),
+            ConnState::Pairing | ConnState::Error => Some((FAST_BLINK_MS, FAST_BLINK_MS)),
+            ConnState::AwaitingConfirmation => Some((CONFIRM_BLINK_MS, CONFIRM_BLINK_MS)),
+        };
+
+        let deadline = match blink_ms {
+            Some((on_ms, off_ms)) => {
+                if Instant::now() >= next_toggle {
+                    on = !on;
+                    led.set(on).await;
+                    let ms = if on { on_ms } else { off_ms };
+                    next_toggle = Instant::now() + Duration::from_millis(ms);
+                }
+                next_toggle
+            }
+            None => {
+                on = state == ConnState::Connected;
+                led.set(on).await;
+                Instant::MAX
+            }
+        };
+        let deadline = settling.map_or(deadline, |(_, at)| deadline.min(at));
+
+        let event = select(
+            select4(
+                CONN_STATE.wait(),
+                BLINK.wait(),
+                ROTATED.wait(),
+                LOCK_STATE.wait(),
+            ),
+            ERROR_CODE.wait(),
+        );
+        let event = match with_deadline(deadline, event).await {
+            Ok(Either::First(event)) => event,
+            Ok(Either::Second(error)) => show_error(&mut led, error).await,
+            Err(_) => continue,
+        };
+        match event {
+            Either4::First(new_state) => {
+                settling = Some((new_state, Instant::now() + STATE_SETTLE));
+            }
+            Either4::Second(times) => blink_fast(&mut led, times).await,
+            Either4::Third(_) => led.flash().await,
+            Either4::Fourth(new_locked) => {
+                locked = new_locked;
+                next_toggle = Instant::now();
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn led_task(led: Cyw43Led) {
+    show_state(led).await
+}
+
+#[embassy_executor::task]
+pub async fn gpio_led_task(led: Output<'static>) {
+    show_state(led).await
+}
+
+#[embassy_executor::task]
+pub async fn ws2812_led_task(led: Ws2812Led) {
+    show_state(led).await
+}
@@ -0,0 +1,34 @@
+//! `info!` and `debug!` that can be turned down at runtime over GATT,
+//! warnings and errors always go through. Import these over the `defmt`
+//! ones to gate a module.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Only warnings and errors.
+pub const LOG_QUIET: u8 = 0;
+pub const LOG_INFO: u8 = 1;
+pub const LOG_DEBUG: u8 = 2;
+
+pub static LOG_LEVEL: AtomicU8 = AtomicU8::new(LOG_DEBUG);
+
+pub fn enabled(level: u8) -> bool {
+    LOG_LEVEL.load(Ordering::Relaxed) >= level
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LOG_INFO) {
+            defmt::info!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LOG_DEBUG) {
+            defmt::debug!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, info};
@@ -1,127 +1,390 @@
 #![no_std]
 #![no_main]
 
+pub mod battery;
 pub mod bluetooth;
+pub mod click;
+pub mod debounce;
+pub mod diagnostics;
+pub mod event;
+pub mod fatal;
+pub mod haptic;
 pub mod hid;
+pub mod knob;
+pub mod led;
+pub mod log;
+pub mod pio_encoder;
+#[cfg(feature = "input-pot")]
+pub mod pot;
+pub mod power;
+pub mod storage;
+pub mod transport;
+#[cfg(feature = "usb-hid")]
+pub mod usb;
+pub mod watchdog;
+
+pub use knob_core::{encoder, gatt, record};
+
+use core::sync::atomic::Ordering;
 
-use async_debounce::Debouncer;
-use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_futures::select::select;
 use embassy_rp::{
-    Peri, bind_interrupts,
-    clocks::RoscRng,
-    gpio::{AnyPin, Input, Level, Output, Pull},
-    peripherals::{DMA_CH0, PIO0},
-    pio::{InterruptHandler, Pio},
+    Peri,
+    adc::{self, Adc},
+    bind_interrupts,
+    gpio::{AnyPin, Level, Output, Pull},
+    peripherals::{PIN_12, PIN_22, PIO1, PWM_SLICE6},
+    pio::{Common, InterruptHandler, Pio},
+    pwm::{self, Pwm},
+    watchdog::Watchdog,
+};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, mutex::Mutex, signal::Signal,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
 use embassy_time::Duration;
-use embedded_hal::digital::InputPin;
-use embedded_hal_async::digital::Wait;
 use static_cell::StaticCell;
+
+use crate::{
+    battery::SharedAdc,
+    bluetooth::KeyPressed,
+    knob::KnobPins,
+    led::{CONN_STATE, ConnState, Ws2812Led},
+    storage::Storage,
+};
+
+// Only BLE brings up the cyw43
+#[cfg(not(feature = "usb-hid"))]
+use crate::{
+    bluetooth::{NoHooks, PairingPolicy},
+    led::{Cyw43Led, SharedControl},
+};
+#[cfg(not(feature = "usb-hid"))]
+use cyw43_pio::PioSpi;
+#[cfg(not(feature = "usb-hid"))]
+use embassy_rp::{
+    clocks::RoscRng,
+    peripherals::{DMA_CH0, PIO0},
+};
+#[cfg(feature = "usb-hid")]
+use embassy_rp::{
+    peripherals::USB,
+    usb::{self as rp_usb, Driver},
+};
+#[cfg(not(feature = "usb-hid"))]
 use trouble_host::prelude::ExternalController;
 
-use crate::bluetooth::KeyPressed;
+use defmt_rtt as _;
 
-use {defmt_rtt as _, panic_probe as _};
+const _: () = core::assert!(
+    cfg!(feature = "profile-volume") as u8
+        + cfg!(feature = "profile-media") as u8
+        + cfg!(feature = "profile-presenter") as u8
+        + cfg!(feature = "profile-scroll") as u8
+        == 1,
+    "Enable exactly one of the `profile-volume`, `profile-media`, `profile-presenter` and `profile-scroll` features"
+);
 
-const MASK: u8 = 0b111;
-const LEFT_P1: u8 = 0b100;
-const LEFT_P2: u8 = 0b110;
-const LEFT_P1_INV: u8 = 0b011;
-const LEFT_P2_INV: u8 = 0b001;
-const RIGHT_P1: u8 = 0b110;
-const RIGHT_P2: u8 = 0b100;
-const RIGHT_P1_INV: u8 = 0b001;
-const RIGHT_P2_INV: u8 = 0b011;
+const _: () = core::assert!(
+    !cfg!(feature = "absolute-volume")
+        || cfg!(feature = "input-pot")
+            && (cfg!(feature = "profile-volume") || cfg!(feature = "profile-media")),
+    "`absolute-volume` needs `input-pot` and the `profile-volume` or `profile-media` feature"
+);
 
-const DEBOUNCE_MS: u64 = 1;
+/// Blinks once the encoder passed the `self-test`.
+const SELF_TEST_PASSED_BLINKS: u8 = 2;
+
+/// How long the button has to be held at boot to forget the bonds.
+const FORGET_BOND_HOLD: Duration = Duration::from_secs(3);
 
 const CYW43_FW: &[u8] = include_bytes!("../cyw43-firmware/43439A0.bin");
 const CYW43_CLM: &[u8] = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
 const CYW43_BTFW: &[u8] = include_bytes!("../cyw43-firmware/43439A0_btfw.bin");
 
-pub static KEY_PRESS_CHANNEL: Channel<ThreadModeRawMutex, KeyPressed, 48> = Channel::new();
+// A truncated blob, or a Git LFS pointer checked out in place of one, makes
+// the cyw43 init hang without saying why. They're baked into the image, so
+// they're checked at build time. The minimums are well below the sizes of
+// the blobs shipped in `cyw43-firmware`.
+const CYW43_FW_MIN_LEN: usize = 200 * 1024;
+const CYW43_CLM_MIN_LEN: usize = 512;
+const CYW43_BTFW_MIN_LEN: usize = 4 * 1024;
+const _: () = {
+    core::assert!(
+        CYW43_FW.len() >= CYW43_FW_MIN_LEN,
+        "cyw43-firmware/43439A0.bin is truncated or missing"
+    );
+    core::assert!(
+        CYW43_CLM.len() >= CYW43_CLM_MIN_LEN,
+        "cyw43-firmware/43439A0_clm.bin is truncated or missing"
+    );
+    // The CLM data comes in a container starting with this magic
+    core::assert!(
+        matches!(CYW43_CLM, [b'B', b'L', b'O', b'B', ..]),
+        "cyw43-firmware/43439A0_clm.bin isn't a CLM blob"
+    );
+    core::assert!(
+        CYW43_BTFW.len() >= CYW43_BTFW_MIN_LEN,
+        "cyw43-firmware/43439A0_btfw.bin is truncated or missing"
+    );
+};
+
+pub const KEY_PRESS_CHANNEL_LEN: usize = 64;
+/// Shared by every knob, with room for a fast spin of two of them.
+pub static KEY_PRESS_CHANNEL: Channel<ThreadModeRawMutex, KeyPressed, KEY_PRESS_CHANNEL_LEN> =
+    Channel::new();
+/// Signaled on every encoder edge and GATT event, keeps the connection from idling out.
+pub static ACTIVITY: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Signaled when nobody connected for a while, the knob puts the chip
+/// into dormant sleep until it's turned again.
+pub static SLEEP: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Signaled by the knob to move on to the next bonded host.
+pub static SWITCH_HOST: Signal<ThreadModeRawMutex, ()> = Signal::new();
 
 bind_interrupts!(struct Irqs {
+    #[cfg(not(feature = "usb-hid"))]
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    #[cfg(feature = "usb-hid")]
+    USBCTRL_IRQ => rp_usb::InterruptHandler<USB>;
 });
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    let mut storage = Storage::new(p.FLASH);
+    let settings = storage.load_settings();
+    info!("Settings: {:?}", settings);
+    knob::STEPS_PER_DETENT.store(
+        settings
+            .steps_per_detent
+            .clamp(1, knob::STEPS_PER_DETENT_MAX),
+        Ordering::Relaxed,
+    );
+    knob::INVERT_DIRECTION.store(settings.invert_direction, Ordering::Relaxed);
+    for (action, &stored) in knob::ACTIONS.iter().zip(settings.actions.iter()) {
+        action.store(stored, Ordering::Relaxed);
+    }
+    knob::restore_mode(settings.mode);
+    if transport::press_ms_allowed(settings.press_ms) {
+        transport::PRESS_MS.store(settings.press_ms, Ordering::Relaxed);
+    }
+    bluetooth::RSSI_INTERVAL_SECS.store(settings.rssi_interval_secs, Ordering::Relaxed);
+    if bluetooth::lock_rssi_allowed(settings.lock_rssi) {
+        bluetooth::LOCK_RSSI.store(settings.lock_rssi, Ordering::Relaxed);
+    }
+    if battery::calibration_allowed(settings.battery_empty_mv, settings.battery_full_mv) {
+        battery::EMPTY_MV.store(settings.battery_empty_mv, Ordering::Relaxed);
+        battery::FULL_MV.store(settings.battery_full_mv, Ordering::Relaxed);
+    }
+    knob::BUTTON_DEBOUNCE_MS.store(
+        settings
+            .button_debounce_ms
+            .clamp(knob::BUTTON_DEBOUNCE_MIN_MS, knob::BUTTON_DEBOUNCE_MAX_MS),
+        Ordering::Relaxed,
+    );
+
+    // Change these to match your wiring
+    let mut knob_pins = KnobPins {
+        a: p.PIN_16.into(),
+        b: p.PIN_17.into(),
+        button: p.PIN_18.into(),
+    };
+
+    // Holding the button while plugging the knob in forgets all hosts
+    let forget_bond = knob::button_held(knob_pins.button.reborrow(), FORGET_BOND_HOLD).await;
+    if forget_bond {
+        info!("Button held at boot, forgetting bonds");
+        storage.erase_bonds();
+    }
+
+    let self_test = cfg!(all(feature = "self-test", not(feature = "input-pot")));
+    let self_test_passed =
+        !self_test || knob::self_test(&mut knob_pins, &knob::KnobConfig::default()).await;
+    if self_test && self_test_passed {
+        // Shown once the LED task is up
+        led::BLINK.signal(SELF_TEST_PASSED_BLINKS);
+    }
+
+    // Every task has to be spawned before `run_bluetooth` is awaited at the
+    // end of `main`, it never returns.
+
+    // The CHRG line of a charger, e.g. `Some(p.PIN_15.into())` for a TP4056.
+    // Without one the knob always reports not charging.
+    let charge_pin: Option<Peri<'static, AnyPin>> = None;
+    if let Some(pin) = charge_pin {
+        spawner.spawn(battery::charge_monitor(pin)).unwrap();
+    }
+
+    static ADC: StaticCell<SharedAdc> = StaticCell::new();
+    let adc = ADC.init(Mutex::new(Adc::new(p.ADC, Irqs, adc::Config::default())));
+    let battery_channel = adc::Channel::new_pin(p.PIN_26, Pull::None);
     spawner
-        .spawn(knob_controller(p.PIN_16.into(), p.PIN_17.into()))
+        .spawn(battery::battery_monitor(adc, battery_channel))
         .unwrap();
 
-    let pwr = Output::new(p.PIN_23, Level::Low);
-    let cs = Output::new(p.PIN_25, Level::High);
-    let mut pio = Pio::new(p.PIO0, Irqs);
-    let spi = PioSpi::new(
-        &mut pio.common,
-        pio.sm0,
-        cyw43_pio::DEFAULT_CLOCK_DIVIDER,
-        pio.irq0,
-        cs,
-        p.PIN_24,
-        p.PIN_29,
-        p.DMA_CH0,
-    );
+    // cyw43 has PIO0, the WS2812 and the encoder share PIO1
+    static PIO1_COMMON: StaticCell<Common<'static, PIO1>> = StaticCell::new();
+    let pio1 = Pio::new(p.PIO1, Irqs);
+    // Kept forever, dropping it would stop the PIO
+    let pio1_common = PIO1_COMMON.init(pio1.common);
 
-    static CYW43_STATE: StaticCell<cyw43::State> = StaticCell::new();
-    let cyw43_state = CYW43_STATE.init(cyw43::State::new());
-    let (_net_device, bt_device, mut control, runner) =
-        cyw43::new_with_bluetooth(cyw43_state, pwr, spi, CYW43_FW, CYW43_BTFW).await;
-    spawner.spawn(cyw43_task(runner)).unwrap();
-    control.init(CYW43_CLM).await;
+    #[cfg(not(feature = "input-pot"))]
+    {
+        // Counts the main encoder's transitions in PIO1, for knobs spun
+        // fast enough that the CPU misses edges
+        let pio_encoder = cfg!(feature = "pio-encoder").then(|| {
+            let program = pio_encoder::PioEncoderProgram::new(pio1_common);
+            pio_encoder::PioEncoder::new(pio1.sm1, &program, &knob_pins.a, &knob_pins.b)
+        });
+        spawner
+            .spawn(knob::knob_controller(
+                knob_pins,
+                knob::KnobConfig::default(),
+                pio_encoder,
+            ))
+            .unwrap();
+    }
+    // A second encoder sending next and previous track, e.g.
+    // `Some(KnobPins { a: p.PIN_19.into(), b: p.PIN_20.into(), button: p.PIN_21.into() })`.
+    // Without a push switch on it, give it any free pin, the pull up keeps
+    // it released.
+    #[cfg(not(feature = "input-pot"))]
+    let second_knob: Option<KnobPins> = None;
+    #[cfg(not(feature = "input-pot"))]
+    if let Some(pins) = second_knob {
+        spawner
+            .spawn(knob::knob_controller(
+                pins,
+                knob::KnobConfig::second(),
+                None,
+            ))
+            .unwrap();
+    }
+    // The pot replaces the encoder, the button is still used at boot
+    #[cfg(feature = "input-pot")]
+    spawner
+        .spawn(pot::pot_controller(
+            adc,
+            adc::Channel::new_pin(p.PIN_27, Pull::None),
+        ))
+        .unwrap();
+
+    // A haptic motor or piezo on a PWM pin and its slice, e.g.
+    // `Some((p.PWM_SLICE6, p.PIN_12))`, ticks on every key sent. Change the
+    // types along with it.
+    let haptic: Option<(Peri<'static, PWM_SLICE6>, Peri<'static, PIN_12>)> = None;
+    if let Some((slice, pin)) = haptic {
+        let pwm = Pwm::new_output_a(slice, pin, pwm::Config::default());
+        spawner.spawn(haptic::haptic_task(pwm)).unwrap();
+    }
+
+    // BLE only, a `usb-hid` knob leaves the radio off along with the
+    // onboard LED wired to it
+    #[cfg(not(feature = "usb-hid"))]
+    let (control, bt_device) = {
+        let pwr = Output::new(p.PIN_23, Level::Low);
+        let cs = Output::new(p.PIN_25, Level::High);
+        let mut pio = Pio::new(p.PIO0, Irqs);
+        let spi = PioSpi::new(
+            &mut pio.common,
+            pio.sm0,
+            cyw43_pio::DEFAULT_CLOCK_DIVIDER,
+            pio.irq0,
+            cs,
+            p.PIN_24,
+            p.PIN_29,
+            p.DMA_CH0,
+        );
 
-    let bt_controller: ExternalController<_, 10> = ExternalController::new(bt_device);
+        static CYW43_STATE: StaticCell<cyw43::State> = StaticCell::new();
+        let cyw43_state = CYW43_STATE.init(cyw43::State::new());
+        let (_net_device, bt_device, mut control, runner) =
+            cyw43::new_with_bluetooth(cyw43_state, pwr, spi, CYW43_FW, CYW43_BTFW).await;
+        spawner.spawn(cyw43_task(runner)).unwrap();
+        control.init(CYW43_CLM).await;
 
-    bluetooth::run_bluetooth(bt_controller, RoscRng).await;
+        static CONTROL: StaticCell<SharedControl> = StaticCell::new();
+        (CONTROL.init(Mutex::new(control)), bt_device)
+    };
+    // A status LED on a GPIO, e.g. `Some(p.PIN_14.into())`, for enclosures
+    // hiding the onboard one. Without one the onboard LED is used, or none
+    // with `usb-hid`.
+    let external_led: Option<Peri<'static, AnyPin>> = None;
+    // A WS2812 showing the state in color, e.g. `Some(p.PIN_22)`, takes
+    // precedence over both. Change the pin type along with it.
+    let rgb_led: Option<Peri<'static, PIN_22>> = None;
+    match (rgb_led, external_led) {
+        (Some(pin), _) => {
+            let mut led = Ws2812Led::new(pio1_common, pio1.sm0, p.DMA_CH1, pin);
+            if forget_bond {
+                led::blink_fast(&mut led, 10).await;
+            }
+            spawner.spawn(led::ws2812_led_task(led)).unwrap();
+        }
+        (None, Some(pin)) => {
+            let mut led = Output::new(pin, Level::Low);
+            if forget_bond {
+                led::blink_fast(&mut led, 10).await;
+            }
+            spawner.spawn(led::gpio_led_task(led)).unwrap();
+        }
+        #[cfg(not(feature = "usb-hid"))]
+        (None, None) => {
+            let mut led = Cyw43Led(control);
+            if forget_bond {
+                led::blink_fast(&mut led, 10).await;
+            }
+            spawner.spawn(led::led_task(led)).unwrap();
+        }
+        #[cfg(feature = "usb-hid")]
+        (None, None) => {}
+    }
+    #[cfg(not(feature = "usb-hid"))]
+    spawner.spawn(power::power_task(control)).unwrap();
+
+    if !self_test_passed {
+        // Stays blinking the error until the wiring is fixed and it's reset
+        CONN_STATE.signal(ConnState::Error);
+        core::future::pending::<()>().await;
+    }
+
+    // Started last, the heartbeats only come in once everything is running
+    spawner
+        .spawn(watchdog::watchdog_task(Watchdog::new(p.WATCHDOG)))
+        .unwrap();
+
+    #[cfg(feature = "usb-hid")]
+    {
+        let serial = bluetooth::serial_number(storage.unique_id());
+        usb::run_usb(spawner, Driver::new(p.USB, Irqs), serial).await;
+    }
+
+    #[cfg(not(feature = "usb-hid"))]
+    {
+        let bt_controller: ExternalController<_, 10> = ExternalController::new(bt_device);
+
+        // A click confirms the passkey the host shows. Builds without the
+        // button, or without anyone at the knob, confirm it on their own, or
+        // use `JustWorks` when there's no way to check a passkey at all.
+        let pairing_policy = if cfg!(any(feature = "auto-confirm", feature = "input-pot")) {
+            PairingPolicy::AutoConfirm
+        } else {
+            PairingPolicy::ButtonConfirm
+        };
+        // Forks put their own `ConnHooks` here
+        let hooks = NoHooks;
+
+        bluetooth::run_bluetooth(bt_controller, RoscRng, &mut storage, pairing_policy, hooks).await;
+    }
 }
 
+#[cfg(not(feature = "usb-hid"))]
 #[embassy_executor::task]
 async fn cyw43_task(
     runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
 ) -> ! {
     runner.run().await
 }
-
-#[embassy_executor::task]
-async fn knob_controller(p1: Peri<'static, AnyPin>, p2: Peri<'static, AnyPin>) {
-    let mut in1 = Debouncer::new(Input::new(p1, Pull::Up), Duration::from_millis(DEBOUNCE_MS));
-    let mut in2 = Debouncer::new(Input::new(p2, Pull::Up), Duration::from_millis(DEBOUNCE_MS));
-
-    let mut in1_history: u8 = in1.is_high().unwrap() as u8;
-    let mut in2_history: u8 = in2.is_high().unwrap() as u8;
-
-    loop {
-        // Infallible errors
-        let _ = select(in1.wait_for_any_edge(), in2.wait_for_any_edge()).await;
-        in1_history <<= 1;
-        in1_history |= in1.is_high().unwrap() as u8;
-        in2_history <<= 1;
-        in2_history |= in2.is_high().unwrap() as u8;
-
-        let in1_pattern = in1_history & MASK;
-        let in2_pattern = in2_history & MASK;
-
-        // info!("P1 {:03b} P2 {:03b}", in1_pattern, in2_pattern);
-        // info!("P1 {:01b} P2 {:01b}", in1_history & 1, in2_history & 1);
-
-        if in1_pattern == LEFT_P1 && in2_pattern == LEFT_P2
-            || in1_pattern == LEFT_P1_INV && in2_pattern == LEFT_P2_INV
-        {
-            info!("Rot left");
-            KEY_PRESS_CHANNEL.send(KeyPressed::VolDown).await;
-        } else if in1_pattern == RIGHT_P1 && in2_pattern == RIGHT_P2
-            || in1_pattern == RIGHT_P1_INV && in2_pattern == RIGHT_P2_INV
-        {
-            info!("Rot right");
-            KEY_PRESS_CHANNEL.send(KeyPressed::VolUp).await;
-        }
-    }
-}
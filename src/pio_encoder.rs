@@ -0,0 +1,165 @@
+//! Counts the encoder transitions in a PIO state machine, so a spin too
+//! fast for the CPU to see every edge still adds up right.
+//!
+//! cyw43 has PIO0. This takes state machine 1 of PIO1 and 26 of its 32
+//! instructions, loaded at 0 for the jump table, state machine 0 is left
+//! for the WS2812.
+
+use embassy_rp::{
+    Peri,
+    gpio::{AnyPin, Pin},
+    pac,
+    peripherals::PIO1,
+    pio::{
+        Common, Config, FifoJoin, LoadedProgram, ShiftDirection, StateMachine,
+        program::{
+            Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource,
+            OutDestination,
+        },
+    },
+    pio_programs::clock_divider::calculate_pio_clock_divider,
+};
+
+use crate::encoder::{self, Direction};
+
+/// How often the state machine goes through its loop, sampling both pins.
+const SAMPLE_HZ: u32 = 200_000;
+/// Instructions per loop while the pins don't change.
+const LOOP_INSTRUCTIONS: u32 = 7;
+
+/// Keeps the net count of transitions in Y, up for [`Direction::Right`],
+/// and pushes it every loop without waiting, so the FIFO never stalls it
+/// and the newest count is always a pull away. The previous and current
+/// pin levels make up the address of a jump table entry counting that
+/// transition, built from [`encoder::step`].
+pub struct PioEncoderProgram {
+    program: LoadedProgram<'static, PIO1>,
+}
+
+impl PioEncoderProgram {
+    pub fn new(common: &mut Common<'static, PIO1>) -> Self {
+        let mut a = Assembler::<32>::new();
+        let mut decrement = a.label();
+        let mut update = a.label();
+        let mut increment = a.label();
+        let mut increment_done = a.label();
+
+        // Address 0bPPCC, P the previous levels and C the current ones, B in
+        // the upper bit of each as the PIO reads pins upwards from A
+        let state = |levels: u8| encoder::state(levels & 0b01 != 0, levels & 0b10 != 0);
+        for address in 0..16u8 {
+            let target = match encoder::step(state(address >> 2), state(address & 0b11)) {
+                Direction::Right => &mut increment,
+                Direction::Left => &mut decrement,
+                Direction::None => &mut update,
+            };
+            a.jmp(JmpCondition::Always, target);
+        }
+
+        a.bind(&mut decrement);
+        // Falls through to the update with Y at zero as well
+        a.jmp(JmpCondition::YDecNonZero, &mut update);
+        a.bind(&mut update);
+        a.mov(MovDestination::ISR, MovOperation::None, MovSource::Y);
+        a.push(false, false);
+        // The current levels from the last jump become the previous ones
+        a.out(OutDestination::ISR, 2);
+        a.r#in(InSource::PINS, 2);
+        a.mov(MovDestination::OSR, MovOperation::None, MovSource::ISR);
+        a.mov(MovDestination::PC, MovOperation::None, MovSource::ISR);
+        // The PIO can only decrement, Y goes up as its inverse goes down
+        a.bind(&mut increment);
+        a.mov(MovDestination::Y, MovOperation::Invert, MovSource::Y);
+        a.jmp(JmpCondition::YDecNonZero, &mut increment_done);
+        a.bind(&mut increment_done);
+        a.mov(MovDestination::Y, MovOperation::Invert, MovSource::Y);
+
+        let program = a
+            .assemble_with_wrap(increment_done, update)
+            .set_origin(Some(0));
+        Self {
+            program: common.load_program(&program),
+        }
+    }
+}
+
+/// The transitions of an encoder on two consecutive pins, A being the
+/// lower one. The knob task still owns the pins as inputs, the PIO can
+/// read any pin whatever it's used for.
+pub struct PioEncoder {
+    sm: StateMachine<'static, PIO1, 1>,
+    // PIO count the knob task was handed transitions up to
+    seen: u32,
+    // Last count read from the PIO
+    count: u32,
+}
+
+impl PioEncoder {
+    pub fn new(
+        mut sm: StateMachine<'static, PIO1, 1>,
+        program: &PioEncoderProgram,
+        pin_a: &Peri<'_, AnyPin>,
+        pin_b: &Peri<'_, AnyPin>,
+    ) -> Self {
+        assert_eq!(
+            pin_b.pin(),
+            pin_a.pin() + 1,
+            "A and B have to be consecutive"
+        );
+        let mut cfg = Config::default();
+        cfg.fifo_join = FifoJoin::RxOnly;
+        cfg.shift_in.direction = ShiftDirection::Left;
+        cfg.shift_out.direction = ShiftDirection::Right;
+        cfg.clock_divider = calculate_pio_clock_divider(SAMPLE_HZ * LOOP_INSTRUCTIONS);
+        cfg.use_program(&program.program, &[]);
+        sm.set_config(&cfg);
+        // Only pins taken over by the PIO can be passed to the config, these
+        // stay plain inputs
+        pac::PIO1
+            .sm(1)
+            .pinctrl()
+            .modify(|w| w.set_in_base(pin_a.pin()));
+        sm.set_enable(true);
+        // The first push is from before the pins were sampled. By the second
+        // one a knob resting between detents was counted as turned there
+        // from both pins low, which it never was.
+        let mut pull = || loop {
+            if let Some(count) = sm.rx().try_pull() {
+                break count;
+            }
+        };
+        pull();
+        let seen = pull();
+        Self {
+            sm,
+            seen,
+            count: seen,
+        }
+    }
+
+    /// The newest count, the FIFO holds older ones.
+    async fn read_count(&mut self) -> u32 {
+        while self.sm.rx().try_pull().is_some() {}
+        self.sm.rx().wait_pull().await
+    }
+
+    /// The levels of A and B after the next transition counted since the
+    /// last call, starting from `levels`, or `None` once there's none left.
+    /// Transitions undone before they were read cancel out.
+    pub async fn next_transition(&mut self, levels: (bool, bool)) -> Option<(bool, bool)> {
+        if self.seen == self.count {
+            self.count = self.read_count().await;
+        }
+        let direction = match self.count.wrapping_sub(self.seen) as i32 {
+            0 => return None,
+            ahead if ahead > 0 => Direction::Right,
+            _ => Direction::Left,
+        };
+        self.seen = match direction {
+            Direction::Right => self.seen.wrapping_add(1),
+            _ => self.seen.wrapping_sub(1),
+        };
+        let state = encoder::next_state(encoder::state(levels.0, levels.1), direction);
+        Some(encoder::levels(state))
+    }
+}
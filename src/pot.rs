@@ -0,0 +1,93 @@
+use defmt::*;
+use embassy_rp::adc::Channel;
+use embassy_time::{Duration, Timer};
+
+use core::sync::atomic::Ordering;
+
+use crate::{
+    ACTIVITY,
+    battery::SharedAdc,
+    bluetooth::{ABSOLUTE_VOLUME, KeyPressed, VOLUME_LEVEL},
+    knob::send_key,
+    watchdog::KNOB_HEARTBEAT,
+};
+
+const ADC_MAX: u16 = 4095;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+/// Readings closer than this to the last accepted one are noise.
+const DEADBAND: u16 = 40;
+/// How much a single volume step moves the host, 2% on Windows.
+/// Other hosts step differently, so the pot only roughly matches there.
+const PERCENT_PER_STEP: u8 = 2;
+/// Extra steps sent when the pot reaches an end, to catch up with a host
+/// whose volume was changed elsewhere.
+const SYNC_STEPS: u8 = 10;
+
+fn raw_to_percent(raw: u16) -> u8 {
+    (raw.min(ADC_MAX) as u32 * 100 / ADC_MAX as u32) as u8
+}
+
+/// Follows a linear potentiometer, moving the host volume towards the pot
+/// position with relative steps. The host volume isn't known, so the knob
+/// keeps its own estimate, starting out at wherever the pot is at boot.
+/// With `absolute-volume` the position is sent as is to hosts taking it.
+#[embassy_executor::task]
+pub async fn pot_controller(adc: &'static SharedAdc, mut channel: Channel<'static>) {
+    let mut accepted: Option<u16> = None;
+    let mut volume: u8 = 0;
+
+    loop {
+        KNOB_HEARTBEAT.beat();
+        Timer::after(SAMPLE_INTERVAL).await;
+        let result = adc.lock().await.read(&mut channel).await;
+        let raw = match result {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("[pot] error reading ADC: {:?}", e);
+                continue;
+            }
+        };
+
+        let Some(last) = accepted else {
+            accepted = Some(raw);
+            volume = raw_to_percent(raw);
+            info!("[pot] starting at {}%", volume);
+            continue;
+        };
+        if raw.abs_diff(last) < DEADBAND {
+            continue;
+        }
+        accepted = Some(raw);
+        ACTIVITY.signal(());
+
+        let target = raw_to_percent(raw);
+        if ABSOLUTE_VOLUME.load(Ordering::Relaxed) {
+            // The host is right where the pot is, steps pick up from there
+            volume = target;
+            info!("[pot] volume set to {}%", volume);
+            VOLUME_LEVEL.signal(volume);
+            continue;
+        }
+        let (key, mut steps) = if target > volume {
+            (KeyPressed::VolUp, (target - volume) / PERCENT_PER_STEP)
+        } else {
+            (KeyPressed::VolDown, (volume - target) / PERCENT_PER_STEP)
+        };
+        // Reaching an end pushes the host all the way there as well
+        if (target == 0 || target == 100) && target != volume {
+            steps += SYNC_STEPS;
+        }
+        if steps == 0 {
+            continue;
+        }
+
+        volume = match key {
+            KeyPressed::VolUp => volume.saturating_add(steps * PERCENT_PER_STEP).min(100),
+            _ => volume.saturating_sub(steps * PERCENT_PER_STEP),
+        };
+        info!("[pot] {:?} x{}, now at {}%", key, steps, volume);
+        for _ in 0..steps {
+            send_key(key);
+        }
+    }
+}
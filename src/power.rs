@@ -0,0 +1,86 @@
+use cyw43::PowerManagementMode;
+use defmt::*;
+use embassy_rp::{
+    gpio::{Level, Output},
+    peripherals::PIN_23,
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+
+use crate::led::SharedControl;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RadioPower {
+    /// Lowest latency, while the knob is being used.
+    Full,
+    /// Connected but idle for a while.
+    Save,
+}
+
+impl RadioPower {
+    fn mode(self) -> PowerManagementMode {
+        match self {
+            RadioPower::Full => PowerManagementMode::Performance,
+            RadioPower::Save => PowerManagementMode::Aggressive,
+        }
+    }
+}
+
+pub static RADIO_POWER: Signal<ThreadModeRawMutex, RadioPower> = Signal::new();
+/// Signaled by the knob to power off, the BLE task drops the connection and
+/// stops advertising for good.
+pub static POWER_OFF: Signal<ThreadModeRawMutex, ()> = Signal::new();
+/// Signaled back by the BLE task once the radio can be cut off.
+pub static RADIO_STOPPED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Holds the cyw43 in reset by pulling WL_ON low, for the lowest current
+/// while powered off or to get it off the air after a panic. Only a reset
+/// brings it back.
+pub fn cut_radio_power() {
+    // Safety: the cyw43 driver owns the pin, but it's never used again
+    // before the reset
+    let pin = unsafe { PIN_23::steal() };
+    // Dropping it would let the pin float
+    core::mem::forget(Output::new(pin, Level::Low));
+}
+
+#[embassy_executor::task]
+pub async fn power_task(control: &'static SharedControl) {
+    let mut power = RadioPower::Full;
+    control
+        .lock()
+        .await
+        .set_power_management(power.mode())
+        .await;
+
+    let mut since = Instant::now();
+    // Time spent in power save since boot, to see what it's worth
+    let mut saving = Duration::from_secs(0);
+
+    loop {
+        let new_power = RADIO_POWER.wait().await;
+        if new_power == power {
+            continue;
+        }
+
+        let elapsed = since.elapsed();
+        if power == RadioPower::Save {
+            saving += elapsed;
+        }
+        info!(
+            "[power] {:?} -> {:?} after {} s, {} s in power save since boot",
+            power,
+            new_power,
+            elapsed.as_secs(),
+            saving.as_secs()
+        );
+
+        control
+            .lock()
+            .await
+            .set_power_management(new_power.mode())
+            .await;
+        power = new_power;
+        since = Instant::now();
+    }
+}
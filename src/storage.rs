@@ -0,0 +1,463 @@
+use defmt::*;
+use embassy_rp::{
+    Peri,
+    flash::{Blocking, ERASE_SIZE, Flash},
+    peripherals::FLASH,
+};
+use trouble_host::prelude::*;
+
+use crate::{
+    battery::{EMPTY_DEFAULT_MV, FULL_DEFAULT_MV},
+    bluetooth::{DEVICE_NAME_MAX, RSSI_INTERVAL_DEFAULT_SECS},
+    knob::{self, KNOB_EVENTS},
+    record::{self, Kind, Slot, record_len},
+    transport::PRESS_DEFAULT_MS,
+};
+
+/// Size of the flash on the Pico W.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+// The last four sectors of the flash, reserved in `memory.x`. The bonds and
+// the settings are stored in two copies each, see `record`.
+const BOND_OFFSETS: [u32; 2] = [
+    (FLASH_SIZE - ERASE_SIZE) as u32,
+    (FLASH_SIZE - 4 * ERASE_SIZE) as u32,
+];
+const SETTINGS_OFFSETS: [u32; 2] = [
+    (FLASH_SIZE - 2 * ERASE_SIZE) as u32,
+    (FLASH_SIZE - 3 * ERASE_SIZE) as u32,
+];
+
+/// Number of hosts the knob remembers.
+pub const BOND_SLOTS: usize = 3;
+
+const BONDS: Kind = Kind {
+    magic: *b"SVKB",
+    version: 4,
+};
+// is_bonded + security_level + has_irk + bd_addr + ltk + irk
+const BOND_LEN: usize = 1 + 1 + 1 + 6 + 16 + 16;
+const SLOT_LEN: usize = record::SLOT_OVERHEAD + BOND_LEN;
+// active slot + slots
+const BONDS_LEN: usize = 1 + BOND_SLOTS * SLOT_LEN;
+
+const SETTINGS: Kind = Kind {
+    magic: *b"SVKS",
+    version: 11,
+};
+// steps_per_detent + actions + invert_direction + mode + press_ms
+// + rssi_interval_secs + lock_rssi + battery_empty_mv + battery_full_mv
+// + button_debounce_ms
+pub const SETTINGS_LEN: usize = 1 + KNOB_EVENTS + 1 + 1 + 1 + 1 + 1 + 2 + 2 + 1;
+// Fits into one write at the default ATT MTU of 23
+const _: () = core::assert!(SETTINGS_LEN <= 23 - 3);
+/// The device name, zero padded, is stored after the settings. It's kept
+/// out of the settings characteristic, which has to fit into one write.
+const STORED_LEN: usize = SETTINGS_LEN + DEVICE_NAME_MAX;
+
+/// Room for the longest record.
+const RECORD_MAX: usize = {
+    let (bonds, settings) = (record_len(BONDS_LEN), record_len(STORED_LEN));
+    if bonds > settings { bonds } else { settings }
+};
+const _: () = core::assert!(RECORD_MAX <= ERASE_SIZE);
+
+/// Settings changed at runtime over GATT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    pub steps_per_detent: u8,
+    /// Action bytes, indexed by [`crate::knob::KnobEvent`].
+    pub actions: [u8; KNOB_EVENTS],
+    /// Flips the direction on top of the build time default.
+    pub invert_direction: bool,
+    /// The [`crate::knob::KnobMode`] last switched to.
+    pub mode: u8,
+    /// How long keys are held, see [`crate::transport::PRESS_MS`].
+    pub press_ms: u8,
+    /// See [`crate::bluetooth::RSSI_INTERVAL_SECS`].
+    pub rssi_interval_secs: u8,
+    /// See [`crate::bluetooth::LOCK_RSSI`].
+    pub lock_rssi: i8,
+    /// See [`crate::battery::EMPTY_MV`].
+    pub battery_empty_mv: u16,
+    /// See [`crate::battery::FULL_MV`].
+    pub battery_full_mv: u16,
+    /// See [`crate::knob::BUTTON_DEBOUNCE_MS`].
+    pub button_debounce_ms: u8,
+}
+
+impl Settings {
+    /// The layout stored in flash, also read and written over GATT.
+    pub const DEFAULT: Self = Self {
+        steps_per_detent: 1,
+        actions: [0; KNOB_EVENTS],
+        invert_direction: false,
+        mode: knob::DEFAULT_MODE as u8,
+        press_ms: PRESS_DEFAULT_MS,
+        rssi_interval_secs: RSSI_INTERVAL_DEFAULT_SECS,
+        lock_rssi: 0,
+        battery_empty_mv: EMPTY_DEFAULT_MV,
+        battery_full_mv: FULL_DEFAULT_MV,
+        button_debounce_ms: knob::BUTTON_DEBOUNCE_DEFAULT_MS,
+    };
+
+    pub const fn encode(&self) -> [u8; SETTINGS_LEN] {
+        let mut buf = [0u8; SETTINGS_LEN];
+        buf[0] = self.steps_per_detent;
+        let mut i = 0;
+        while i < KNOB_EVENTS {
+            buf[1 + i] = self.actions[i];
+            i += 1;
+        }
+        buf[1 + KNOB_EVENTS] = self.invert_direction as u8;
+        buf[2 + KNOB_EVENTS] = self.mode;
+        buf[3 + KNOB_EVENTS] = self.press_ms;
+        buf[4 + KNOB_EVENTS] = self.rssi_interval_secs;
+        buf[5 + KNOB_EVENTS] = self.lock_rssi as u8;
+        [buf[6 + KNOB_EVENTS], buf[7 + KNOB_EVENTS]] = self.battery_empty_mv.to_le_bytes();
+        [buf[8 + KNOB_EVENTS], buf[9 + KNOB_EVENTS]] = self.battery_full_mv.to_le_bytes();
+        buf[10 + KNOB_EVENTS] = self.button_debounce_ms;
+        buf
+    }
+
+    pub const fn decode(buf: &[u8; SETTINGS_LEN]) -> Self {
+        let mut actions = [0; KNOB_EVENTS];
+        let mut i = 0;
+        while i < KNOB_EVENTS {
+            actions[i] = buf[1 + i];
+            i += 1;
+        }
+        Self {
+            steps_per_detent: buf[0],
+            actions,
+            invert_direction: buf[1 + KNOB_EVENTS] != 0,
+            mode: buf[2 + KNOB_EVENTS],
+            press_ms: buf[3 + KNOB_EVENTS],
+            rssi_interval_secs: buf[4 + KNOB_EVENTS],
+            lock_rssi: buf[5 + KNOB_EVENTS] as i8,
+            battery_empty_mv: u16::from_le_bytes([buf[6 + KNOB_EVENTS], buf[7 + KNOB_EVENTS]]),
+            battery_full_mv: u16::from_le_bytes([buf[8 + KNOB_EVENTS], buf[9 + KNOB_EVENTS]]),
+            button_debounce_ms: buf[10 + KNOB_EVENTS],
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The bonded hosts, only the active one is advertised to.
+#[derive(Debug, Clone, Default)]
+pub struct Bonds {
+    slots: [Option<BondInformation>; BOND_SLOTS],
+    active: usize,
+}
+
+impl Bonds {
+    pub fn active(&self) -> Option<&BondInformation> {
+        self.slots[self.active].as_ref()
+    }
+
+    pub fn active_slot(&self) -> usize {
+        self.active
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BondInformation> {
+        self.slots.iter().flatten()
+    }
+
+    /// Puts `bond` in the active slot, dropping any other slot holding the
+    /// same host. Returns whether anything changed.
+    pub fn set_active_bond(&mut self, bond: BondInformation) -> bool {
+        if self.active() == Some(&bond) {
+            return false;
+        }
+        for slot in self.slots.iter_mut() {
+            if slot
+                .as_ref()
+                .is_some_and(|other| other.identity.bd_addr == bond.identity.bd_addr)
+            {
+                *slot = None;
+            }
+        }
+        self.slots[self.active] = Some(bond);
+        true
+    }
+
+    /// Moves on to the next bonded slot. Empty slots are skipped, except
+    /// for the first one so there's always a way to pair another host.
+    pub fn switch(&mut self) {
+        let first_empty = self.slots.iter().position(Option::is_none);
+        for i in 1..=BOND_SLOTS {
+            let slot = (self.active + i) % BOND_SLOTS;
+            if self.slots[slot].is_some() || Some(slot) == first_empty {
+                self.active = slot;
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, defmt::Format)]
+enum WriteError {
+    Flash(embassy_rp::flash::Error),
+    /// What was read back isn't what was written.
+    Mismatch,
+}
+
+impl From<embassy_rp::flash::Error> for WriteError {
+    fn from(e: embassy_rp::flash::Error) -> Self {
+        WriteError::Flash(e)
+    }
+}
+
+pub struct Storage<'d> {
+    flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl<'d> Storage<'d> {
+    pub fn new(flash: Peri<'d, FLASH>) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    /// The unique ID of the flash chip, zeroes if it can't be read.
+    pub fn unique_id(&mut self) -> [u8; 8] {
+        let mut id = [0u8; 8];
+        if let Err(e) = self.flash.blocking_unique_id(&mut id) {
+            warn!("[storage] error reading the flash unique ID: {:?}", e);
+        }
+        id
+    }
+
+    /// Loads the newest intact copy of the bonds, no bonds when neither
+    /// is, and a slot not matching its CRC as an empty one.
+    pub fn load_bonds(&mut self) -> Bonds {
+        let Some(buf) = self.load::<BONDS_LEN>(BONDS, BOND_OFFSETS) else {
+            return Bonds::default();
+        };
+
+        let mut bonds = Bonds::default();
+        if (buf[0] as usize) < BOND_SLOTS {
+            bonds.active = buf[0] as usize;
+        }
+        let slots = bonds.slots.iter_mut().zip(buf[1..].chunks_exact(SLOT_LEN));
+        for (i, (slot, data)) in slots.enumerate() {
+            match record::open_slot(data) {
+                Slot::Empty => {}
+                Slot::Used(bond) => *slot = decode_bond(bond),
+                Slot::Corrupted => warn!(
+                    "[storage] bond slot {} is corrupted, treating it as empty",
+                    i
+                ),
+            }
+        }
+        bonds
+    }
+
+    /// Stores the bonds, replacing the previous ones.
+    pub fn store_bonds(&mut self, bonds: &Bonds) {
+        let mut buf = [0u8; BONDS_LEN];
+        buf[0] = bonds.active as u8;
+        for (slot, data) in bonds.slots.iter().zip(buf[1..].chunks_exact_mut(SLOT_LEN)) {
+            if let Some(bond) = slot {
+                record::seal_slot(&encode_bond(bond), data);
+            }
+        }
+        match self.update(BONDS, BOND_OFFSETS, [0; BONDS_LEN], |data| *data = buf) {
+            Ok(copy) => info!(
+                "[storage] bonds stored in copy {}, active slot {}",
+                copy, bonds.active
+            ),
+            Err(e) => warn!("[storage] error storing bonds: {:?}", e),
+        }
+    }
+
+    pub fn erase_bonds(&mut self) {
+        for offset in BOND_OFFSETS {
+            match self
+                .flash
+                .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            {
+                Ok(_) => info!("[storage] bonds at 0x{:x} erased", offset),
+                Err(e) => warn!("[storage] error erasing bonds: {:?}", e),
+            }
+        }
+    }
+
+    /// Erases the bonds and the settings.
+    pub fn factory_reset(&mut self) {
+        self.erase_bonds();
+        for offset in SETTINGS_OFFSETS {
+            match self
+                .flash
+                .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            {
+                Ok(_) => info!("[storage] settings at 0x{:x} erased", offset),
+                Err(e) => warn!("[storage] error erasing settings: {:?}", e),
+            }
+        }
+    }
+
+    /// Loads the newest intact copy of the settings, falling back to the
+    /// defaults when neither is.
+    pub fn load_settings(&mut self) -> Settings {
+        match self.load::<STORED_LEN>(SETTINGS, SETTINGS_OFFSETS) {
+            Some(data) => Settings::decode(data.first_chunk().unwrap()),
+            None => Settings::default(),
+        }
+    }
+
+    /// Stores the settings, keeping the name.
+    pub fn store_settings(&mut self, settings: &Settings) {
+        match self.update(SETTINGS, SETTINGS_OFFSETS, default_stored(), |data| {
+            data[..SETTINGS_LEN].copy_from_slice(&settings.encode());
+        }) {
+            Ok(copy) => info!("[storage] settings stored in copy {}: {:?}", copy, settings),
+            Err(e) => warn!("[storage] error storing settings: {:?}", e),
+        }
+    }
+
+    /// The name set over GATT, zero padded. All zeroes when none was.
+    pub fn load_name(&mut self) -> [u8; DEVICE_NAME_MAX] {
+        match self.load::<STORED_LEN>(SETTINGS, SETTINGS_OFFSETS) {
+            Some(data) => *data.last_chunk().unwrap(),
+            None => [0; DEVICE_NAME_MAX],
+        }
+    }
+
+    /// Stores the name, keeping the settings.
+    pub fn store_name(&mut self, name: &[u8; DEVICE_NAME_MAX]) {
+        match self.update(SETTINGS, SETTINGS_OFFSETS, default_stored(), |data| {
+            data[SETTINGS_LEN..].copy_from_slice(name)
+        }) {
+            Ok(copy) => info!("[storage] name stored in copy {}", copy),
+            Err(e) => warn!("[storage] error storing name: {:?}", e),
+        }
+    }
+
+    /// The data of the newest intact copy of a record.
+    fn load<const N: usize>(&mut self, kind: Kind, offsets: [u32; 2]) -> Option<[u8; N]> {
+        let copies = self.read_copies::<N>(kind, offsets);
+        let newest = record::newest(copies.map(|copy| copy.map(|(sequence, _)| sequence)))?;
+        copies[newest].map(|(_, data)| data)
+    }
+
+    /// Changes the data of the newest copy of a record, or `default` when
+    /// there's none, and writes it over the older copy. The newer one is
+    /// only superseded once the write has been read back. Returns the copy
+    /// written.
+    fn update<const N: usize>(
+        &mut self,
+        kind: Kind,
+        offsets: [u32; 2],
+        default: [u8; N],
+        update: impl FnOnce(&mut [u8; N]),
+    ) -> Result<usize, WriteError> {
+        let copies = self.read_copies::<N>(kind, offsets);
+        let sequences = copies.map(|copy| copy.map(|(sequence, _)| sequence));
+        let mut data = record::newest(sequences)
+            .and_then(|newest| copies[newest])
+            .map_or(default, |(_, data)| data);
+        update(&mut data);
+        let (copy, sequence) = record::next(sequences);
+        let mut buf = [0u8; RECORD_MAX];
+        let sealed = &mut buf[..record_len(N)];
+        record::seal(kind, sequence, &data, sealed);
+        self.write_verified(offsets[copy], sealed)?;
+        Ok(copy)
+    }
+
+    /// The sequence number and data of both copies of a record, `None` for
+    /// one that can't be read or isn't intact.
+    fn read_copies<const N: usize>(
+        &mut self,
+        kind: Kind,
+        offsets: [u32; 2],
+    ) -> [Option<(u32, [u8; N])>; 2] {
+        let mut copies = [None; 2];
+        for (copy, offset) in offsets.into_iter().enumerate() {
+            let mut buf = [0u8; RECORD_MAX];
+            let sealed = &mut buf[..record_len(N)];
+            if let Err(e) = self.flash.blocking_read(offset, sealed) {
+                warn!("[storage] error reading 0x{:x}: {:?}", offset, e);
+                continue;
+            }
+            match record::open(kind, sealed) {
+                Some((sequence, data)) => {
+                    copies[copy] = Some((sequence, *data.first_chunk().unwrap()));
+                }
+                None => debug!("[storage] no intact record at 0x{:x}", offset),
+            }
+        }
+        copies
+    }
+
+    /// Writes a record and reads it back.
+    fn write_verified(&mut self, offset: u32, sealed: &[u8]) -> Result<(), WriteError> {
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+        self.flash.blocking_write(offset, sealed)?;
+        let mut buf = [0u8; RECORD_MAX];
+        let written = &mut buf[..sealed.len()];
+        self.flash.blocking_read(offset, written)?;
+        if written != sealed {
+            return Err(WriteError::Mismatch);
+        }
+        Ok(())
+    }
+}
+
+/// What's stored before the settings were ever written.
+fn default_stored() -> [u8; STORED_LEN] {
+    let mut data = [0u8; STORED_LEN];
+    data[..SETTINGS_LEN].copy_from_slice(&Settings::default().encode());
+    data
+}
+
+fn encode_bond(bond: &BondInformation) -> [u8; BOND_LEN] {
+    let mut buf = [0u8; BOND_LEN];
+    buf[0] = bond.is_bonded as u8;
+    buf[1] = match bond.security_level {
+        SecurityLevel::NoEncryption => 0,
+        SecurityLevel::Encrypted => 1,
+        SecurityLevel::EncryptedAuthenticated => 2,
+    };
+    buf[2] = bond.identity.irk.is_some() as u8;
+    buf[3..9].copy_from_slice(bond.identity.bd_addr.raw());
+    buf[9..25].copy_from_slice(&bond.ltk.to_le_bytes());
+    if let Some(irk) = bond.identity.irk {
+        buf[25..41].copy_from_slice(&irk.to_le_bytes());
+    }
+    buf
+}
+
+fn decode_bond(buf: &[u8]) -> Option<BondInformation> {
+    let security_level = match buf[1] {
+        0 => SecurityLevel::NoEncryption,
+        1 => SecurityLevel::Encrypted,
+        2 => SecurityLevel::EncryptedAuthenticated,
+        _ => return None,
+    };
+    let irk = match buf[2] {
+        0 => None,
+        1 => Some(IdentityResolvingKey::from_le_bytes(
+            buf[25..41].try_into().unwrap(),
+        )),
+        _ => return None,
+    };
+    let identity = Identity {
+        bd_addr: BdAddr::new(buf[3..9].try_into().unwrap()),
+        irk,
+    };
+    let ltk = LongTermKey::from_le_bytes(buf[9..25].try_into().unwrap());
+    Some(BondInformation::new(
+        identity,
+        ltk,
+        security_level,
+        buf[0] != 0,
+    ))
+}
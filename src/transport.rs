@@ -0,0 +1,244 @@
+//! What the knob's keys go out through. The knobs only queue them with
+//! [`crate::knob::send_key`], [`forward_keys`] drains
+//! [`KEY_PRESS_CHANNEL`] into a [`KeySender`], which turns them into
+//! reports for the host.
+//!
+//! BLE sends them by default, the `usb` module with `usb-hid`. Both write
+//! [`KeyPressed::as_report`], the press timing, ramping and coalescing
+//! here are shared.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::*;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+
+use crate::{KEY_PRESS_CHANNEL, bluetooth::KeyPressed, log::debug};
+
+/// With `volume-ramp`, a held volume key is released once no rotation
+/// came in for this long.
+const RAMP_RELEASE_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// Volume steps coming in this close together are sent as one burst.
+const COALESCE_WINDOW: Duration = Duration::from_millis(20);
+
+// How long a key is held between its press and release. A short hold gets
+// keys out quicker and lets fast turns through, but a host slow to look at
+// its reports can miss a key released before it looked. A long one is never
+// missed, but lags and caps how many keys go out per second.
+pub const PRESS_DEFAULT_MS: u8 = 50;
+/// A press duration of 0 adapts it to the host, between these.
+pub const PRESS_ADAPTIVE: u8 = 0;
+pub const PRESS_MIN_MS: u8 = 20;
+pub const PRESS_MAX_MS: u8 = 200;
+/// A press taking this long to go out means the host is lagging.
+const PRESS_SLOW_MS: u64 = 30;
+/// Taken off an adaptive press for every one going out promptly.
+const PRESS_STEP_MS: u8 = 5;
+
+/// Next adaptive press duration after a press took `took_ms` to go out. A
+/// lagging host gets longer presses right away, a prompt one earns the
+/// shorter ones back a step at a time.
+const fn adapt_press_ms(current: u8, took_ms: u64) -> u8 {
+    if took_ms >= PRESS_SLOW_MS {
+        let ms = current.saturating_mul(2);
+        if ms > PRESS_MAX_MS { PRESS_MAX_MS } else { ms }
+    } else if current > PRESS_MIN_MS + PRESS_STEP_MS {
+        current - PRESS_STEP_MS
+    } else {
+        PRESS_MIN_MS
+    }
+}
+
+const _: () = {
+    core::assert!(adapt_press_ms(50, 0) == 45);
+    core::assert!(adapt_press_ms(50, PRESS_SLOW_MS) == 100);
+    core::assert!(adapt_press_ms(PRESS_MIN_MS + 1, 0) == PRESS_MIN_MS);
+    core::assert!(adapt_press_ms(PRESS_MIN_MS, 0) == PRESS_MIN_MS);
+    core::assert!(adapt_press_ms(150, 1000) == PRESS_MAX_MS);
+    core::assert!(adapt_press_ms(PRESS_MAX_MS, 1000) == PRESS_MAX_MS);
+};
+
+pub const fn press_ms_allowed(ms: u8) -> bool {
+    matches!(ms, PRESS_ADAPTIVE | PRESS_MIN_MS..=PRESS_MAX_MS)
+}
+
+/// How long keys are held in ms, or [`PRESS_ADAPTIVE`].
+pub static PRESS_MS: AtomicU8 = AtomicU8::new(PRESS_DEFAULT_MS);
+// Where an adaptive press duration got to, kept across connections
+static ADAPTED_PRESS_MS: AtomicU8 = AtomicU8::new(PRESS_DEFAULT_MS);
+
+/// How long to hold a key whose press took `took` to go out.
+pub fn press_hold(took: Duration) -> Duration {
+    let ms = match PRESS_MS.load(Ordering::Relaxed) {
+        PRESS_ADAPTIVE => {
+            let current = ADAPTED_PRESS_MS.load(Ordering::Relaxed);
+            let ms = adapt_press_ms(current, took.as_millis());
+            if ms != current {
+                debug!(
+                    "[keys] press took {} ms, holding for {} ms",
+                    took.as_millis(),
+                    ms
+                );
+            }
+            ADAPTED_PRESS_MS.store(ms, Ordering::Relaxed);
+            ms
+        }
+        ms => ms,
+    };
+    Duration::from_millis(ms as u64)
+}
+
+/// Sends single keys to the host.
+// Only used on the single threaded executor, like `bluetooth::ConnHooks`
+#[allow(async_fn_in_trait)]
+pub trait KeySender {
+    type Error: Format;
+
+    /// Why the host can't take keys right now, they're dropped meanwhile.
+    fn blocked(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Whether keys can still go out after `e`.
+    fn recoverable(&self, e: &Self::Error) -> bool;
+
+    async fn press(&mut self, key: KeyPressed) -> Result<(), Self::Error>;
+
+    async fn release(&mut self, key: KeyPressed) -> Result<(), Self::Error>;
+
+    /// Presses and releases the key.
+    async fn send(&mut self, key: KeyPressed) -> Result<(), Self::Error> {
+        let started = Instant::now();
+        self.press(key).await?;
+        Timer::after(press_hold(started.elapsed())).await;
+        self.release(key).await
+    }
+}
+
+/// Sends the queued keys through `sender` until it fails for good.
+pub async fn forward_keys<S: KeySender>(sender: &mut S) -> S::Error {
+    // Volume key held down with `volume-ramp`
+    let mut held: Option<KeyPressed> = None;
+    // Key that ended coalescing, sent next
+    let mut pending: Option<KeyPressed> = None;
+    loop {
+        let receive = KEY_PRESS_CHANNEL.receive();
+        let key_press = match (pending.take(), held) {
+            (Some(key_press), _) => key_press,
+            (None, Some(key)) => match with_timeout(RAMP_RELEASE_TIMEOUT, receive).await {
+                Ok(key_press) => key_press,
+                Err(_) => {
+                    // The knob stopped, let go so the host stops repeating
+                    held = None;
+                    let result = sender.release(key).await;
+                    if let Err(e) = recover(sender, result, "releasing key") {
+                        return e;
+                    }
+                    continue;
+                }
+            },
+            (None, None) => receive.await,
+        };
+        if let Some(reason) = sender.blocked() {
+            debug!("[keys] {}, dropping {:?}", reason, key_press);
+            continue;
+        }
+
+        let ramp = cfg!(feature = "volume-ramp")
+            && matches!(key_press, KeyPressed::VolUp | KeyPressed::VolDown);
+        if held == Some(key_press) && ramp {
+            // Keep holding
+            continue;
+        }
+        let mut result = Ok(());
+        if let Some(key) = held.take() {
+            result = sender.release(key).await;
+        }
+        if result.is_ok() {
+            result = if ramp {
+                held = Some(key_press);
+                sender.press(key_press).await
+            } else if let Some(step) = volume_step(key_press) {
+                let net;
+                (net, pending) = coalesce(step).await;
+                send_volume(sender, net).await
+            } else {
+                sender.send(key_press).await
+            };
+        }
+        if let Err(e) = recover(sender, result, "sending key press") {
+            return e;
+        }
+    }
+}
+
+/// Logs and swallows a recoverable error, passes a fatal one on.
+fn recover<S: KeySender>(
+    sender: &S,
+    result: Result<(), S::Error>,
+    context: &str,
+) -> Result<(), S::Error> {
+    match result {
+        Err(e) if sender.recoverable(&e) => {
+            warn!("[keys] error {}: {:?}, continuing", context, e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn volume_step(key: KeyPressed) -> Option<i16> {
+    match key {
+        KeyPressed::VolUp => Some(1),
+        KeyPressed::VolDown => Some(-1),
+        _ => None,
+    }
+}
+
+/// Sums up `net` and the volume steps queued up within [`COALESCE_WINDOW`],
+/// stopping at the first other key, which is returned to be sent next.
+async fn coalesce(mut net: i16) -> (i16, Option<KeyPressed>) {
+    Timer::after(COALESCE_WINDOW).await;
+    while let Ok(key) = KEY_PRESS_CHANNEL.try_receive() {
+        match volume_step(key) {
+            Some(step) => net += step,
+            None => return (net, Some(key)),
+        }
+    }
+    (net, None)
+}
+
+/// Sends `net` volume steps back to back, only the last press is held
+/// for the usual time. The host can stop taking keys while the burst goes
+/// out, whatever is left of it is dropped then.
+async fn send_volume<S: KeySender>(sender: &mut S, net: i16) -> Result<(), S::Error> {
+    let key = if net > 0 {
+        KeyPressed::VolUp
+    } else {
+        KeyPressed::VolDown
+    };
+    let steps = net.unsigned_abs();
+    if steps == 0 {
+        // Turned back and forth, nothing to send
+        return Ok(());
+    }
+    for step in 1..=steps {
+        if let Some(reason) = sender.blocked() {
+            debug!(
+                "[keys] {}, dropping {} of {} {:?}",
+                reason,
+                steps - step + 1,
+                steps,
+                key
+            );
+            return Ok(());
+        }
+        if step < steps {
+            sender.press(key).await?;
+            sender.release(key).await?;
+        } else {
+            sender.send(key).await?;
+        }
+    }
+    Ok(())
+}
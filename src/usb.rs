@@ -0,0 +1,511 @@
+//! USB HID in place of BLE, with the `usb-hid` feature. The knob shows up
+//! as the same HID device sending the same reports, [`KeyPressed::as_report`]
+//! against [`hid::HID_REPORT_DESCRIPTOR`], only wired. Settings stay as
+//! stored, there's no GATT to change them over.
+//!
+//! A single HID interface needs little of a USB stack, this is that little
+//! on top of the RP2040 driver: the standard requests enumeration takes and
+//! the HID class ones hosts send.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either3, select3};
+use embassy_rp::{
+    peripherals::USB,
+    usb::{Bus, ControlPipe, Driver, Endpoint, In},
+};
+use embassy_time::{Duration, Ticker, with_timeout};
+use embassy_usb_driver::{
+    Bus as _, ControlPipe as _, Driver as _, EndpointAddress, EndpointError, EndpointIn as _,
+    EndpointType, Event,
+};
+
+use crate::{
+    bluetooth::{self, KeyPressed, SERIAL_NUMBER_LEN},
+    diagnostics::{self, DROPPED_REPORTS},
+    hid,
+    led::{CONN_STATE, ConnState},
+    log::debug,
+    transport::{self, KeySender},
+    watchdog::{HEARTBEAT_INTERVAL, USB_HEARTBEAT},
+};
+
+/// pid.codes test IDs, fine for a knob of your own. Knobs handed out need
+/// IDs of their own.
+const VENDOR_ID: u16 = 0x1209;
+const PRODUCT_ID: u16 = 0x0001;
+
+const CONTROL_MAX_PACKET: u16 = 64;
+/// The HID interrupt endpoint, IN 1.
+const HID_ENDPOINT: u8 = 0x81;
+/// Reports are a report ID and a byte.
+const HID_MAX_PACKET: u16 = 8;
+/// Polled every ms, the least lag a full speed device gets.
+const HID_POLL_MS: u8 = 1;
+/// Drawn from the bus with the radio off, well above what the knob takes.
+const MAX_POWER_MA: u8 = 100;
+
+/// A report the host doesn't poll for within this is given up on.
+const REPORT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Descriptor types
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_STRING: u8 = 3;
+const DESCRIPTOR_INTERFACE: u8 = 4;
+const DESCRIPTOR_ENDPOINT: u8 = 5;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+
+// String descriptor indices, 0 is the list of languages
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+const STRING_SERIAL: u8 = 3;
+/// US English, the only language the strings come in.
+const LANGUAGE_ID: u16 = 0x0409;
+
+const DEVICE_DESCRIPTOR: [u8; 18] = {
+    let [vendor_lo, vendor_hi] = VENDOR_ID.to_le_bytes();
+    let [product_lo, product_hi] = PRODUCT_ID.to_le_bytes();
+    [
+        18,
+        DESCRIPTOR_DEVICE,
+        // USB 2.0
+        0x00,
+        0x02,
+        // Class, subclass and protocol given by the interface
+        0,
+        0,
+        0,
+        CONTROL_MAX_PACKET as u8,
+        vendor_lo,
+        vendor_hi,
+        product_lo,
+        product_hi,
+        // Device release 1.00
+        0x00,
+        0x01,
+        STRING_MANUFACTURER,
+        STRING_PRODUCT,
+        STRING_SERIAL,
+        // Configurations
+        1,
+    ]
+};
+
+const HID_DESCRIPTOR: [u8; 9] = {
+    let [len_lo, len_hi] = (hid::HID_REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+    [
+        9,
+        DESCRIPTOR_HID,
+        // HID 1.11
+        0x11,
+        0x01,
+        // Not localized
+        0,
+        // Class descriptors
+        1,
+        DESCRIPTOR_REPORT,
+        len_lo,
+        len_hi,
+    ]
+};
+
+const CONFIGURATION_LEN: usize = 9 + 9 + HID_DESCRIPTOR.len() + 7;
+const CONFIGURATION_VALUE: u8 = 1;
+const CONFIGURATION_DESCRIPTOR: [u8; CONFIGURATION_LEN] = {
+    let mut descriptor = [0; CONFIGURATION_LEN];
+    let header = [
+        // Configuration
+        9,
+        DESCRIPTOR_CONFIGURATION,
+        CONFIGURATION_LEN as u8,
+        0,
+        // Interfaces
+        1,
+        CONFIGURATION_VALUE,
+        // No string
+        0,
+        // Bus powered
+        0x80,
+        MAX_POWER_MA / 2,
+        // Interface 0, alternate setting 0
+        9,
+        DESCRIPTOR_INTERFACE,
+        0,
+        0,
+        // Endpoints
+        1,
+        // HID without a boot protocol, the reports carry IDs
+        3,
+        0,
+        0,
+        // No string
+        0,
+    ];
+    let endpoint = [
+        7,
+        DESCRIPTOR_ENDPOINT,
+        HID_ENDPOINT,
+        // Interrupt
+        0x03,
+        HID_MAX_PACKET as u8,
+        0,
+        HID_POLL_MS,
+    ];
+    let mut at = 0;
+    let mut i = 0;
+    while i < header.len() {
+        descriptor[at] = header[i];
+        at += 1;
+        i += 1;
+    }
+    i = 0;
+    while i < HID_DESCRIPTOR.len() {
+        descriptor[at] = HID_DESCRIPTOR[i];
+        at += 1;
+        i += 1;
+    }
+    i = 0;
+    while i < endpoint.len() {
+        descriptor[at] = endpoint[i];
+        at += 1;
+        i += 1;
+    }
+    core::assert!(at == CONFIGURATION_LEN);
+    descriptor
+};
+
+// Requests, by the request type they come with
+const REQUEST_GET_STATUS: u8 = 0x00;
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+const REQUEST_SET_FEATURE: u8 = 0x03;
+const REQUEST_SET_ADDRESS: u8 = 0x05;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_GET_INTERFACE: u8 = 0x0A;
+const REQUEST_SET_INTERFACE: u8 = 0x0B;
+const HID_GET_REPORT: u8 = 0x01;
+const HID_GET_IDLE: u8 = 0x02;
+const HID_GET_PROTOCOL: u8 = 0x03;
+const HID_SET_IDLE: u8 = 0x0A;
+const HID_SET_PROTOCOL: u8 = 0x0B;
+/// The only feature of an endpoint.
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+/// The report protocol, the only one without a boot interface.
+const HID_PROTOCOL_REPORT: u16 = 1;
+
+// Request types: direction, standard or class, and recipient
+const TO_DEVICE: u8 = 0x00;
+const TO_INTERFACE: u8 = 0x01;
+const TO_ENDPOINT: u8 = 0x02;
+const FROM_DEVICE: u8 = 0x80;
+const FROM_INTERFACE: u8 = 0x81;
+const FROM_ENDPOINT: u8 = 0x82;
+const CLASS_TO_INTERFACE: u8 = 0x21;
+const CLASS_FROM_INTERFACE: u8 = 0xA1;
+
+/// Set while the host has the knob configured, keys can only go out then.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// Set while the bus is suspended, the host doesn't poll then.
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+fn set_configured(configured: bool) {
+    if CONFIGURED.load(Ordering::Relaxed) != configured {
+        CONFIGURED.store(configured, Ordering::Relaxed);
+        info!(
+            "[usb] {}",
+            if configured {
+                "configured"
+            } else {
+                "unconfigured"
+            }
+        );
+        CONN_STATE.signal(if configured {
+            ConnState::Connected
+        } else {
+            ConnState::Idle
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+struct Setup {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+}
+
+impl Setup {
+    fn parse(packet: [u8; 8]) -> Self {
+        let word = |at: usize| u16::from_le_bytes([packet[at], packet[at + 1]]);
+        Self {
+            request_type: packet[0],
+            request: packet[1],
+            value: word(2),
+            index: word(4),
+            length: word(6),
+        }
+    }
+}
+
+/// The bus and its control pipe, with what the host reads back over it.
+struct Device {
+    bus: Bus<'static, USB>,
+    pipe: ControlPipe<'static, USB>,
+    serial: [u8; SERIAL_NUMBER_LEN],
+    idle: u8,
+}
+
+impl Device {
+    async fn control(&mut self, setup: Setup) {
+        let hid_endpoint = EndpointAddress::from(HID_ENDPOINT);
+        let [descriptor_type, descriptor_index] = setup.value.to_be_bytes();
+        match (setup.request_type, setup.request) {
+            (FROM_DEVICE, REQUEST_GET_DESCRIPTOR) => match descriptor_type {
+                DESCRIPTOR_DEVICE => self.respond(setup, &DEVICE_DESCRIPTOR).await,
+                DESCRIPTOR_CONFIGURATION => self.respond(setup, &CONFIGURATION_DESCRIPTOR).await,
+                DESCRIPTOR_STRING => {
+                    let [lo, hi] = LANGUAGE_ID.to_le_bytes();
+                    let languages = [4, DESCRIPTOR_STRING, lo, hi];
+                    let serial = self.serial;
+                    let serial = core::str::from_utf8(&serial).unwrap_or_default();
+                    let mut buf = [0; CONTROL_MAX_PACKET as usize];
+                    let string = match descriptor_index {
+                        0 => &languages[..],
+                        STRING_MANUFACTURER => {
+                            string_descriptor(bluetooth::MANUFACTURER_STR, &mut buf)
+                        }
+                        STRING_PRODUCT => string_descriptor(bluetooth::NAME, &mut buf),
+                        STRING_SERIAL => string_descriptor(serial, &mut buf),
+                        _ => return self.pipe.reject().await,
+                    };
+                    self.respond(setup, string).await;
+                }
+                // A device qualifier among them, only high speed devices have one
+                _ => self.pipe.reject().await,
+            },
+            (FROM_INTERFACE, REQUEST_GET_DESCRIPTOR) if setup.index == 0 => match descriptor_type {
+                DESCRIPTOR_HID => self.respond(setup, &HID_DESCRIPTOR).await,
+                DESCRIPTOR_REPORT => self.respond(setup, &hid::HID_REPORT_DESCRIPTOR).await,
+                _ => self.pipe.reject().await,
+            },
+            (TO_DEVICE, REQUEST_SET_ADDRESS) => {
+                self.pipe.accept_set_address(setup.value as u8).await;
+            }
+            (TO_DEVICE, REQUEST_SET_CONFIGURATION) => {
+                let configured = match setup.value {
+                    0 => false,
+                    value if value == CONFIGURATION_VALUE as u16 => true,
+                    _ => return self.pipe.reject().await,
+                };
+                self.bus.endpoint_set_enabled(hid_endpoint, configured);
+                self.pipe.accept().await;
+                set_configured(configured);
+            }
+            (FROM_DEVICE, REQUEST_GET_CONFIGURATION) => {
+                let configured = CONFIGURED.load(Ordering::Relaxed);
+                let value = if configured { CONFIGURATION_VALUE } else { 0 };
+                self.respond(setup, &[value]).await;
+            }
+            // Bus powered, remote wakeup not supported
+            (FROM_DEVICE | FROM_INTERFACE, REQUEST_GET_STATUS) => {
+                self.respond(setup, &[0, 0]).await;
+            }
+            (FROM_ENDPOINT, REQUEST_GET_STATUS) => {
+                let halted = self
+                    .bus
+                    .endpoint_is_stalled(EndpointAddress::from(setup.index as u8));
+                self.respond(setup, &[halted as u8, 0]).await;
+            }
+            (TO_ENDPOINT, REQUEST_CLEAR_FEATURE | REQUEST_SET_FEATURE)
+                if setup.value == FEATURE_ENDPOINT_HALT && setup.index as u8 == HID_ENDPOINT =>
+            {
+                let halt = setup.request == REQUEST_SET_FEATURE;
+                self.bus.endpoint_set_stalled(hid_endpoint, halt);
+                self.pipe.accept().await;
+            }
+            (FROM_INTERFACE, REQUEST_GET_INTERFACE) if setup.index == 0 => {
+                self.respond(setup, &[0]).await;
+            }
+            (TO_INTERFACE, REQUEST_SET_INTERFACE) if setup.index == 0 && setup.value == 0 => {
+                self.pipe.accept().await;
+            }
+            // Nothing pressed, keys only go out on the interrupt endpoint
+            (CLASS_FROM_INTERFACE, HID_GET_REPORT) => {
+                let [id, _] = setup.value.to_le_bytes();
+                self.respond(setup, &[id, 0]).await;
+            }
+            // Reports only go out on changes whatever the idle rate, it's
+            // kept to be read back
+            (CLASS_TO_INTERFACE, HID_SET_IDLE) => {
+                [_, self.idle] = setup.value.to_le_bytes();
+                self.pipe.accept().await;
+            }
+            (CLASS_FROM_INTERFACE, HID_GET_IDLE) => {
+                let idle = self.idle;
+                self.respond(setup, &[idle]).await;
+            }
+            (CLASS_FROM_INTERFACE, HID_GET_PROTOCOL) => {
+                self.respond(setup, &[HID_PROTOCOL_REPORT as u8]).await;
+            }
+            (CLASS_TO_INTERFACE, HID_SET_PROTOCOL) if setup.value == HID_PROTOCOL_REPORT => {
+                self.pipe.accept().await;
+            }
+            _ => {
+                debug!("[usb] rejecting {:?}", setup);
+                self.pipe.reject().await;
+            }
+        }
+    }
+
+    /// Sends `data` in the data stage of `setup`, cut off at the length the
+    /// host asked for.
+    async fn respond(&mut self, setup: Setup, data: &[u8]) {
+        let data = &data[..data.len().min(setup.length as usize)];
+        let packet = CONTROL_MAX_PACKET as usize;
+        // A response ending on a full packet short of what was asked for
+        // needs an empty one after it to tell it's over
+        let zero_length = data.len() < setup.length as usize && data.len().is_multiple_of(packet);
+        // Nothing to send is a single empty packet as well
+        let packets = (data.len().div_ceil(packet) + zero_length as usize).max(1);
+        for i in 0..packets {
+            let chunk = data.chunks(packet).nth(i).unwrap_or_default();
+            if let Err(e) = self.pipe.data_in(chunk, i == 0, i == packets - 1).await {
+                debug!("[usb] control transfer cut short: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// A string descriptor of `s`, in `buf`, cut off at what fits.
+fn string_descriptor<'a>(s: &str, buf: &'a mut [u8; CONTROL_MAX_PACKET as usize]) -> &'a [u8] {
+    let mut len = 2;
+    for unit in s.encode_utf16() {
+        if len + 2 > buf.len() {
+            break;
+        }
+        buf[len..len + 2].copy_from_slice(&unit.to_le_bytes());
+        len += 2;
+    }
+    buf[0] = len as u8;
+    buf[1] = DESCRIPTOR_STRING;
+    &buf[..len]
+}
+
+/// Sends keys as reports on the HID endpoint.
+pub struct UsbKeys {
+    endpoint: Endpoint<'static, USB, In>,
+}
+
+impl UsbKeys {
+    /// A press the host doesn't poll for in time is dropped and counted. A
+    /// release is tried until it goes out or the host is gone, so a key
+    /// can't stay held on the host.
+    async fn write(&mut self, report: &[u8], release: bool) -> Result<(), EndpointError> {
+        loop {
+            match with_timeout(REPORT_TIMEOUT, self.endpoint.write(report)).await {
+                Ok(result) => return result,
+                Err(_) if release && CONFIGURED.load(Ordering::Relaxed) => {
+                    debug!("[usb] report timed out, trying again");
+                }
+                Err(_) => {
+                    warn!("[usb] host isn't polling, dropping {:?}", report);
+                    diagnostics::count(&DROPPED_REPORTS);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl KeySender for UsbKeys {
+    type Error = EndpointError;
+
+    fn blocked(&self) -> Option<&'static str> {
+        if !CONFIGURED.load(Ordering::Relaxed) {
+            Some("not configured")
+        } else if SUSPENDED.load(Ordering::Relaxed) {
+            Some("bus suspended")
+        } else {
+            None
+        }
+    }
+
+    // The endpoint is only disabled while the host resets or unconfigures
+    // the knob, it's enabled again once it's configured
+    fn recoverable(&self, _e: &EndpointError) -> bool {
+        true
+    }
+
+    async fn press(&mut self, key: KeyPressed) -> Result<(), EndpointError> {
+        self.write(&key.as_report(), false).await
+    }
+
+    async fn release(&mut self, key: KeyPressed) -> Result<(), EndpointError> {
+        // Nothing pressed, or no movement, is all zeroes in every report
+        let id = key.as_report()[0];
+        self.write(&[id, 0], true).await
+    }
+}
+
+#[embassy_executor::task]
+async fn usb_keys_task(mut keys: UsbKeys) {
+    let e = transport::forward_keys(&mut keys).await;
+    error!("[usb] stopped sending keys: {:?}", e);
+}
+
+/// Runs the USB device for good, the keys are sent from a task of their
+/// own. `serial` is reported as the serial number, the same as over BLE.
+pub async fn run_usb(
+    spawner: Spawner,
+    mut driver: Driver<'static, USB>,
+    serial: [u8; SERIAL_NUMBER_LEN],
+) -> ! {
+    let endpoint = unwrap!(driver.alloc_endpoint_in(
+        EndpointType::Interrupt,
+        Some(EndpointAddress::from(HID_ENDPOINT)),
+        HID_MAX_PACKET,
+        HID_POLL_MS,
+    ));
+    spawner.spawn(usb_keys_task(UsbKeys { endpoint })).unwrap();
+
+    let (bus, pipe) = driver.start(CONTROL_MAX_PACKET);
+    let mut device = Device {
+        bus,
+        pipe,
+        serial,
+        idle: 0,
+    };
+    let mut heartbeat = Ticker::every(HEARTBEAT_INTERVAL);
+    loop {
+        USB_HEARTBEAT.beat();
+        match select3(device.bus.poll(), device.pipe.setup(), heartbeat.next()).await {
+            Either3::First(event) => {
+                debug!("[usb] {:?}", event);
+                match event {
+                    Event::PowerDetected => device.bus.enable().await,
+                    Event::PowerRemoved => {
+                        device.bus.disable().await;
+                        set_configured(false);
+                    }
+                    // The driver disables the endpoints and clears the address
+                    Event::Reset => {
+                        SUSPENDED.store(false, Ordering::Relaxed);
+                        set_configured(false);
+                    }
+                    Event::Suspend => SUSPENDED.store(true, Ordering::Relaxed),
+                    Event::Resume => SUSPENDED.store(false, Ordering::Relaxed),
+                }
+            }
+            Either3::Second(packet) => device.control(Setup::parse(packet)).await,
+            Either3::Third(_) => {}
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use defmt::{Display2Format, error, info, warn};
+use embassy_rp::{
+    peripherals::WATCHDOG,
+    watchdog::{ResetReason, Watchdog},
+};
+use embassy_time::{Duration, Timer};
+
+use crate::power;
+
+/// The chip resets when the watchdog isn't fed for this long,
+/// the RP2040 can't go above about 8.3 seconds.
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+/// How often the supervisor looks at the heartbeats.
+pub const PET_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the supervised loops beat, short enough that every pet
+/// interval sees at least one.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Scratch register left set by a panic, it survives the reset.
+const PANIC_SCRATCH: usize = 0;
+const PANIC_MARKER: u32 = u32::from_le_bytes(*b"SVKP");
+
+/// Set by a supervised loop whenever it makes progress.
+pub struct Heartbeat {
+    name: &'static str,
+    alive: AtomicBool,
+}
+
+impl Heartbeat {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            alive: AtomicBool::new(false),
+        }
+    }
+
+    pub fn beat(&self) {
+        self.alive.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        let alive = self.alive.load(Ordering::Relaxed);
+        self.alive.store(false, Ordering::Relaxed);
+        alive
+    }
+}
+
+pub static BLE_HEARTBEAT: Heartbeat = Heartbeat::new("ble");
+#[cfg(feature = "usb-hid")]
+pub static USB_HEARTBEAT: Heartbeat = Heartbeat::new("usb");
+pub static KNOB_HEARTBEAT: Heartbeat = Heartbeat::new("knob");
+
+#[cfg(not(feature = "usb-hid"))]
+const HEARTBEATS: [&Heartbeat; 2] = [&BLE_HEARTBEAT, &KNOB_HEARTBEAT];
+// BLE is never started
+#[cfg(feature = "usb-hid")]
+const HEARTBEATS: [&Heartbeat; 2] = [&USB_HEARTBEAT, &KNOB_HEARTBEAT];
+
+/// Feeds the watchdog only while every heartbeat keeps coming in.
+#[embassy_executor::task]
+pub async fn watchdog_task(mut watchdog: Watchdog) {
+    match watchdog.reset_reason() {
+        Some(ResetReason::TimedOut) => warn!("[watchdog] last reset was a watchdog timeout"),
+        Some(ResetReason::Forced) if watchdog.get_scratch(PANIC_SCRATCH) == PANIC_MARKER => {
+            warn!("[watchdog] last reset was after a panic");
+        }
+        Some(ResetReason::Forced) => info!("[watchdog] last reset was forced"),
+        None => {}
+    }
+
+    watchdog.set_scratch(PANIC_SCRATCH, 0);
+
+    // Let a debugger halt the chip without it resetting
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+
+    loop {
+        Timer::after(PET_INTERVAL).await;
+        let mut alive = true;
+        for heartbeat in HEARTBEATS {
+            if !heartbeat.take() {
+                warn!("[watchdog] no heartbeat from {}", heartbeat.name);
+                alive = false;
+            }
+        }
+        if alive {
+            watchdog.feed();
+        }
+    }
+}
+
+/// Logs the panic, powers the radio down and resets the chip, so a knob
+/// in the field comes back advertising instead of hanging. The executor
+/// is stopped for good by then, so the host can't be told. With the cyw43
+/// held in reset it drops the link after its supervision timeout.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
+    cortex_m::interrupt::disable();
+    // A panic while handling one goes straight to the reset
+    if !PANICKED.load(Ordering::Relaxed) {
+        PANICKED.store(true, Ordering::Relaxed);
+        error!("{}", Display2Format(info));
+        power::cut_radio_power();
+    }
+
+    // Safety: nothing else runs anymore
+    let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
+    watchdog.set_scratch(PANIC_SCRATCH, PANIC_MARKER);
+    watchdog.trigger_reset();
+    loop {
+        cortex_m::asm::nop();
+    }
+}